@@ -1,6 +1,4 @@
 use rebab::config;
-use schemars;
-use serde_json;
 use std::fs;
 fn main() {
 	generate_schema("src/schema.json");
@@ -0,0 +1,92 @@
+use std::net::IpAddr;
+
+use hyper::{Request, Response};
+use ipnet::IpNet;
+
+use crate::body::RebabBody;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::proxy::Connection;
+use crate::service::response;
+
+/// クライアントの IP で断る（deny に入るか、allow があってそこに入らなければ 403）
+///
+/// クライアントの IP は [`Connection::client_ip`]（信頼できるプロキシ経由なら X-Forwarded-For から求めたもの）
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+	pub allow: Vec<IpNet>,
+	pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+	pub fn is_empty(&self) -> bool {
+		self.allow.is_empty() && self.deny.is_empty()
+	}
+
+	/// deny が allow より優先する
+	pub fn permits(&self, ip: IpAddr) -> bool {
+		let ip = ip.to_canonical();
+		if self.deny.iter().any(|v| v.contains(&ip)) {
+			return false;
+		}
+		self.allow.is_empty() || self.allow.iter().any(|v| v.contains(&ip))
+	}
+}
+
+impl Middleware for IpFilter {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			if !self.permits(conn.client_ip) {
+				return response(403, format!("rebab forbidden for {}", conn.client_ip));
+			}
+			next.run(req, conn).await
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+		let nets = |v: &[&str]| v.iter().map(|v| v.parse().unwrap()).collect();
+		IpFilter {
+			allow: nets(allow),
+			deny: nets(deny),
+		}
+	}
+
+	fn permits(filter: &IpFilter, ip: &str) -> bool {
+		filter.permits(ip.parse().unwrap())
+	}
+
+	#[test]
+	fn allow_list() {
+		let filter = filter(&["127.0.0.0/8", "::1/128", "10.8.0.0/16"], &[]);
+		assert!(permits(&filter, "127.0.0.1"));
+		assert!(permits(&filter, "::1"));
+		assert!(permits(&filter, "10.8.3.4"));
+		assert!(!permits(&filter, "192.168.1.2"));
+		// IPv4-mapped IPv6 は IPv4 として扱う
+		assert!(permits(&filter, "::ffff:10.8.0.1"));
+	}
+
+	#[test]
+	fn deny_wins() {
+		let filter = filter(&["10.0.0.0/8"], &["10.0.0.13/32", "2001:db8::/32"]);
+		assert!(permits(&filter, "10.0.0.12"));
+		assert!(!permits(&filter, "10.0.0.13"));
+		assert!(!permits(&filter, "2001:db8::1"));
+		// allow が無ければ deny 以外は通す
+		let deny_only = IpFilter {
+			deny: filter.deny.clone(),
+			..Default::default()
+		};
+		assert!(permits(&deny_only, "192.0.2.1"));
+		assert!(!permits(&deny_only, "10.0.0.13"));
+	}
+}
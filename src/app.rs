@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::middleware::Middleware;
+use crate::serve::{Listener, Server};
+use crate::{
+	auth, cache, compress, config, dashboard, error_page, files, forwarded, limit, log, matcher,
+	process, proxy, template,
+};
+
+/// 設定どおりにプロセスを起動し、frontend で待ち受ける rebab 本体
+///
+/// ```no_run
+/// # async fn run() -> Result<(), String> {
+/// let router: rebab::config::Router = serde_json::from_str(
+///     r#"{"frontend": "127.0.0.1:8080", "rules": [{"backend_port": 3000}]}"#,
+/// )
+/// .unwrap();
+/// let mut app = rebab::Rebab::start(router.clone()).await?;
+/// // ルールを差し替える（変わったプロセスだけ起動し直す）
+/// app.reload(router).await?;
+/// app.stop().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Rebab {
+	server: Server,
+	proxy: Arc<RebabProxy>,
+	process_manager: Arc<process::ProcessManager>,
+	monitor: JoinHandle<()>,
+	/// 管理下のプロセスが終了したときのエラー
+	failed: mpsc::Receiver<String>,
+}
+
+impl Rebab {
+	/// プロセスを depends_on の順に起動してから待ち受けを始める
+	///
+	/// 途中で失敗したら起動したプロセスを止めてエラーを返す
+	pub async fn start(router: config::Router) -> Result<Self, String> {
+		Self::start_with(router, vec![]).await
+	}
+
+	/// すべてのリクエストに `middleware` を挟んで起動する
+	pub async fn start_with(
+		mut router: config::Router,
+		middleware: Vec<Arc<dyn Middleware>>,
+	) -> Result<Self, String> {
+		router.validate()?;
+		let listener = Listener {
+			middleware,
+			..listener(&router)?
+		};
+		let process_manager = Arc::new(process::ProcessManager::new());
+
+		// Assign free ports to command rules without backend_port
+		allocate_ports(&mut router, None)?;
+
+		// Execute commands for each rule in dependency order
+		if let Err(e) = start_processes(&router, &process_manager, &[]).await {
+			log::log(format!("Command execution error: {}", e));
+			terminate_processes(&process_manager).await;
+			return Err(e);
+		}
+
+		let requests = Arc::new(dashboard::RequestLog::default());
+		let routes = Routes::new(router, &process_manager, &requests)?;
+		let proxy = Arc::new(RebabProxy {
+			routes: RwLock::new(Arc::new(routes)),
+			process_manager: process_manager.clone(),
+			requests,
+		});
+		let server = match Server::bind(listener, proxy.clone()).await {
+			Ok(v) => v,
+			Err(e) => {
+				terminate_processes(&process_manager).await;
+				return Err(e.to_string());
+			}
+		};
+
+		// Start process monitoring task
+		let (failure, failed) = mpsc::channel(1);
+		let pm_for_monitor = process_manager.clone();
+		let monitor = tokio::spawn(async move {
+			let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+			loop {
+				interval.tick().await;
+				if let Err(e) = pm_for_monitor.check_all() {
+					log::log(format!("Process monitoring error: {}", e));
+					let _ = failure.send(e).await;
+					return;
+				}
+			}
+		});
+
+		Ok(Self {
+			server,
+			proxy,
+			process_manager,
+			monitor,
+			failed,
+		})
+	}
+
+	/// 実際に待ち受けているアドレス（frontend のポートが 0 なら割り当てられたもの）
+	pub fn local_addr(&self) -> SocketAddr {
+		self.server.local_addr()
+	}
+
+	/// 現在のルールと processes の設定（割り当てたポートを含む）
+	pub fn router(&self) -> config::Router {
+		self.proxy.routes().router.clone()
+	}
+
+	/// 設定を差し替える
+	///
+	/// 新しいルールを作り、プロセスを起動してからルールを切り替え、無くなったプロセスを止める。
+	/// 設定の変わったプロセスは同じ名前とポートを使うので起動の前に止める。
+	/// 起動に失敗したら、このとき起動したプロセスを止め、止めたプロセスを元の設定で起動し直して元のルールのままにする
+	/// frontend と待ち受けの設定（trusted_proxies など）は再起動するまで変わらない
+	pub async fn reload(&mut self, mut router: config::Router) -> Result<(), String> {
+		router.validate()?;
+		let current = self.proxy.routes();
+		let previous = &current.router;
+		if router.frontend != previous.frontend
+			|| router.trusted_proxies != previous.trusted_proxies
+			|| router.proxy_protocol != previous.proxy_protocol
+			|| router.scheme != previous.scheme
+			|| router.max_header_size != previous.max_header_size
+			|| router.max_headers != previous.max_headers
+		{
+			log::log("frontend and listener settings are applied on restart");
+		}
+		allocate_ports(&mut router, Some(previous))?;
+
+		// 同じ設定のまま残るプロセス
+		let old = previous.managed();
+		let new = router.managed();
+		let kept: Vec<String> = new
+			.iter()
+			.filter(|v| v.command.is_some())
+			.filter(|v| {
+				old.iter()
+					.any(|w| w.label == v.label && same_process(previous, w, &router, v))
+			})
+			.map(|v| v.label.clone())
+			.collect();
+		let started: Vec<String> = new
+			.iter()
+			.filter(|v| v.command.is_some() && !kept.contains(&v.label))
+			.map(|v| v.label.clone())
+			.collect();
+		let routes = Routes::new(router, &self.process_manager, &self.proxy.requests)?;
+		// 設定の変わったプロセスは同じ名前とポートを使うので先に止める
+		let (changed, untouched): (Vec<String>, Vec<String>) = old
+			.iter()
+			.filter(|v| v.command.is_some())
+			.map(|v| v.label.clone())
+			.partition(|v| started.contains(v));
+		stop_processes(&self.process_manager, changed).await;
+		if let Err(e) = start_processes(&routes.router, &self.process_manager, &kept).await {
+			stop_processes(&self.process_manager, started).await;
+			if let Err(e) = start_processes(previous, &self.process_manager, &untouched).await {
+				log::log(format!("Failed to restart the previous processes: {}", e));
+			}
+			return Err(e);
+		}
+		*self.proxy.routes.write().unwrap() = Arc::new(routes);
+		// 無くなったプロセスはルールを切り替えてから止める
+		let removed = untouched
+			.into_iter()
+			.filter(|v| !kept.contains(v))
+			.collect();
+		stop_processes(&self.process_manager, removed).await;
+		log::log("configuration reloaded");
+		Ok(())
+	}
+
+	/// 待ち受けが終わるか、管理下のプロセスが終了するまで待つ
+	pub async fn wait(&mut self) -> Result<(), String> {
+		tokio::select! {
+			result = self.server.wait() => result.map_err(|e| format!("Server error: {}", e)),
+			Some(e) = self.failed.recv() => Err(e),
+		}
+	}
+
+	/// 待ち受けをやめ、すべてのプロセスを止める
+	pub async fn stop(self) {
+		self.monitor.abort();
+		self.server.stop().await;
+		terminate_processes(&self.process_manager).await;
+	}
+}
+
+/// すべてのプロセスを止める（止まるまで待つことがあるので、別のスレッドで止める）
+async fn terminate_processes(process_manager: &Arc<process::ProcessManager>) {
+	let process_manager = process_manager.clone();
+	let _ = tokio::task::spawn_blocking(move || process_manager.terminate_all()).await;
+}
+
+/// プロセスを止める（同上）
+async fn stop_processes(process_manager: &Arc<process::ProcessManager>, names: Vec<String>) {
+	if names.is_empty() {
+		return;
+	}
+	let process_manager = process_manager.clone();
+	let _ = tokio::task::spawn_blocking(move || {
+		for name in &names {
+			process_manager.remove(name);
+		}
+	})
+	.await;
+}
+
+/// 再読み込みの前後で同じプロセスとみなせるか
+fn same_process(
+	old_router: &config::Router,
+	old: &config::Managed,
+	new_router: &config::Router,
+	new: &config::Managed,
+) -> bool {
+	old.command == new.command
+		&& old.backend_host == new.backend_host
+		&& old.backend_port == new.backend_port
+		&& old.depends_on == new.depends_on
+		&& old.watch == new.watch
+		&& old_router.log_file(old) == new_router.log_file(new)
+}
+
+fn listener(router: &config::Router) -> Result<Listener, String> {
+	Ok(Listener {
+		addr: router.frontend,
+		trusted_proxies: forwarded::TrustedProxies::new(router.trusted_proxies()?),
+		proxy_protocol: router.proxy_protocol.unwrap_or(false),
+		scheme: router.scheme,
+		max_header_size: router.max_header_size,
+		max_headers: router.max_headers,
+		middleware: vec![],
+	})
+}
+
+/// command があり backend_port の無いローカルのルールに空きポートを割り当てる
+///
+/// 再読み込みでは、同じ名前で同じコマンドのルールに前回のポートを引き継ぐ
+fn allocate_ports(
+	router: &mut config::Router,
+	previous: Option<&config::Router>,
+) -> Result<(), String> {
+	for (index, rule) in router.rules.iter_mut().enumerate() {
+		if rule.command.is_some()
+			&& rule.is_forward()
+			&& rule.backend_port.is_none()
+			&& rule.backend_host.is_none()
+		{
+			let label = rule.label(index);
+			let kept = previous.and_then(|v| {
+				v.rules
+					.iter()
+					.enumerate()
+					.find(|(i, v)| v.label(*i) == label && v.command == rule.command)
+					.and_then(|(_, v)| v.backend_port)
+			});
+			if let Some(port) = kept {
+				rule.backend_port = Some(port);
+				continue;
+			}
+			let port = process::free_port()?;
+			log::log(format!("{}: assigned port {}", rule.label(index), port));
+			rule.backend_port = Some(port);
+		}
+	}
+	Ok(())
+}
+
+/// depends_on の順にルールと processes のコマンドを起動する
+///
+/// `running` は起動済みでそのまま使うもの（依存先としては待つ）
+async fn start_processes(
+	router: &config::Router,
+	process_manager: &process::ProcessManager,
+	running: &[String],
+) -> Result<(), String> {
+	let managed = router.managed();
+	let width = managed
+		.iter()
+		.filter(|v| v.command.is_some())
+		.map(|v| v.label.len())
+		.max()
+		.unwrap_or(0);
+	for index in router.startup_order()? {
+		let entry = &managed[index];
+		// 依存先がポートを持つなら、接続を受け付けるまで待つ
+		for name in entry.depends_on {
+			let dependency = managed
+				.iter()
+				.find(|v| &v.label == name)
+				.expect("validated in startup_order");
+			if let Some(port) = dependency.backend_port {
+				let host = dependency.backend_host.unwrap_or("localhost");
+				process_manager
+					.wait_ready(name, host, port, process::READY_TIMEOUT)
+					.await?;
+			}
+		}
+		let Some(command) = entry.command else {
+			continue;
+		};
+		if running.contains(&entry.label) {
+			continue;
+		}
+		let label = log::Label::new(entry.label.clone(), index, width);
+		let log_file = router
+			.log_file(entry)
+			.map(|path| {
+				log::RotatingFile::open(
+					&path,
+					router
+						.log_max_size
+						.unwrap_or(log::RotatingFile::DEFAULT_MAX_SIZE),
+					router
+						.log_max_files
+						.unwrap_or(log::RotatingFile::DEFAULT_MAX_FILES),
+				)
+				.map_err(|e| format!("Failed to open log file {}: {}", path.display(), e))
+			})
+			.transpose()?;
+		process_manager.spawn_command(label, command, entry.backend_port, log_file)?;
+		if let Some(watch) = entry.watch {
+			process_manager.watch(&entry.label, watch)?;
+		}
+	}
+	Ok(())
+}
+
+/// 設定から作ったルーティング（再読み込みで丸ごと差し替える）
+struct Routes {
+	router: config::Router,
+	/// rules と同じ順にコンパイルした条件
+	matchers: Vec<matcher::Matcher>,
+	/// rules と同じ順の、ルールごとに挟む処理（global を含む）
+	middleware: Vec<Vec<Arc<dyn Middleware>>>,
+	/// どのルールにもマッチしないリクエストに挟む処理
+	global: Vec<Arc<dyn Middleware>>,
+	dashboard: Option<Arc<dashboard::Dashboard>>,
+	/// ダッシュボードへのリクエストに挟む処理（記録はしない）
+	dashboard_middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Routes {
+	fn new(
+		router: config::Router,
+		process_manager: &Arc<process::ProcessManager>,
+		requests: &Arc<dashboard::RequestLog>,
+	) -> Result<Self, String> {
+		let matchers = router
+			.rules
+			.iter()
+			.map(matcher::Matcher::new)
+			.collect::<Result<_, _>>()?;
+		// すべてのルールの前に挟む処理
+		let error_pages = error_page::ErrorPages::load(
+			&router.error_pages,
+			router.hide_error_details.unwrap_or(false),
+		)?;
+		let mut global: Vec<Arc<dyn Middleware>> = vec![Arc::new(error_pages)];
+		let ip_filter = router.ip_filter()?;
+		if !ip_filter.is_empty() {
+			global.push(Arc::new(ip_filter));
+		}
+		let dashboard = router
+			.dashboard
+			.as_ref()
+			.map(|path| {
+				dashboard::Dashboard::new(
+					path,
+					router.clone(),
+					process_manager.clone(),
+					requests.clone(),
+				)
+				.map(Arc::new)
+			})
+			.transpose()?;
+		let dashboard_middleware = global.clone();
+		// ダッシュボードがあれば、そこに出すリクエストを一番外側で記録する
+		let recorder = |rule: Option<String>| -> Vec<Arc<dyn Middleware>> {
+			match dashboard {
+				Some(_) => vec![Arc::new(dashboard::Recorder {
+					log: requests.clone(),
+					rule,
+				})],
+				None => vec![],
+			}
+		};
+		let limits = concurrency_limits(&router)?;
+		let middleware = router
+			.rules
+			.iter()
+			.enumerate()
+			.map(|(index, rule)| {
+				Ok([
+					recorder(Some(rule.label(index))),
+					global.clone(),
+					rule_middleware(&router, index, rule, &limits)?,
+				]
+				.concat())
+			})
+			.collect::<Result<_, String>>()?;
+		let global = [recorder(None), global].concat();
+		Ok(Self {
+			router,
+			matchers,
+			middleware,
+			global,
+			dashboard,
+			dashboard_middleware,
+		})
+	}
+}
+
+/// max_concurrent のある転送するルールの、転送先（host:port）ごとの同時リクエスト数の制限
+///
+/// 同じ転送先のルールは一つの制限を共有するので、設定が揃っていなければならない
+fn concurrency_limits(
+	router: &config::Router,
+) -> Result<HashMap<String, Arc<limit::ConcurrencyLimit>>, String> {
+	let mut limits = HashMap::new();
+	// 転送先ごとの、最初のルールのラベルと設定
+	let mut first = HashMap::new();
+	for (index, rule) in router.rules.iter().enumerate() {
+		let (Some(max), Some(authority)) = (rule.max_concurrent, rule.backend_authority()) else {
+			continue;
+		};
+		let settings = (max, rule.max_queue, rule.queue_timeout);
+		match first.get(&authority) {
+			Some((label, v)) if *v != settings => {
+				return Err(format!(
+					"rule {}: max_concurrent, max_queue and queue_timeout must match rule {}, which forwards to the same backend {}",
+					rule.label(index),
+					label,
+					authority
+				));
+			}
+			Some(_) => {}
+			None => {
+				limits.insert(authority.clone(), Arc::new(concurrency_limit(rule, max)?));
+				first.insert(authority, (rule.label(index), settings));
+			}
+		}
+	}
+	Ok(limits)
+}
+
+fn concurrency_limit(rule: &config::Rule, max: usize) -> Result<limit::ConcurrencyLimit, String> {
+	let timeout = rule
+		.queue_timeout
+		.map_or(limit::DEFAULT_QUEUE_TIMEOUT, std::time::Duration::from_secs);
+	limit::ConcurrencyLimit::new(max, rule.max_queue.unwrap_or(0), timeout)
+}
+
+/// ルールの設定から、そのルールに挟む処理を組み立てる（前から順に通る）
+///
+/// `limits` は concurrency_limits で作った転送先ごとの同時リクエスト数の制限
+fn rule_middleware(
+	router: &config::Router,
+	index: usize,
+	rule: &config::Rule,
+	limits: &HashMap<String, Arc<limit::ConcurrencyLimit>>,
+) -> Result<Vec<Arc<dyn Middleware>>, String> {
+	let mut middleware: Vec<Arc<dyn Middleware>> = vec![];
+	let ip_filter = rule.ip_filter()?;
+	if !ip_filter.is_empty() {
+		middleware.push(Arc::new(ip_filter));
+	}
+	// 認証より前に数え、認証に失敗したリクエスト（総当たり）も制限する
+	if let Some(rate) = rule.rate_limit {
+		let key = match &rule.rate_limit_key {
+			Some(v) => v.parse()?,
+			None => limit::RateKey::ClientIp,
+		};
+		let burst = rule
+			.rate_limit_burst
+			.unwrap_or_else(|| limit::RateLimit::default_burst(rate));
+		middleware.push(Arc::new(limit::RateLimit::new(rate, burst, key)?));
+	}
+	if rule.requires_auth() {
+		let mut users = rule.basic_auth.clone();
+		if let Some(path) = &rule.htpasswd {
+			users.extend(auth::read_htpasswd(path)?);
+		}
+		let realm = rule
+			.auth_realm
+			.clone()
+			.unwrap_or_else(|| auth::DEFAULT_REALM.to_string());
+		middleware.push(Arc::new(auth::Auth::new(
+			realm,
+			users,
+			rule.bearer_tokens.clone(),
+			rule.strip_authorization.unwrap_or(false),
+		)?));
+	}
+	if let Some(max) = rule.max_body_size.or(router.max_body_size) {
+		middleware.push(Arc::new(limit::BodyLimit::new(max)));
+	}
+	if let Some(max) = rule.max_concurrent {
+		// 転送しないルールはルールごとに数える
+		match rule.backend_authority() {
+			Some(authority) => middleware.push(limits[&authority].clone()),
+			None => middleware.push(Arc::new(concurrency_limit(rule, max)?)),
+		}
+	}
+	if rule.compress.unwrap_or(false) {
+		let mut compression = compress::Compression::default();
+		if let Some(v) = rule.compress_min_size {
+			compression.min_size = v;
+		}
+		if !rule.compress_types.is_empty() {
+			compression.types = rule.compress_types.clone();
+		}
+		middleware.push(Arc::new(compression));
+	}
+	// 転送の直前に置き、裏での再検証と同じものを保存する。再読み込みでは作り直すので空になる
+	if rule.cache.unwrap_or(false) {
+		let disk = rule.cache_dir.as_ref().map(|dir| cache::DiskTier {
+			dir: dir.join(rule.label(index)),
+			max_size: rule
+				.cache_dir_max_size
+				.unwrap_or(cache::DEFAULT_DISK_MAX_SIZE),
+		});
+		middleware.push(Arc::new(cache::Cache::new(
+			rule.cache_max_size.unwrap_or(cache::DEFAULT_MAX_SIZE),
+			rule.cache_max_entry_size
+				.unwrap_or(cache::DEFAULT_MAX_ENTRY_SIZE),
+			disk,
+		)?));
+	}
+	Ok(middleware)
+}
+
+/// 設定のルールで振り分ける Proxy
+struct RebabProxy {
+	routes: RwLock<Arc<Routes>>,
+	process_manager: Arc<process::ProcessManager>,
+	/// ダッシュボードに出すリクエスト（再読み込みをまたいで残す）
+	requests: Arc<dashboard::RequestLog>,
+}
+
+impl RebabProxy {
+	fn routes(&self) -> Arc<Routes> {
+		self.routes.read().unwrap().clone()
+	}
+
+	/// マッチしたルールの動作
+	fn action(
+		rule: &config::Rule,
+		uri: &hyper::Uri,
+		vars: &template::Vars,
+		rest: &str,
+	) -> proxy::Decision {
+		let headers: Vec<(String, String)> = rule
+			.headers
+			.iter()
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect();
+
+		// リダイレクト
+		if let Some(location) = &rule.redirect {
+			return proxy::Decision::Redirect {
+				status: rule.status.unwrap_or(302),
+				location: vars.expand(location),
+				headers,
+			};
+		}
+
+		// 固定レスポンス
+		if rule.status.is_some() || rule.body.is_some() {
+			return proxy::Decision::Respond {
+				status: rule.status.unwrap_or(200),
+				headers,
+				body: rule.body.clone().unwrap_or_default(),
+			};
+		}
+
+		// 静的ファイル配信（prefix を除いたパスで探す）
+		if let Some(root) = &rule.static_dir {
+			return proxy::Decision::Static(files::StaticFiles {
+				root: root.clone(),
+				path: rest.to_string(),
+				index: rule
+					.index_file
+					.clone()
+					.unwrap_or_else(|| "index.html".to_string()),
+				spa_fallback: rule.spa_fallback.unwrap_or(false),
+			});
+		}
+
+		// URI を組み立て（rewrite があればパスを置き換える）
+		let path_q = match &rule.rewrite {
+			Some(v) => vars.expand(v),
+			None => uri
+				.path_and_query()
+				.map(|pq| pq.as_str())
+				.unwrap_or("/")
+				.to_string(),
+		};
+		let target_uri = format!(
+			"http://{}{}{}",
+			match &rule.backend_host {
+				Some(v) => v,
+				None => "localhost",
+			},
+			match rule.backend_port.or(uri.port_u16()) {
+				Some(v) => format!(":{v}"),
+				None => "".to_string(),
+			},
+			path_q
+		);
+		// 文字列 → hyper::Uri にパース（展開したリクエストの値によっては失敗する）
+		let uri = match target_uri.parse::<hyper::Uri>() {
+			Ok(v) => v,
+			Err(e) => {
+				log::log(format!("invalid URI generated: {e} (from {target_uri})"));
+				return proxy::Decision::Reject {
+					status: 502,
+					reason: "rebab invalid backend URI".to_string(),
+				};
+			}
+		};
+		proxy::Decision::Forward(proxy::Upstream {
+			uri,
+			request_headers: header_ops(rule.request_headers.as_ref(), vars),
+			timeout: None,
+		})
+	}
+}
+
+/// 設定のヘッダ操作を、変数を展開して remove → set → add の順に並べる
+fn header_ops(ops: Option<&config::HeaderOps>, vars: &template::Vars) -> Vec<proxy::HeaderOp> {
+	use hyper::header::{HeaderName, HeaderValue};
+	use proxy::HeaderOp;
+	let Some(ops) = ops else {
+		return vec![];
+	};
+	// 名前は config::Router::validate で検証済み
+	let name = |v: &String| HeaderName::from_bytes(v.as_bytes()).ok();
+	let value = |v: &String| {
+		let expanded = vars.expand(v);
+		HeaderValue::from_str(&expanded)
+			.inspect_err(|_| log::log(format!("invalid header value: {}", expanded)))
+			.ok()
+	};
+	let remove = ops
+		.remove
+		.iter()
+		.filter_map(|k| Some(HeaderOp::Remove(name(k)?)));
+	let set = ops
+		.set
+		.iter()
+		.filter_map(|(k, v)| Some(HeaderOp::Set(name(k)?, value(v)?)));
+	let add = ops
+		.add
+		.iter()
+		.filter_map(|(k, v)| Some(HeaderOp::Add(name(k)?, value(v)?)));
+	remove.chain(set).chain(add).collect()
+}
+
+impl proxy::Proxy for RebabProxy {
+	fn route(
+		&self,
+		parts: &hyper::http::request::Parts,
+		conn: &proxy::Connection,
+	) -> proxy::Routing {
+		let routes = self.routes();
+		if let Some(dashboard) = &routes.dashboard
+			&& dashboard.matches(parts.uri.path())
+		{
+			return proxy::Routing {
+				middleware: routes.dashboard_middleware.clone(),
+				..dashboard.route(parts, conn).into()
+			};
+		}
+		let buckets = matcher::Buckets::new(parts, conn.client_ip);
+		let Some((index, matched)) = routes
+			.matchers
+			.iter()
+			.enumerate()
+			.find_map(|(index, matcher)| Some((index, matcher.matches(parts, &buckets)?)))
+		else {
+			return proxy::Routing {
+				middleware: routes.global.clone(),
+				..proxy::Decision::Reject {
+					status: 404,
+					reason: format!("rebab no route for {}", parts.uri),
+				}
+				.into()
+			};
+		};
+		let rule = &routes.router.rules[index];
+		let vars = template::Vars::new(parts, &matched, conn.client_ip);
+
+		let mut response_headers = header_ops(rule.response_headers.as_ref(), &vars);
+		// 振り分けに使ったバケットを次回以降も使うよう保存する
+		let weighted = routes.matchers[..=index]
+			.iter()
+			.any(|v| v.uses_bucket_cookie());
+		if weighted && let Some(cookie) = buckets.set_cookie() {
+			response_headers.push(proxy::HeaderOp::Add(
+				hyper::header::SET_COOKIE,
+				hyper::header::HeaderValue::from_str(&cookie).unwrap(),
+			));
+		}
+		proxy::Routing {
+			decision: Self::action(rule, &parts.uri, &vars, &matched.rest),
+			response_headers,
+			middleware: routes.middleware[index].clone(),
+		}
+	}
+
+	async fn wait_upstream(&self, uri: &hyper::Uri) {
+		if let Some(port) = uri.port_u16() {
+			self.process_manager
+				.wait_restarted(port, process::READY_TIMEOUT)
+				.await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn commands(app: &Rebab) -> Vec<(String, String)> {
+		app.process_manager
+			.status()
+			.into_iter()
+			.filter(|v| v.pid.is_some())
+			.map(|v| (v.name, v.command))
+			.collect()
+	}
+
+	#[test]
+	fn concurrency_limits_per_backend() {
+		let limits = |rules: &str| {
+			let router: config::Router = serde_json::from_str(&format!(
+				r#"{{"frontend": "127.0.0.1:0", "rules": {}}}"#,
+				rules
+			))
+			.unwrap();
+			concurrency_limits(&router).map(|v| {
+				let mut keys: Vec<String> = v.into_keys().collect();
+				keys.sort();
+				keys
+			})
+		};
+		assert_eq!(
+			limits(
+				r#"[
+					{"path": "/a", "backend_port": 3000, "max_concurrent": 4},
+					{"path": "/b", "backend_port": 3000, "max_concurrent": 4},
+					{"path": "/c", "backend_host": "api", "max_concurrent": 2},
+					{"path": "/d", "backend_port": 3001},
+					{"path": "/e", "static_dir": ".", "max_concurrent": 1}
+				]"#
+			)
+			.unwrap(),
+			["api", "localhost:3000"]
+		);
+		let error = limits(
+			r#"[
+				{"name": "a", "backend_port": 3000, "max_concurrent": 4},
+				{"name": "b", "backend_port": 3000, "max_concurrent": 4, "max_queue": 10}
+			]"#,
+		)
+		.unwrap_err();
+		assert!(error.starts_with("rule b:"), "{}", error);
+	}
+
+	#[tokio::test]
+	async fn failed_reload_restores_processes() {
+		let router = |json: &str| -> config::Router { serde_json::from_str(json).unwrap() };
+		let mut app = Rebab::start(router(
+			r#"{"frontend": "127.0.0.1:0", "rules": [], "processes": [
+				{"name": "a", "command": "sleep 30"},
+				{"name": "b", "command": "sleep 31"}
+			]}"#,
+		))
+		.await
+		.unwrap();
+		// a は設定が変わり、c はログファイルを開けずに起動できない
+		let result = app
+			.reload(router(
+				r#"{"frontend": "127.0.0.1:0", "rules": [], "processes": [
+					{"name": "a", "command": "sleep 32"},
+					{"name": "b", "command": "sleep 31"},
+					{"name": "c", "command": "sleep 33", "depends_on": "a", "log_file": "/dev/null/c.log"}
+				]}"#,
+			))
+			.await;
+		assert!(result.unwrap_err().contains("/dev/null/c.log"));
+		let mut running = commands(&app);
+		running.sort();
+		assert_eq!(
+			running,
+			[
+				("a".to_string(), "sleep 30".to_string()),
+				("b".to_string(), "sleep 31".to_string()),
+			]
+		);
+		assert_eq!(app.router().processes.len(), 2);
+		app.stop().await;
+	}
+}
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::Engine;
+use hyper::header::{AUTHORIZATION, HeaderValue, WWW_AUTHENTICATE};
+use hyper::{Request, Response};
+use sha2::{Digest, Sha256};
+
+use crate::body::RebabBody;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::proxy::Connection;
+use crate::service::response;
+
+/// 検証済みとして覚えておく資格情報の数（超えたら忘れる）
+const MAX_VERIFIED: usize = 1024;
+
+/// realm を省略したときの値
+pub const DEFAULT_REALM: &str = "rebab";
+
+/// 認証に成功したユーザー（Basic のみ）
+///
+/// リクエストの extensions に入るので、後ろの Middleware や Proxy から参照できる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User(pub String);
+
+/// 認証に成功したリクエスト（Basic と Bearer のどちらでも）
+///
+/// strip_authorization で Authorization を消しても、Cache が共有してよいレスポンスか判断できるように入れる
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Authenticated;
+
+/// Basic 認証（bcrypt / argon2 のハッシュ）か Bearer トークンを要求する
+pub struct Auth {
+	realm: String,
+	/// ユーザー名とパスワードのハッシュ
+	users: BTreeMap<String, String>,
+	tokens: Vec<String>,
+	/// 認証に使った Authorization を upstream に送らない
+	strip_authorization: bool,
+	/// 検証に成功した (ユーザー, パスワード, ハッシュ) の SHA-256（bcrypt は遅いので毎回は検証しない）
+	verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl Auth {
+	/// ハッシュの形式が分からなければエラー
+	pub fn new(
+		realm: String,
+		users: BTreeMap<String, String>,
+		tokens: Vec<String>,
+		strip_authorization: bool,
+	) -> Result<Self, String> {
+		for (user, hash) in &users {
+			check_hash(hash).map_err(|e| format!("user {}: {}", user, e))?;
+		}
+		if tokens.iter().any(|v| v.is_empty()) {
+			return Err("bearer token must not be empty".to_string());
+		}
+		Ok(Self {
+			realm,
+			users,
+			tokens,
+			strip_authorization,
+			verified: Default::default(),
+		})
+	}
+
+	/// リクエストの Authorization を確かめる
+	async fn authenticate(&self, value: &HeaderValue) -> Result<Option<User>, ()> {
+		let value = value.to_str().map_err(|_| ())?;
+		let (scheme, credentials) = value.trim().split_once(' ').ok_or(())?;
+		let credentials = credentials.trim();
+		if scheme.eq_ignore_ascii_case("bearer") {
+			return match self.tokens.iter().any(|v| constant_time_eq(v, credentials)) {
+				true => Ok(None),
+				false => Err(()),
+			};
+		}
+		if !scheme.eq_ignore_ascii_case("basic") {
+			return Err(());
+		}
+		let decoded = base64::engine::general_purpose::STANDARD
+			.decode(credentials)
+			.map_err(|_| ())?;
+		let decoded = String::from_utf8(decoded).map_err(|_| ())?;
+		let (user, password) = decoded.split_once(':').ok_or(())?;
+		let hash = self.users.get(user).ok_or(())?;
+
+		let digest: [u8; 32] = Sha256::new()
+			.chain_update(user)
+			.chain_update([0])
+			.chain_update(password)
+			.chain_update([0])
+			.chain_update(hash)
+			.finalize()
+			.into();
+		if self.verified.lock().unwrap().contains(&digest) {
+			return Ok(Some(User(user.to_string())));
+		}
+		let (password, hash) = (password.to_string(), hash.clone());
+		let valid = tokio::task::spawn_blocking(move || verify(&password, &hash))
+			.await
+			.unwrap_or(false);
+		if !valid {
+			return Err(());
+		}
+		let mut verified = self.verified.lock().unwrap();
+		if verified.len() >= MAX_VERIFIED {
+			verified.clear();
+		}
+		verified.insert(digest);
+		Ok(Some(User(user.to_string())))
+	}
+
+	fn unauthorized(&self) -> Response<RebabBody> {
+		let mut resp = response(401, "rebab authentication required".to_string());
+		let realm = self.realm.replace(['"', '\\'], "");
+		if !self.users.is_empty() {
+			let v = format!(r#"Basic realm="{}", charset="UTF-8""#, realm);
+			if let Ok(v) = HeaderValue::from_str(&v) {
+				resp.headers_mut().append(WWW_AUTHENTICATE, v);
+			}
+		}
+		if !self.tokens.is_empty() {
+			let v = format!(r#"Bearer realm="{}""#, realm);
+			if let Ok(v) = HeaderValue::from_str(&v) {
+				resp.headers_mut().append(WWW_AUTHENTICATE, v);
+			}
+		}
+		resp
+	}
+}
+
+impl Middleware for Auth {
+	fn call<'a>(
+		&'a self,
+		mut req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let Some(value) = req.headers().get(AUTHORIZATION) else {
+				return self.unauthorized();
+			};
+			match self.authenticate(value).await {
+				Ok(user) => {
+					if let Some(user) = user {
+						req.extensions_mut().insert(user);
+					}
+					req.extensions_mut().insert(Authenticated);
+				}
+				Err(()) => {
+					crate::log::log(format!(
+						"{}: authentication failed for {}",
+						conn.client_ip,
+						req.uri().path()
+					));
+					return self.unauthorized();
+				}
+			}
+			if self.strip_authorization {
+				req.headers_mut().remove(AUTHORIZATION);
+			}
+			next.run(req, conn).await
+		})
+	}
+}
+
+/// 対応するハッシュか（bcrypt: `$2b$...`、argon2: `$argon2id$...`）
+pub fn check_hash(hash: &str) -> Result<(), String> {
+	if ["$2a$", "$2b$", "$2x$", "$2y$"]
+		.iter()
+		.any(|v| hash.starts_with(v))
+	{
+		return hash
+			.parse::<bcrypt::HashParts>()
+			.map(|_| ())
+			.map_err(|e| format!("invalid bcrypt hash: {}", e));
+	}
+	if hash.starts_with("$argon2") {
+		return PasswordHash::new(hash)
+			.map(|_| ())
+			.map_err(|e| format!("invalid argon2 hash: {}", e));
+	}
+	Err("password hash must be bcrypt ($2b$...) or argon2 ($argon2id$...)".to_string())
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+	if hash.starts_with("$argon2") {
+		return PasswordHash::new(hash).is_ok_and(|v| {
+			Argon2::default()
+				.verify_password(password.as_bytes(), &v)
+				.is_ok()
+		});
+	}
+	bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// 長さ以外の情報が比較時間から漏れないように比べる
+fn constant_time_eq(a: &str, b: &str) -> bool {
+	a.len() == b.len()
+		&& a.bytes()
+			.zip(b.bytes())
+			.fold(0u8, |acc, (x, y)| acc | (x ^ y))
+			== 0
+}
+
+/// htpasswd ファイル（`user:hash` の行）を読む。空行と `#` で始まる行は無視する
+pub fn read_htpasswd(path: &Path) -> Result<BTreeMap<String, String>, String> {
+	let text = std::fs::read_to_string(path)
+		.map_err(|e| format!("failed to read htpasswd {}: {}", path.display(), e))?;
+	text.lines()
+		.map(str::trim)
+		.filter(|v| !v.is_empty() && !v.starts_with('#'))
+		.map(|line| {
+			let (user, hash) = line
+				.split_once(':')
+				.ok_or_else(|| format!("invalid htpasswd line in {}: {}", path.display(), line))?;
+			check_hash(hash)
+				.map_err(|e| format!("htpasswd {} user {}: {}", path.display(), user, e))?;
+			Ok((user.to_string(), hash.to_string()))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ARGON2: &str = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$leF08Fu/gOi7XGf5NvDjbfR9GY+siUuPd+cKkq57H/c";
+
+	fn auth() -> Auth {
+		let users = BTreeMap::from([
+			("alice".to_string(), bcrypt::hash("secret", 4).unwrap()),
+			("bob".to_string(), ARGON2.to_string()),
+		]);
+		Auth::new("test".to_string(), users, vec!["tok".to_string()], false).unwrap()
+	}
+
+	fn basic(user: &str, password: &str) -> HeaderValue {
+		let credentials =
+			base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+		HeaderValue::from_str(&format!("Basic {}", credentials)).unwrap()
+	}
+
+	#[tokio::test]
+	async fn basic_with_bcrypt_and_argon2() {
+		let auth = auth();
+		let alice = Some(User("alice".to_string()));
+		assert_eq!(
+			auth.authenticate(&basic("alice", "secret")).await,
+			Ok(alice.clone())
+		);
+		// 2 回目は検証済みのキャッシュから
+		assert_eq!(
+			auth.authenticate(&basic("alice", "secret")).await,
+			Ok(alice)
+		);
+		assert_eq!(auth.authenticate(&basic("alice", "wrong")).await, Err(()));
+		assert_eq!(
+			auth.authenticate(&basic("bob", "hunter2")).await,
+			Ok(Some(User("bob".to_string())))
+		);
+		assert_eq!(auth.authenticate(&basic("carol", "secret")).await, Err(()));
+	}
+
+	#[tokio::test]
+	async fn bearer() {
+		let auth = auth();
+		let header = |v: &str| HeaderValue::from_str(v).unwrap();
+		assert_eq!(auth.authenticate(&header("Bearer tok")).await, Ok(None));
+		assert_eq!(auth.authenticate(&header("bearer  tok ")).await, Ok(None));
+		assert_eq!(auth.authenticate(&header("Bearer to")).await, Err(()));
+		assert_eq!(auth.authenticate(&header("Token tok")).await, Err(()));
+	}
+
+	#[test]
+	fn unsupported_hashes() {
+		assert!(check_hash(ARGON2).is_ok());
+		assert!(check_hash("$apr1$salt$hash").is_err());
+		assert!(check_hash("{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=").is_err());
+		assert!(check_hash("$2b$04$short").is_err());
+	}
+}
@@ -1,15 +1,55 @@
 use std::{
+	any::Any,
 	pin::Pin,
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
 	task::{Context, Poll},
 };
 
+use bytes::BytesMut;
 use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use tokio::io::AsyncRead;
+
+/// Incoming は hyper::Error を返すので、双方に共通なエラー型として Box<dyn Error + Send + Sync> に寄せる
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// ファイルを読み出すときのチャンクサイズ
+const FILE_CHUNK: usize = 64 * 1024;
 
 // --- 自前 Body ---------------------------------------------------------------
-/// Incoming（ストリーム）か、静的 Bytes かを一つの Body で表現
+/// Incoming（ストリーム）か、静的 Bytes か、ファイルかを一つの Body で表現
 pub enum RebabBody {
 	Incoming(Incoming),
 	Static(Option<Bytes>), // 1回だけ data() を返して終わる
+	File(tokio::io::Take<tokio::fs::File>, BytesMut), // 残りバイト数まで読み出して終わる（読み出し用のバッファを使い回す）
+	Reader(Pin<Box<dyn AsyncRead + Send>>, BytesMut), // 圧縮など、長さの分からないストリーム
+	Guarded(Box<RebabBody>, Box<dyn Any + Send>),     // 送り終える（drop される）まで値を保持する
+	Limited(Box<RebabBody>, u64, Arc<AtomicBool>), // 残りバイト数を超えたらフラグを立ててエラーにする
+	Boxed(Pin<Box<dyn Body<Data = Bytes, Error = BoxError> + Send>>), // 他のモジュールで実装した Body
+}
+
+impl RebabBody {
+	/// ファイルの残りバイト数まで読み出す
+	pub fn file(file: tokio::io::Take<tokio::fs::File>) -> Self {
+		RebabBody::File(file, BytesMut::new())
+	}
+
+	/// 終わりまで読み出す
+	pub fn reader(reader: impl AsyncRead + Send + 'static) -> Self {
+		RebabBody::Reader(Box::pin(reader), BytesMut::new())
+	}
+
+	/// ボディを送り終えるまで `guard` を保持する（同時実行数の枠など）
+	pub fn guarded(self, guard: impl Any + Send) -> Self {
+		RebabBody::Guarded(Box::new(self), Box::new(guard))
+	}
+
+	/// `max` バイトを超えたら `exceeded` を立て、エラーで打ち切る
+	pub fn limited(self, max: u64, exceeded: Arc<AtomicBool>) -> Self {
+		RebabBody::Limited(Box::new(self), max, exceeded)
+	}
 }
 
 impl From<Incoming> for RebabBody {
@@ -30,8 +70,7 @@ impl From<Bytes> for RebabBody {
 
 impl Body for RebabBody {
 	type Data = Bytes;
-	// Incoming は hyper::Error を返すので、双方に共通なエラー型として Box<dyn Error + Send + Sync> に寄せる
-	type Error = Box<dyn std::error::Error + Send + Sync>;
+	type Error = BoxError;
 
 	fn poll_frame(
 		self: Pin<&mut Self>,
@@ -59,6 +98,41 @@ impl Body for RebabBody {
 					Poll::Ready(None)
 				}
 			}
+			RebabBody::File(file, buf) => {
+				if file.limit() == 0 {
+					return Poll::Ready(None);
+				}
+				poll_read(Pin::new(file), cx, buf)
+			}
+			RebabBody::Reader(reader, buf) => poll_read(reader.as_mut(), cx, buf),
+			RebabBody::Guarded(body, _) => Pin::new(body.as_mut()).poll_frame(cx),
+			RebabBody::Boxed(body) => body.as_mut().poll_frame(cx),
+			RebabBody::Limited(body, remaining, exceeded) => {
+				let frame = Pin::new(body.as_mut()).poll_frame(cx);
+				if let Poll::Ready(Some(Ok(frame))) = &frame
+					&& let Some(data) = frame.data_ref()
+				{
+					match remaining.checked_sub(data.len() as u64) {
+						Some(v) => *remaining = v,
+						None => {
+							exceeded.store(true, Ordering::SeqCst);
+							return Poll::Ready(Some(Err("request body too large".into())));
+						}
+					}
+				}
+				frame
+			}
+		}
+	}
+
+	fn is_end_stream(&self) -> bool {
+		match self {
+			RebabBody::Incoming(inc) => inc.is_end_stream(),
+			RebabBody::Static(slot) => slot.is_none(),
+			RebabBody::File(file, _) => file.limit() == 0,
+			RebabBody::Reader(..) => false,
+			RebabBody::Guarded(body, _) | RebabBody::Limited(body, _, _) => body.is_end_stream(),
+			RebabBody::Boxed(body) => body.is_end_stream(),
 		}
 	}
 
@@ -67,6 +141,28 @@ impl Body for RebabBody {
 			RebabBody::Incoming(inc) => inc.size_hint(),
 			RebabBody::Static(Some(b)) => SizeHint::with_exact(b.len() as u64),
 			RebabBody::Static(None) => SizeHint::with_exact(0),
+			RebabBody::File(file, _) => SizeHint::with_exact(file.limit()),
+			RebabBody::Reader(..) => SizeHint::default(),
+			RebabBody::Boxed(body) => body.size_hint(),
+			RebabBody::Guarded(body, _) | RebabBody::Limited(body, _, _) => body.size_hint(),
 		}
 	}
 }
+
+/// 最大 FILE_CHUNK バイトを読み、1 フレームにする（0 バイトなら終わり）
+///
+/// `buf` の空き容量に直接読み込み、読めた分だけ切り出す（送り終えた分の領域は次の reserve で再利用される）
+fn poll_read(
+	reader: Pin<&mut (impl AsyncRead + ?Sized)>,
+	cx: &mut Context<'_>,
+	buf: &mut BytesMut,
+) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+	buf.reserve(FILE_CHUNK);
+	match tokio_util::io::poll_read_buf(reader, cx, buf) {
+		// 読み終わった（ファイルなら途中で短くなった）
+		Poll::Ready(Ok(0)) => Poll::Ready(None),
+		Poll::Ready(Ok(_)) => Poll::Ready(Some(Ok(Frame::data(buf.split().freeze())))),
+		Poll::Ready(Err(e)) => Poll::Ready(Some(Err(Box::new(e)))),
+		Poll::Pending => Poll::Pending,
+	}
+}
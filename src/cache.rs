@@ -0,0 +1,1176 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{
+	AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES, HeaderMap, HeaderName,
+	HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, PRAGMA, RANGE, SET_COOKIE, VARY,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use lru::LruCache;
+use tokio::sync::watch;
+
+use crate::auth::Authenticated;
+use crate::body::{BoxError, RebabBody};
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::proxy::{Connection, Upstream};
+
+/// メモリに置くボディの合計の既定値（バイト）
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// キャッシュするレスポンスのボディの最大の既定値（バイト）
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 8 * 1024 * 1024;
+
+/// ディスクに置くボディの合計の既定値（バイト）
+pub const DEFAULT_DISK_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// 同じリクエストの upstream からの取得を待つ最大時間（過ぎたら自分で取りに行く）
+const COLLAPSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 保存できるステータス（RFC 9110 15.1 の heuristically cacheable なもの）
+const CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// キャッシュの結果を載せるヘッダ
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// ルートの転送先（レスポンスを返した後の再検証に使う）
+///
+/// 転送するルートでは、ルートの Middleware に渡すリクエストの extensions に入っている
+#[derive(Clone)]
+pub(crate) struct Origin(pub Upstream);
+
+/// キャッシュの結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+	/// 新しいキャッシュから返した
+	Hit,
+	/// upstream から取得した（保存できれば保存した）
+	Miss,
+	/// 古いキャッシュを返し、裏で再検証した（stale-while-revalidate）
+	Stale,
+	/// upstream に確かめ（304）、キャッシュから返した
+	Revalidated,
+	/// キャッシュを使わなかった（GET と HEAD 以外、Range、no-store）
+	Bypass,
+}
+
+impl Outcome {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Outcome::Hit => "HIT",
+			Outcome::Miss => "MISS",
+			Outcome::Stale => "STALE",
+			Outcome::Revalidated => "REVALIDATED",
+			Outcome::Bypass => "BYPASS",
+		}
+	}
+}
+
+/// Cache-Control の指示（知らないものは無視する）
+#[derive(Debug, Default, PartialEq)]
+struct Directives {
+	no_store: bool,
+	no_cache: bool,
+	private: bool,
+	public: bool,
+	must_revalidate: bool,
+	max_age: Option<u64>,
+	s_maxage: Option<u64>,
+	stale_while_revalidate: Option<u64>,
+}
+
+impl Directives {
+	fn parse(headers: &HeaderMap) -> Self {
+		let mut directives = Self::default();
+		let values = headers
+			.get_all(CACHE_CONTROL)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','));
+		for v in values {
+			let (name, value) = match v.split_once('=') {
+				Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+				None => (v, None),
+			};
+			let seconds = value.and_then(|v| v.parse::<u64>().ok());
+			match name.trim().to_ascii_lowercase().as_str() {
+				"no-store" => directives.no_store = true,
+				"no-cache" => directives.no_cache = true,
+				"private" => directives.private = true,
+				"public" => directives.public = true,
+				"must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+				"max-age" => directives.max_age = seconds.or(Some(0)),
+				"s-maxage" => directives.s_maxage = seconds.or(Some(0)),
+				"stale-while-revalidate" => directives.stale_while_revalidate = seconds,
+				_ => {}
+			}
+		}
+		// Cache-Control の無い HTTP/1.0 のクライアント
+		if !headers.contains_key(CACHE_CONTROL)
+			&& headers
+				.get(PRAGMA)
+				.and_then(|v| v.to_str().ok())
+				.is_some_and(|v| v.to_ascii_lowercase().contains("no-cache"))
+		{
+			directives.no_cache = true;
+		}
+		directives
+	}
+}
+
+/// 保存したレスポンス（ボディは Store が持つ）
+#[derive(Debug)]
+struct Entry {
+	status: StatusCode,
+	headers: HeaderMap,
+	/// Vary に挙がったリクエストヘッダと、保存したときの値
+	vary: Vec<(HeaderName, String)>,
+	/// 受け取った時刻
+	stored: Instant,
+	/// 受け取った時点での経過時間（Age と Date から）
+	initial_age: Duration,
+	/// 新しいとみなせる期間
+	fresh_for: Duration,
+	/// 新しくなくなってから、裏で再検証しながら返してよい期間
+	stale_while_revalidate: Duration,
+}
+
+impl Entry {
+	/// 保存できるレスポンスなら Entry にする
+	///
+	/// `req` は Vary の値を見るためのリクエストヘッダ、`authorized` は認証したリクエストか（fn authorized）
+	fn new(
+		status: StatusCode,
+		headers: &HeaderMap,
+		req: &HeaderMap,
+		authorized: bool,
+	) -> Option<Self> {
+		if !CACHEABLE_STATUS.contains(&status.as_u16()) || headers.contains_key(SET_COOKIE) {
+			return None;
+		}
+		let directives = Directives::parse(headers);
+		if directives.no_store || directives.private {
+			return None;
+		}
+		// 認証したリクエストへのレスポンスは、共有してよいと明示されたときだけ
+		if authorized
+			&& !(directives.public || directives.s_maxage.is_some() || directives.must_revalidate)
+		{
+			return None;
+		}
+		let mut vary = vec![];
+		for name in list(headers, VARY) {
+			if name == "*" {
+				return None;
+			}
+			let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+			let value = joined(req, &name);
+			vary.push((name, value));
+		}
+
+		let now = SystemTime::now();
+		let date = http_date(headers, DATE);
+		let fresh_for = match (
+			directives.s_maxage.or(directives.max_age),
+			directives.no_cache,
+		) {
+			(_, true) => Duration::ZERO,
+			(Some(v), _) => Duration::from_secs(v),
+			// 読めない Expires は過去とみなす
+			(None, _) if headers.contains_key(EXPIRES) => http_date(headers, EXPIRES)
+				.and_then(|v| v.duration_since(date.unwrap_or(now)).ok())
+				.unwrap_or_default(),
+			(None, _) => Duration::ZERO,
+		};
+		// 再検証できず、すぐに古くなるものは保存しても使えない
+		if fresh_for.is_zero()
+			&& !headers.contains_key(ETAG)
+			&& !headers.contains_key(LAST_MODIFIED)
+		{
+			return None;
+		}
+		let age = headers
+			.get(AGE)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.trim().parse::<u64>().ok())
+			.map(Duration::from_secs)
+			.unwrap_or_default();
+		let apparent_age = date
+			.and_then(|v| now.duration_since(v).ok())
+			.unwrap_or_default();
+		let stale_while_revalidate = match directives.no_cache || directives.must_revalidate {
+			true => Duration::ZERO,
+			false => Duration::from_secs(directives.stale_while_revalidate.unwrap_or(0)),
+		};
+		Some(Self {
+			status,
+			headers: headers.clone(),
+			vary,
+			stored: Instant::now(),
+			initial_age: age.max(apparent_age),
+			fresh_for,
+			stale_while_revalidate,
+		})
+	}
+
+	/// 304 のヘッダで更新したもの（保存できなくなっていれば None）
+	fn refresh(&self, not_modified: &HeaderMap, req: &HeaderMap, authorized: bool) -> Option<Self> {
+		let mut headers = self.headers.clone();
+		for name in not_modified.keys() {
+			if name == CONTENT_LENGTH {
+				continue;
+			}
+			headers.remove(name);
+			for value in not_modified.get_all(name) {
+				headers.append(name, value.clone());
+			}
+		}
+		Self::new(self.status, &headers, req, authorized)
+	}
+
+	fn age(&self) -> Duration {
+		self.initial_age + self.stored.elapsed()
+	}
+
+	/// リクエストの Vary の値が保存したときと同じか
+	fn matches(&self, req: &HeaderMap) -> bool {
+		self.vary
+			.iter()
+			.all(|(name, value)| joined(req, name) == *value)
+	}
+
+	/// 新しいか（リクエストの max-age が短ければそれに従う）
+	fn is_fresh(&self, age: Duration, max_age: Option<u64>) -> bool {
+		age < self.fresh_for && max_age.is_none_or(|v| age < Duration::from_secs(v))
+	}
+
+	/// 古いが、再検証しながら返してよいか
+	fn serves_stale(&self, age: Duration) -> bool {
+		age < self.fresh_for + self.stale_while_revalidate
+	}
+}
+
+/// ディスクへの書き込みと削除（ロックの外で行う）
+enum DiskOp {
+	Write(u64, Bytes),
+	Delete(u64),
+}
+
+struct Slot {
+	key: String,
+	entry: Arc<Entry>,
+}
+
+/// 保存したレスポンスと、メモリとディスクの LRU
+///
+/// メモリから溢れたボディはディスクに移し、ディスクから読んだものはメモリに戻す
+struct Store {
+	entries: HashMap<u64, Slot>,
+	/// キー（ホストとパス）ごとの、Vary の値の違うもの
+	variants: HashMap<String, Vec<u64>>,
+	memory: LruCache<u64, Bytes>,
+	memory_size: u64,
+	max_size: u64,
+	/// ディスクに書いたボディの長さ
+	disk: LruCache<u64, u64>,
+	disk_size: u64,
+	/// None ならディスクを使わない
+	disk_max_size: Option<u64>,
+	next_id: u64,
+}
+
+impl Store {
+	fn new(max_size: u64, disk_max_size: Option<u64>) -> Self {
+		Self {
+			entries: HashMap::new(),
+			variants: HashMap::new(),
+			memory: LruCache::unbounded(),
+			memory_size: 0,
+			max_size,
+			disk: LruCache::unbounded(),
+			disk_size: 0,
+			disk_max_size,
+			next_id: 0,
+		}
+	}
+
+	/// リクエストに合うもの。ボディが None ならディスクにある
+	///
+	/// ディスクへ書いている途中のものは見つからない
+	fn find(&mut self, key: &str, req: &HeaderMap) -> Option<(u64, Arc<Entry>, Option<Bytes>)> {
+		let id = *self
+			.variants
+			.get(key)?
+			.iter()
+			.find(|id| self.entries[id].entry.matches(req))?;
+		let entry = self.entries[&id].entry.clone();
+		if let Some(body) = self.memory.get(&id) {
+			return Some((id, entry, Some(body.clone())));
+		}
+		self.disk.get(&id)?;
+		Some((id, entry, None))
+	}
+
+	fn insert(&mut self, key: String, entry: Entry, body: Bytes) -> Vec<DiskOp> {
+		// 同じ Vary の値のものを置き換える
+		let replaced: Vec<u64> = self
+			.variants
+			.get(&key)
+			.into_iter()
+			.flatten()
+			.filter(|id| self.entries[id].entry.vary == entry.vary)
+			.copied()
+			.collect();
+		let mut ops: Vec<DiskOp> = replaced
+			.into_iter()
+			.flat_map(|id| self.remove(id))
+			.collect();
+		let id = self.next_id;
+		self.next_id += 1;
+		self.variants.entry(key.clone()).or_default().push(id);
+		self.entries.insert(
+			id,
+			Slot {
+				key,
+				entry: Arc::new(entry),
+			},
+		);
+		ops.extend(self.put_memory(id, body));
+		ops
+	}
+
+	/// ボディをメモリに置き、溢れた古いものをディスクに移すか捨てる
+	fn put_memory(&mut self, id: u64, body: Bytes) -> Vec<DiskOp> {
+		self.memory_size += body.len() as u64;
+		if let Some(old) = self.memory.put(id, body) {
+			self.memory_size -= old.len() as u64;
+		}
+		let mut ops = vec![];
+		while self.memory_size > self.max_size
+			&& let Some((id, body)) = self.memory.pop_lru()
+		{
+			self.memory_size -= body.len() as u64;
+			if self.disk.contains(&id) {
+				continue;
+			}
+			match self.disk_max_size {
+				Some(max) if body.len() as u64 <= max => ops.push(DiskOp::Write(id, body)),
+				_ => self.forget(id),
+			}
+		}
+		ops
+	}
+
+	/// ディスクに書き終えた
+	fn written(&mut self, id: u64, len: u64) -> Vec<DiskOp> {
+		// 書いている間に消されたか置き換えられた
+		if !self.entries.contains_key(&id) {
+			return vec![DiskOp::Delete(id)];
+		}
+		self.disk.put(id, len);
+		self.disk_size += len;
+		let mut ops = vec![];
+		while self.disk_size > self.disk_max_size.unwrap_or(0)
+			&& let Some((id, len)) = self.disk.pop_lru()
+		{
+			self.disk_size -= len;
+			ops.push(DiskOp::Delete(id));
+			if !self.memory.contains(&id) {
+				self.forget(id);
+			}
+		}
+		ops
+	}
+
+	/// ディスクから読んだボディをメモリにも置く
+	fn promote(&mut self, id: u64, body: Bytes) -> Vec<DiskOp> {
+		match self.entries.contains_key(&id) {
+			true => self.put_memory(id, body),
+			false => vec![],
+		}
+	}
+
+	fn refresh(&mut self, id: u64, entry: Arc<Entry>) {
+		if let Some(slot) = self.entries.get_mut(&id) {
+			slot.entry = entry;
+		}
+	}
+
+	fn remove(&mut self, id: u64) -> Vec<DiskOp> {
+		if let Some(body) = self.memory.pop(&id) {
+			self.memory_size -= body.len() as u64;
+		}
+		let mut ops = vec![];
+		if let Some(len) = self.disk.pop(&id) {
+			self.disk_size -= len;
+			ops.push(DiskOp::Delete(id));
+		}
+		self.forget(id);
+		ops
+	}
+
+	/// キーのものをすべて消す
+	fn invalidate(&mut self, key: &str) -> Vec<DiskOp> {
+		let ids = self.variants.get(key).cloned().unwrap_or_default();
+		ids.into_iter().flat_map(|id| self.remove(id)).collect()
+	}
+
+	/// メタデータを消す（ボディは呼び出し側で片付ける）
+	fn forget(&mut self, id: u64) {
+		let Some(slot) = self.entries.remove(&id) else {
+			return;
+		};
+		if let Some(ids) = self.variants.get_mut(&slot.key) {
+			ids.retain(|v| *v != id);
+			if ids.is_empty() {
+				self.variants.remove(&slot.key);
+			}
+		}
+	}
+}
+
+/// ディスクの置き場所と大きさ
+#[derive(Debug, Clone)]
+pub struct DiskTier {
+	pub dir: PathBuf,
+	pub max_size: u64,
+}
+
+struct Inner {
+	store: Mutex<Store>,
+	/// upstream から取得中のキー（終わると Sender が drop され、待っている側が起きる）
+	pending: Mutex<HashMap<String, watch::Receiver<()>>>,
+	max_entry_size: u64,
+	dir: Option<PathBuf>,
+	/// dir を使っている印（Cache::new）
+	lock: Option<File>,
+}
+
+/// 取得中の印（drop すると、待っているリクエストがキャッシュを見直す）
+struct InFlight {
+	inner: Arc<Inner>,
+	key: String,
+	_done: watch::Sender<()>,
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.inner.pending.lock().unwrap().remove(&self.key);
+	}
+}
+
+impl Drop for Inner {
+	/// 書き込み中のタスクも Inner を持っているので、すべて終わってから消す
+	fn drop(&mut self) {
+		if let Some(dir) = &self.dir {
+			let _ = std::fs::remove_dir_all(dir);
+			// 開いたままでは消せないプラットフォームがある
+			drop(self.lock.take());
+			let _ = std::fs::remove_file(lock_path(dir));
+		}
+	}
+}
+
+impl Inner {
+	/// 取得を始める。ほかのリクエストが取得中なら、その終わりを待つ Receiver
+	fn begin(self: &Arc<Self>, key: &str) -> Result<InFlight, watch::Receiver<()>> {
+		let mut pending = self.pending.lock().unwrap();
+		if let Some(v) = pending.get(key) {
+			return Err(v.clone());
+		}
+		let (done, waiting) = watch::channel(());
+		pending.insert(key.to_string(), waiting);
+		Ok(InFlight {
+			inner: self.clone(),
+			key: key.to_string(),
+			_done: done,
+		})
+	}
+
+	fn path(&self, id: u64) -> Option<PathBuf> {
+		Some(self.dir.as_ref()?.join(format!("{}.cache", id)))
+	}
+
+	fn apply(self: &Arc<Self>, ops: Vec<DiskOp>) {
+		for op in ops {
+			match op {
+				DiskOp::Write(id, body) => {
+					let Some(path) = self.path(id) else {
+						continue;
+					};
+					let inner = self.clone();
+					tokio::spawn(async move {
+						// 読む側に書きかけのファイルを見せない
+						let tmp = path.with_extension("tmp");
+						let written = match tokio::fs::write(&tmp, &body).await {
+							Ok(()) => tokio::fs::rename(&tmp, &path).await,
+							Err(e) => Err(e),
+						};
+						let ops = match written {
+							Ok(()) => inner.store.lock().unwrap().written(id, body.len() as u64),
+							Err(e) => {
+								crate::log::log(format!(
+									"failed to write cache {}: {}",
+									path.display(),
+									e
+								));
+								let _ = tokio::fs::remove_file(&tmp).await;
+								inner.store.lock().unwrap().remove(id)
+							}
+						};
+						inner.apply(ops);
+					});
+				}
+				DiskOp::Delete(id) => {
+					if let Some(path) = self.path(id) {
+						tokio::spawn(async move {
+							let _ = tokio::fs::remove_file(path).await;
+						});
+					}
+				}
+			}
+		}
+	}
+
+	/// リクエストに合うものとそのボディ（ディスクにあれば読む）
+	async fn lookup(
+		self: &Arc<Self>,
+		key: &str,
+		req: &HeaderMap,
+	) -> Option<(u64, Arc<Entry>, Bytes)> {
+		let (id, entry, body) = self.store.lock().unwrap().find(key, req)?;
+		if let Some(body) = body {
+			return Some((id, entry, body));
+		}
+		match tokio::fs::read(self.path(id)?).await {
+			Ok(v) => {
+				let body = Bytes::from(v);
+				let ops = self.store.lock().unwrap().promote(id, body.clone());
+				self.apply(ops);
+				Some((id, entry, body))
+			}
+			Err(_) => {
+				let ops = self.store.lock().unwrap().remove(id);
+				self.apply(ops);
+				None
+			}
+		}
+	}
+
+	fn insert(self: &Arc<Self>, key: String, entry: Entry, body: Bytes) {
+		let ops = self.store.lock().unwrap().insert(key, entry, body);
+		self.apply(ops);
+	}
+
+	fn remove(self: &Arc<Self>, id: u64) {
+		let ops = self.store.lock().unwrap().remove(id);
+		self.apply(ops);
+	}
+
+	/// 裏で再検証した結果でキャッシュを更新する
+	///
+	/// 304 ならヘッダを更新し、保存できる新しいレスポンスなら置き換える。upstream のエラーなら古いものを残す
+	async fn update(
+		self: &Arc<Self>,
+		key: String,
+		cached: (u64, Arc<Entry>),
+		resp: Response<RebabBody>,
+		req: &HeaderMap,
+		authorized: bool,
+	) {
+		let (id, entry) = cached;
+		if resp.status() == StatusCode::NOT_MODIFIED {
+			match entry.refresh(resp.headers(), req, authorized) {
+				Some(v) => self.store.lock().unwrap().refresh(id, Arc::new(v)),
+				None => self.remove(id),
+			}
+			return;
+		}
+		if resp.status().is_server_error() {
+			return;
+		}
+		let (parts, body) = resp.into_parts();
+		let Some(entry) = Entry::new(parts.status, &parts.headers, req, authorized) else {
+			self.remove(id);
+			return;
+		};
+		let limited = http_body_util::Limited::new(body, self.max_entry_size as usize);
+		match limited.collect().await {
+			Ok(v) => self.insert(key, entry, v.to_bytes()),
+			Err(_) => self.remove(id),
+		}
+	}
+}
+
+/// upstream のボディをクライアントに流しながら溜め、最後まで読めたらキャッシュに入れる
+struct Tee {
+	body: RebabBody,
+	buffer: Vec<u8>,
+	max: u64,
+	/// 溜めたボディを渡す（大きすぎたか、途中でエラーになれば None）
+	store: Option<Box<dyn FnOnce(Bytes) + Send>>,
+}
+
+impl Tee {
+	fn finish(&mut self) {
+		if let Some(store) = self.store.take() {
+			store(Bytes::from(std::mem::take(&mut self.buffer)));
+		}
+	}
+}
+
+impl Body for Tee {
+	type Data = Bytes;
+	type Error = BoxError;
+
+	fn poll_frame(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+		let this = self.get_mut();
+		let frame = Pin::new(&mut this.body).poll_frame(cx);
+		match &frame {
+			Poll::Ready(Some(Ok(frame))) => {
+				if let Some(data) = frame.data_ref()
+					&& this.store.is_some()
+				{
+					if (this.buffer.len() + data.len()) as u64 > this.max {
+						this.store = None;
+						this.buffer = vec![];
+					} else {
+						this.buffer.extend_from_slice(data);
+					}
+				}
+				// 長さの分かるボディは、最後のフレームの後に poll されないことがある
+				if this.body.is_end_stream() {
+					this.finish();
+				}
+			}
+			Poll::Ready(Some(Err(_))) => this.store = None,
+			Poll::Ready(None) => this.finish(),
+			Poll::Pending => {}
+		}
+		frame
+	}
+
+	fn is_end_stream(&self) -> bool {
+		self.body.is_end_stream()
+	}
+
+	fn size_hint(&self) -> SizeHint {
+		self.body.size_hint()
+	}
+}
+
+/// GET と HEAD のレスポンスを Cache-Control に従って保存し、同じリクエストにはそれを返す
+///
+/// 結果は X-Cache ヘッダ（[`Outcome`]）に載せる。同じリクエストが同時に来れば upstream へは一つだけ送り、
+/// ほかはその結果を待つ。stale-while-revalidate の再検証は、ルートの転送先へ直接送る
+/// （そのため、ルートの Middleware の最後に置く）
+pub struct Cache {
+	inner: Arc<Inner>,
+}
+
+impl Cache {
+	/// `max_size` はメモリに置くボディの合計、`max_entry_size` は一つのボディの最大
+	///
+	/// `disk` があれば、メモリから溢れたものをそのディレクトリの下に置く
+	///
+	/// キャッシュごとに `<dir>/<pid>.<n>/` を作り、drop したら消す。再読み込みで作り直しても、
+	/// 前のキャッシュの書き込みが新しいキャッシュのファイルを上書きすることはない。
+	/// 使っている間は `<dir>/<pid>.<n>.lock` をロックしておき、ロックされていないディレクトリ
+	/// （前に起動して残ったもの）は消す。同じ cache_dir を使うほかの rebab のものは残す
+	pub fn new(max_size: u64, max_entry_size: u64, disk: Option<DiskTier>) -> Result<Self, String> {
+		static INSTANCES: AtomicU64 = AtomicU64::new(0);
+		let (dir, lock) = match &disk {
+			Some(disk) => {
+				let dir = disk.dir.join(format!(
+					"{}.{}",
+					std::process::id(),
+					INSTANCES.fetch_add(1, Ordering::Relaxed)
+				));
+				std::fs::create_dir_all(&disk.dir).map_err(|e| {
+					format!("failed to create cache dir {}: {}", disk.dir.display(), e)
+				})?;
+				// ディレクトリより先にロックするので、ディレクトリがあればロックもある
+				let lock = File::create(lock_path(&dir))
+					.and_then(|v| v.lock().map(|_| v))
+					.and_then(|v| std::fs::create_dir_all(&dir).map(|_| v))
+					.map_err(|e| format!("failed to create cache dir {}: {}", dir.display(), e))?;
+				let entries = std::fs::read_dir(&disk.dir).map_err(|e| {
+					format!("failed to read cache dir {}: {}", disk.dir.display(), e)
+				})?;
+				for path in entries.filter_map(|v| v.ok()).map(|v| v.path()) {
+					let leftover = path
+						.file_name()
+						.and_then(|v| v.to_str())
+						.and_then(|v| v.split_once('.'))
+						.is_some_and(|(pid, n)| {
+							pid.parse::<u32>().is_ok() && n.parse::<u64>().is_ok()
+						}) && path != dir && path.is_dir();
+					if leftover {
+						remove_unlocked(&path);
+					}
+				}
+				(Some(dir), Some(lock))
+			}
+			None => (None, None),
+		};
+		Ok(Self {
+			inner: Arc::new(Inner {
+				store: Mutex::new(Store::new(max_size, disk.map(|v| v.max_size))),
+				pending: Mutex::new(HashMap::new()),
+				max_entry_size,
+				dir,
+				lock,
+			}),
+		})
+	}
+
+	/// 裏で再検証を始める（すでに取得中なら何もしない）
+	///
+	/// 転送先が分からなければ false
+	fn revalidate_in_background(
+		&self,
+		key: &str,
+		req: &Request<RebabBody>,
+		conn: &Connection,
+		cached: (u64, Arc<Entry>),
+	) -> bool {
+		let Some(Origin(upstream)) = req.extensions().get::<Origin>() else {
+			return false;
+		};
+		let Ok(flight) = self.inner.begin(key) else {
+			return true;
+		};
+		let mut headers = req.headers().clone();
+		add_validators(&mut headers, &cached.1);
+		let (mut parts, ()) = Request::builder()
+			.method(Method::GET)
+			.uri(req.uri().clone())
+			.version(req.version())
+			.body(())
+			.expect("building revalidation request")
+			.into_parts();
+		parts.headers = headers;
+		let (inner, upstream, conn) = (self.inner.clone(), upstream.clone(), *conn);
+		let (key, authorized, req) = (key.to_string(), authorized(req), req.headers().clone());
+		tokio::spawn(async move {
+			let _flight = flight;
+			let resp =
+				crate::service::forward(upstream, parts, RebabBody::from(Bytes::new()), &conn)
+					.await;
+			inner.update(key, cached, resp, &req, authorized).await;
+		});
+		true
+	}
+
+	/// upstream から取得し、保存できればボディを流しながら保存する
+	async fn fetch<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+		key: String,
+		cached: Option<(u64, Arc<Entry>, Bytes)>,
+		flight: Option<InFlight>,
+	) -> Response<RebabBody> {
+		let authorized = authorized(&req);
+		let (mut parts, body) = req.into_parts();
+		let headers = parts.headers.clone();
+		let head = parts.method == Method::HEAD;
+		if let Some((_, entry, _)) = &cached {
+			add_validators(&mut parts.headers, entry);
+		}
+		let resp = next.run(Request::from_parts(parts, body), conn).await;
+
+		if let Some((id, entry, body)) = cached
+			&& resp.status() == StatusCode::NOT_MODIFIED
+		{
+			let entry = match entry.refresh(resp.headers(), &headers, authorized) {
+				Some(v) => {
+					let v = Arc::new(v);
+					self.inner.store.lock().unwrap().refresh(id, v.clone());
+					v
+				}
+				None => {
+					self.inner.remove(id);
+					entry
+				}
+			};
+			return serve(&entry, body, &headers, Outcome::Revalidated);
+		}
+		let (parts, body) = resp.into_parts();
+		let len = parts
+			.headers
+			.get(CONTENT_LENGTH)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.parse::<u64>().ok());
+		let entry = match head || len.is_some_and(|v| v > self.inner.max_entry_size) {
+			true => None,
+			false => Entry::new(parts.status, &parts.headers, &headers, authorized),
+		};
+		let body = match entry {
+			Some(entry) => {
+				let inner = self.inner.clone();
+				let store = move |body: Bytes| {
+					inner.insert(key, entry, body);
+					// 保存してから待っているリクエストを起こす
+					drop(flight);
+				};
+				RebabBody::Boxed(Box::pin(Tee {
+					body,
+					buffer: vec![],
+					max: self.inner.max_entry_size,
+					store: Some(Box::new(store)),
+				}))
+			}
+			None => body,
+		};
+		with_outcome(Response::from_parts(parts, body), Outcome::Miss)
+	}
+}
+
+impl Middleware for Cache {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let key = cache_key(&req);
+			if req.method() != Method::GET && req.method() != Method::HEAD {
+				let resp = next.run(req, conn).await;
+				// 変更したリクエストの URI のものは古くなる（RFC 9111 4.4）
+				if resp.status().is_success() || resp.status().is_redirection() {
+					let ops = self.inner.store.lock().unwrap().invalidate(&key);
+					self.inner.apply(ops);
+				}
+				return with_outcome(resp, Outcome::Bypass);
+			}
+			let directives = Directives::parse(req.headers());
+			if directives.no_store || req.headers().contains_key(RANGE) {
+				return with_outcome(next.run(req, conn).await, Outcome::Bypass);
+			}
+			// クライアントが upstream への確認を求めている
+			let revalidate = directives.no_cache || directives.max_age == Some(0);
+
+			let mut cached = self.inner.lookup(&key, req.headers()).await;
+			if let Some((id, entry, body)) = &cached
+				&& !revalidate
+			{
+				let age = entry.age();
+				if entry.is_fresh(age, directives.max_age) {
+					return serve(entry, body.clone(), req.headers(), Outcome::Hit);
+				}
+				if entry.serves_stale(age)
+					&& self.revalidate_in_background(&key, &req, conn, (*id, entry.clone()))
+				{
+					return serve(entry, body.clone(), req.headers(), Outcome::Stale);
+				}
+			}
+			// 同じものを取得中なら、その結果を待つ
+			let flight = match self.inner.begin(&key) {
+				Ok(v) => Some(v),
+				Err(mut waiting) => {
+					let _ = tokio::time::timeout(COLLAPSE_TIMEOUT, waiting.changed()).await;
+					cached = self.inner.lookup(&key, req.headers()).await;
+					if let Some((_, entry, body)) = &cached
+						&& entry.is_fresh(entry.age(), directives.max_age.filter(|v| *v > 0))
+					{
+						return serve(entry, body.clone(), req.headers(), Outcome::Hit);
+					}
+					None
+				}
+			};
+			self.fetch(req, conn, next, key, cached, flight).await
+		})
+	}
+}
+
+/// 認証したリクエストか
+///
+/// Auth が strip_authorization で Authorization を消していても、Authenticated が残っている
+fn authorized(req: &Request<RebabBody>) -> bool {
+	req.headers().contains_key(AUTHORIZATION) || req.extensions().get::<Authenticated>().is_some()
+}
+
+/// キャッシュのディレクトリを使っている間ロックしておくファイル（`<dir>.lock`）
+fn lock_path(dir: &Path) -> PathBuf {
+	let mut path = dir.as_os_str().to_owned();
+	path.push(".lock");
+	PathBuf::from(path)
+}
+
+/// ロックされていない（使っていた rebab が終わった）キャッシュのディレクトリを消す
+fn remove_unlocked(dir: &Path) {
+	let lock = lock_path(dir);
+	match File::open(&lock) {
+		Ok(file) => {
+			if file.try_lock().is_err() {
+				return;
+			}
+			let _ = std::fs::remove_dir_all(dir);
+			drop(file);
+			let _ = std::fs::remove_file(lock);
+		}
+		// ロックが無ければ、使っている rebab も無い
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+			let _ = std::fs::remove_dir_all(dir);
+		}
+		Err(_) => {}
+	}
+}
+
+/// ホストとパス（クエリを含む）
+fn cache_key(req: &Request<RebabBody>) -> String {
+	let host = req
+		.headers()
+		.get(hyper::header::HOST)
+		.and_then(|v| v.to_str().ok())
+		.or(req.uri().host())
+		.unwrap_or_default();
+	let path = req.uri().path_and_query().map_or("/", |v| v.as_str());
+	format!("{}{}", host.to_ascii_lowercase(), path)
+}
+
+/// 保存したものからレスポンスを作る（クライアントの条件付きリクエストに合えば 304）
+fn serve(entry: &Entry, body: Bytes, req: &HeaderMap, outcome: Outcome) -> Response<RebabBody> {
+	let mut resp = Response::new(RebabBody::from(body));
+	*resp.status_mut() = entry.status;
+	*resp.headers_mut() = entry.headers.clone();
+	resp.headers_mut()
+		.insert(AGE, HeaderValue::from(entry.age().as_secs()));
+	if entry.status == StatusCode::OK && not_modified(req, &entry.headers) {
+		*resp.status_mut() = StatusCode::NOT_MODIFIED;
+		*resp.body_mut() = RebabBody::from(Bytes::new());
+		resp.headers_mut().remove(CONTENT_LENGTH);
+	}
+	with_outcome(resp, outcome)
+}
+
+fn with_outcome(mut resp: Response<RebabBody>, outcome: Outcome) -> Response<RebabBody> {
+	resp.headers_mut()
+		.insert(X_CACHE, HeaderValue::from_static(outcome.as_str()));
+	resp
+}
+
+/// 保存したものの ETag と Last-Modified で条件付きリクエストにする（クライアントの条件は置き換える）
+fn add_validators(headers: &mut HeaderMap, entry: &Entry) {
+	headers.remove(IF_NONE_MATCH);
+	headers.remove(IF_MODIFIED_SINCE);
+	if let Some(v) = entry.headers.get(ETAG) {
+		headers.insert(IF_NONE_MATCH, v.clone());
+	}
+	if let Some(v) = entry.headers.get(LAST_MODIFIED) {
+		headers.insert(IF_MODIFIED_SINCE, v.clone());
+	}
+}
+
+/// クライアントの If-None-Match（無ければ If-Modified-Since）が保存したものに合うか
+fn not_modified(req: &HeaderMap, headers: &HeaderMap) -> bool {
+	if req.contains_key(IF_NONE_MATCH) {
+		let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+			return false;
+		};
+		// 弱い比較
+		let etag = etag.trim_start_matches("W/");
+		return list(req, IF_NONE_MATCH)
+			.iter()
+			.any(|v| v == "*" || v.trim_start_matches("W/") == etag);
+	}
+	match (
+		http_date(req, IF_MODIFIED_SINCE),
+		http_date(headers, LAST_MODIFIED),
+	) {
+		(Some(since), Some(modified)) => modified <= since,
+		_ => false,
+	}
+}
+
+/// カンマ区切りのヘッダの値
+fn list(headers: &HeaderMap, name: HeaderName) -> Vec<String> {
+	headers
+		.get_all(name)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.map(|v| v.trim().to_string())
+		.filter(|v| !v.is_empty())
+		.collect()
+}
+
+/// 同じ名前のヘッダを一つの値にする（Vary の比較用）
+fn joined(headers: &HeaderMap, name: &HeaderName) -> String {
+	headers
+		.get_all(name)
+		.iter()
+		.map(|v| String::from_utf8_lossy(v.as_bytes()).trim().to_string())
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+	let v = headers.get(name)?.to_str().ok()?;
+	httpdate::parse_http_date(v).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in values {
+			headers.append(*name, HeaderValue::from_str(value).unwrap());
+		}
+		headers
+	}
+
+	fn entry(values: &[(&'static str, &str)]) -> Option<Entry> {
+		Entry::new(StatusCode::OK, &headers(values), &HeaderMap::new(), false)
+	}
+
+	#[test]
+	fn freshness() {
+		let e = entry(&[("cache-control", "public, max-age=60, s-maxage=120")]).unwrap();
+		assert_eq!(e.fresh_for, Duration::from_secs(120));
+		let e = entry(&[("cache-control", "max-age=60, stale-while-revalidate=30")]).unwrap();
+		assert!(e.is_fresh(Duration::from_secs(59), None));
+		assert!(!e.is_fresh(Duration::from_secs(59), Some(10)));
+		assert!(e.serves_stale(Duration::from_secs(89)));
+		assert!(!e.serves_stale(Duration::from_secs(90)));
+		// must-revalidate なら古いものは返さない
+		let e = entry(&[(
+			"cache-control",
+			"max-age=60, stale-while-revalidate=30, must-revalidate",
+		)])
+		.unwrap();
+		assert!(!e.serves_stale(Duration::from_secs(61)));
+
+		let now = SystemTime::now();
+		let date = httpdate::fmt_http_date(now);
+		let expires = httpdate::fmt_http_date(now + Duration::from_secs(300));
+		let e = entry(&[("date", &date), ("expires", &expires)]).unwrap();
+		assert_eq!(e.fresh_for, Duration::from_secs(300));
+		// 読めない Expires は過去
+		assert!(entry(&[("expires", "0")]).is_none());
+	}
+
+	#[test]
+	fn storable() {
+		assert!(entry(&[("cache-control", "no-store, max-age=60")]).is_none());
+		assert!(entry(&[("cache-control", "private, max-age=60")]).is_none());
+		assert!(entry(&[("cache-control", "max-age=60"), ("set-cookie", "a=b")]).is_none());
+		assert!(entry(&[("cache-control", "max-age=60"), ("vary", "*")]).is_none());
+		// 再検証できれば no-cache も保存する
+		assert!(entry(&[("cache-control", "no-cache")]).is_none());
+		assert!(entry(&[("cache-control", "no-cache"), ("etag", "\"a\"")]).is_some());
+		let status = StatusCode::PARTIAL_CONTENT;
+		let resp = headers(&[("cache-control", "max-age=60")]);
+		assert!(Entry::new(status, &resp, &HeaderMap::new(), false).is_none());
+		// 認証したリクエストは public なものだけ
+		let req = HeaderMap::new();
+		assert!(Entry::new(StatusCode::OK, &resp, &req, true).is_none());
+		let public = headers(&[("cache-control", "public, max-age=60")]);
+		assert!(Entry::new(StatusCode::OK, &public, &req, true).is_some());
+	}
+
+	#[test]
+	fn vary_and_lru() {
+		let mut store = Store::new(10, None);
+		let resp = headers(&[("cache-control", "max-age=60"), ("vary", "Accept-Language")]);
+		let en = headers(&[("accept-language", "en")]);
+		let ja = headers(&[("accept-language", "ja")]);
+		let new = |req: &HeaderMap| Entry::new(StatusCode::OK, &resp, req, false).unwrap();
+		store.insert("a/".into(), new(&en), Bytes::from("hello"));
+		store.insert("a/".into(), new(&ja), Bytes::from("konni"));
+		let found = |store: &mut Store, key: &str, req: &HeaderMap| {
+			store.find(key, req).and_then(|(_, _, body)| body)
+		};
+		assert_eq!(found(&mut store, "a/", &en), Some(Bytes::from("hello")));
+		assert_eq!(found(&mut store, "a/", &ja), Some(Bytes::from("konni")));
+		assert_eq!(found(&mut store, "a/", &HeaderMap::new()), None);
+		assert_eq!(found(&mut store, "a/", &en), Some(Bytes::from("hello")));
+		// 一番前に使った ja が溢れる
+		store.insert(
+			"b/".into(),
+			entry(&[("cache-control", "max-age=60")]).unwrap(),
+			Bytes::from("xy"),
+		);
+		assert_eq!(found(&mut store, "a/", &ja), None);
+		assert_eq!(found(&mut store, "a/", &en), Some(Bytes::from("hello")));
+		assert_eq!(store.memory_size, 7);
+		store.invalidate("a/");
+		assert_eq!(found(&mut store, "a/", &en), None);
+		assert_eq!(store.memory_size, 2);
+	}
+
+	#[test]
+	fn conditional_requests() {
+		let stored = headers(&[
+			("etag", "W/\"v1\""),
+			("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT"),
+		]);
+		assert!(not_modified(
+			&headers(&[("if-none-match", "\"v0\", \"v1\"")]),
+			&stored
+		));
+		assert!(!not_modified(
+			&headers(&[("if-none-match", "\"v2\"")]),
+			&stored
+		));
+		assert!(not_modified(
+			&headers(&[("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]),
+			&stored
+		));
+		assert!(!not_modified(&HeaderMap::new(), &stored));
+	}
+
+	#[test]
+	fn leftover_dirs() {
+		let root = std::env::temp_dir().join(format!("rebab-leftover-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&root);
+		// 終わった rebab のもの、動いている rebab のもの、キャッシュではないもの
+		let (gone, alive, other) = (root.join("1.0"), root.join("1.1"), root.join("notes"));
+		for dir in [&gone, &alive, &other] {
+			std::fs::create_dir_all(dir).unwrap();
+			File::create(lock_path(dir)).unwrap();
+		}
+		let lock = File::open(lock_path(&alive)).unwrap();
+		lock.lock().unwrap();
+		let disk = DiskTier {
+			dir: root.clone(),
+			max_size: 100,
+		};
+		let cache = Cache::new(100, 100, Some(disk)).unwrap();
+		assert!(!gone.exists() && !lock_path(&gone).exists());
+		assert!(alive.exists() && other.exists());
+		let dir = cache.inner.dir.clone().unwrap();
+		assert!(dir.exists() && lock_path(&dir).exists());
+		// 動いている rebab のものとして残す
+		let second = Cache::new(
+			100,
+			100,
+			Some(DiskTier {
+				dir: root.clone(),
+				max_size: 100,
+			}),
+		)
+		.unwrap();
+		assert!(dir.exists());
+		drop(cache);
+		assert!(!dir.exists() && !lock_path(&dir).exists());
+		drop(second);
+		drop(lock);
+		std::fs::remove_dir_all(root).unwrap();
+	}
+}
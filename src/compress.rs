@@ -0,0 +1,363 @@
+use async_compression::Level;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use http_body_util::{BodyDataStream, BodyExt};
+use hyper::body::Body;
+use hyper::header::{
+	ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+	CONTENT_TYPE, ETAG, HeaderMap, HeaderValue,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use tokio_util::io::StreamReader;
+
+use crate::body::RebabBody;
+use crate::header::{add_vary, parse_q_list};
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::proxy::Connection;
+
+/// これより小さいレスポンスは圧縮しない（バイト）
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// 圧縮する Content-Type（`*` は任意の文字列）
+pub const DEFAULT_TYPES: [&str; 8] = [
+	"text/*",
+	"application/json",
+	"application/javascript",
+	"application/xml",
+	"application/wasm",
+	"image/svg+xml",
+	"application/*+json",
+	"application/*+xml",
+];
+
+/// 対応する Content-Encoding（同じ q 値なら前のものを選ぶ）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	Brotli,
+	Zstd,
+	Gzip,
+}
+
+impl Encoding {
+	const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Zstd => "zstd",
+			Encoding::Gzip => "gzip",
+		}
+	}
+
+	/// Accept-Encoding から使うものを選ぶ（対応するものが無ければ None）
+	pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+		let accepted = parse_q_list(headers, ACCEPT_ENCODING);
+		// 明示されたものが優先で、無ければ * の q 値
+		let q = |encoding: Encoding| {
+			let find = |name: &str| accepted.iter().find(|(k, _)| k == name).map(|(_, q)| *q);
+			let aliases: &[&str] = match encoding {
+				Encoding::Gzip => &["gzip", "x-gzip"],
+				_ => &[encoding.as_str()],
+			};
+			aliases
+				.iter()
+				.find_map(|v| find(v))
+				.or_else(|| find("*"))
+				.unwrap_or(0.0)
+		};
+		Self::ALL
+			.into_iter()
+			.map(|v| (v, q(v)))
+			.filter(|(_, q)| *q > 0.0)
+			.fold(None::<(Encoding, f32)>, |best, (v, q)| match best {
+				Some((_, best_q)) if best_q >= q => best,
+				_ => Some((v, q)),
+			})
+			.map(|(v, _)| v)
+	}
+}
+
+/// ルールのレスポンスを Accept-Encoding に応じて圧縮する
+#[derive(Debug, Clone)]
+pub struct Compression {
+	/// これより小さいことが分かっているレスポンスは圧縮しない
+	pub min_size: u64,
+	/// 圧縮する Content-Type
+	pub types: Vec<String>,
+}
+
+impl Default for Compression {
+	fn default() -> Self {
+		Self {
+			min_size: DEFAULT_MIN_SIZE,
+			types: DEFAULT_TYPES.iter().map(|v| v.to_string()).collect(),
+		}
+	}
+}
+
+impl Middleware for Compression {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			// HEAD はボディが無く、Content-Length を変えられない
+			let encoding = match *req.method() {
+				Method::HEAD => None,
+				_ => Encoding::negotiate(req.headers()),
+			};
+			let resp = next.run(req, conn).await;
+			self.compress(resp, encoding)
+		})
+	}
+}
+
+impl Compression {
+	fn compress(
+		&self,
+		resp: Response<RebabBody>,
+		encoding: Option<Encoding>,
+	) -> Response<RebabBody> {
+		let (mut parts, body) = resp.into_parts();
+		if !self.is_compressible(&parts.headers) {
+			return Response::from_parts(parts, body);
+		}
+		// Accept-Encoding によって中身が変わる
+		add_vary(&mut parts.headers, "Accept-Encoding");
+		let Some(encoding) = encoding else {
+			return Response::from_parts(parts, body);
+		};
+		let len = parts
+			.headers
+			.get(CONTENT_LENGTH)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.parse::<u64>().ok())
+			.or(body.size_hint().exact());
+		let skip = matches!(
+			parts.status,
+			StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+		) || parts.status.is_informational()
+			|| parts.headers.contains_key(CONTENT_ENCODING)
+			|| parts.headers.contains_key(CONTENT_RANGE)
+			|| no_transform(&parts.headers)
+			|| len.is_some_and(|v| v < self.min_size);
+		if skip {
+			return Response::from_parts(parts, body);
+		}
+
+		parts.headers.remove(CONTENT_LENGTH);
+		parts.headers.remove(ACCEPT_RANGES);
+		parts.headers.insert(
+			CONTENT_ENCODING,
+			HeaderValue::from_static(encoding.as_str()),
+		);
+		// 圧縮後のバイト列は元と同じではないので弱い ETag にする
+		if let Some(etag) = parts.headers.get(ETAG).and_then(|v| v.to_str().ok())
+			&& !etag.starts_with("W/")
+			&& let Ok(v) = HeaderValue::from_str(&format!("W/{}", etag))
+		{
+			parts.headers.insert(ETAG, v);
+		}
+
+		// ボディを読みながら圧縮する
+		let reader = StreamReader::new(BodyDataStream::new(body.map_err(std::io::Error::other)));
+		let body = match encoding {
+			// 既定の 11 はストリームには遅すぎる
+			Encoding::Brotli => {
+				RebabBody::reader(BrotliEncoder::with_quality(reader, Level::Precise(4)))
+			}
+			Encoding::Zstd => RebabBody::reader(ZstdEncoder::new(reader)),
+			Encoding::Gzip => RebabBody::reader(GzipEncoder::new(reader)),
+		};
+		Response::from_parts(parts, body)
+	}
+
+	fn is_compressible(&self, headers: &HeaderMap) -> bool {
+		let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+			return false;
+		};
+		let essence = content_type
+			.split(';')
+			.next()
+			.unwrap_or("")
+			.trim()
+			.to_ascii_lowercase();
+		self.types.iter().any(|pattern| {
+			let pattern = pattern.to_ascii_lowercase();
+			match pattern.split_once('*') {
+				Some((prefix, suffix)) => {
+					essence.len() >= prefix.len() + suffix.len()
+						&& essence.starts_with(prefix)
+						&& essence.ends_with(suffix)
+				}
+				None => essence == pattern,
+			}
+		})
+	}
+}
+
+/// Cache-Control: no-transform なら中継者が変えてはいけない
+fn no_transform(headers: &HeaderMap) -> bool {
+	headers
+		.get_all(CACHE_CONTROL)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.any(|v| v.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_compression::tokio::bufread::GzipDecoder;
+	use hyper::header::{HeaderName, VARY};
+	use tokio::io::AsyncReadExt;
+
+	const BODY: &str = "rebab compresses this line. ";
+
+	/// BODY を 100 回繰り返すレスポンスを Compression に通す
+	async fn run(
+		method: Method,
+		accept: &str,
+		status: u16,
+		headers: &[(HeaderName, &str)],
+	) -> Response<RebabBody> {
+		let req = Request::builder()
+			.method(method)
+			.header(ACCEPT_ENCODING, accept)
+			.body(RebabBody::from(String::new()))
+			.unwrap();
+		let mut resp = Response::builder()
+			.status(status)
+			.header(CONTENT_TYPE, "text/plain")
+			.header(ACCEPT_RANGES, "bytes")
+			.header(ETAG, "\"v1\"");
+		for (k, v) in headers {
+			resp = resp.header(k, *v);
+		}
+		let resp = resp.body(RebabBody::from(BODY.repeat(100))).unwrap();
+		let addr = "127.0.0.1:8080".parse().unwrap();
+		let conn = Connection {
+			peer: addr,
+			local: addr,
+			client_ip: addr.ip(),
+			trusted: false,
+			scheme: crate::config::Scheme::Http,
+		};
+		let next = Next::new(&[], Box::new(move |_, _| Box::pin(async move { resp })));
+		Compression::default().call(req, &conn, next).await
+	}
+
+	async fn body(resp: Response<RebabBody>) -> Vec<u8> {
+		resp.into_body()
+			.collect()
+			.await
+			.unwrap()
+			.to_bytes()
+			.to_vec()
+	}
+
+	#[tokio::test]
+	async fn compresses_while_streaming() {
+		let resp = run(Method::GET, "gzip", 200, &[(CONTENT_LENGTH, "2800")]).await;
+		let headers = resp.headers();
+		assert_eq!(headers[CONTENT_ENCODING], "gzip");
+		assert_eq!(headers[VARY], "Accept-Encoding");
+		assert_eq!(headers[ETAG], "W/\"v1\"");
+		assert!(!headers.contains_key(CONTENT_LENGTH));
+		assert!(!headers.contains_key(ACCEPT_RANGES));
+		let compressed = body(resp).await;
+		assert!(compressed.len() < BODY.len() * 100);
+		let mut decoded = String::new();
+		GzipDecoder::new(compressed.as_slice())
+			.read_to_string(&mut decoded)
+			.await
+			.unwrap();
+		assert_eq!(decoded, BODY.repeat(100));
+	}
+
+	#[tokio::test]
+	async fn varies_without_encoding() {
+		let resp = run(Method::GET, "identity", 200, &[]).await;
+		assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+		assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+		assert_eq!(resp.headers()[ETAG], "\"v1\"");
+		assert_eq!(body(resp).await, BODY.repeat(100).as_bytes());
+	}
+
+	#[tokio::test]
+	async fn skips() {
+		let cases = [
+			(Method::GET, 206, vec![(CONTENT_RANGE, "bytes 0-2799/5600")]),
+			(Method::HEAD, 200, vec![]),
+			(Method::GET, 200, vec![(CONTENT_ENCODING, "br")]),
+			(
+				Method::GET,
+				200,
+				vec![(CACHE_CONTROL, "max-age=60, No-Transform")],
+			),
+			(Method::GET, 200, vec![(CONTENT_LENGTH, "1023")]),
+		];
+		for (method, status, headers) in cases {
+			let resp = run(method.clone(), "gzip", status, &headers).await;
+			let encoding = resp.headers().get(CONTENT_ENCODING).cloned();
+			assert_eq!(
+				encoding,
+				headers
+					.iter()
+					.find(|(k, _)| k == CONTENT_ENCODING)
+					.map(|(_, v)| HeaderValue::from_static(v)),
+				"{} {} {:?}",
+				method,
+				status,
+				headers
+			);
+			assert_eq!(resp.headers()[ETAG], "\"v1\"");
+			assert_eq!(resp.headers()[ACCEPT_RANGES], "bytes");
+			assert_eq!(body(resp).await, BODY.repeat(100).as_bytes());
+		}
+	}
+
+	fn negotiate(accept: &str) -> Option<Encoding> {
+		let mut headers = HeaderMap::new();
+		headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(accept).unwrap());
+		Encoding::negotiate(&headers)
+	}
+
+	#[test]
+	fn prefers_brotli_on_ties() {
+		assert_eq!(negotiate("gzip, deflate, br, zstd"), Some(Encoding::Brotli));
+		assert_eq!(negotiate("gzip, zstd"), Some(Encoding::Zstd));
+		assert_eq!(negotiate("x-gzip"), Some(Encoding::Gzip));
+	}
+
+	#[test]
+	fn q_values() {
+		assert_eq!(negotiate("br;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+		assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+		assert_eq!(negotiate("*;q=0.1, br;q=0"), Some(Encoding::Zstd));
+		assert_eq!(negotiate("identity, *;q=0"), None);
+	}
+
+	#[test]
+	fn unsupported() {
+		assert_eq!(negotiate("deflate"), None);
+		assert_eq!(negotiate(""), None);
+	}
+
+	#[test]
+	fn content_types() {
+		let compression = Compression::default();
+		let compressible = |content_type: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+			compression.is_compressible(&headers)
+		};
+		assert!(compressible("text/html; charset=utf-8"));
+		assert!(compressible("application/problem+json"));
+		assert!(compressible("Application/JSON"));
+		assert!(!compressible("image/png"));
+		assert!(!compressible("application/jsonl"));
+	}
+}
@@ -1,6 +1,7 @@
 use clap::Parser;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -13,10 +14,155 @@ pub struct Router {
 		description = "Routes are evaluated in order; the first matching rule is applied."
 	)]
 	pub rules: Vec<Rule>,
+	#[schemars(
+		title = "Standalone processes",
+		description = "Commands that are managed like rule commands but serve no routes, such as workers or file watchers."
+	)]
+	#[serde(default)]
+	pub processes: Vec<Process>,
+	#[schemars(
+		title = "Log directory",
+		description = "Directory where the output of each managed process is written as '<rule name>.log'. Overridden per rule by log_file.",
+		example = "logs/"
+	)]
+	pub log_dir: Option<PathBuf>,
+	#[schemars(
+		title = "Maximum log file size in bytes",
+		description = "A log file is rotated to '<file>.1', '<file>.2', ... once it would exceed this size. Defaults to 10485760 (10 MiB).",
+		example = 10485760
+	)]
+	pub log_max_size: Option<u64>,
+	#[schemars(
+		title = "Number of rotated log files to keep",
+		description = "Defaults to 5.",
+		example = 5
+	)]
+	pub log_max_files: Option<usize>,
+	#[schemars(
+		title = "Trusted proxies",
+		description = "IP addresses or CIDR ranges of proxies in front of rebab. X-Forwarded-* and Forwarded headers are kept only when the connection comes from one of them, and replaced otherwise.",
+		example = serde_json::json!(["127.0.0.1/32", "10.0.0.0/8", "::1"])
+	)]
+	#[serde(default)]
+	pub trusted_proxies: Vec<String>,
+	#[schemars(
+		title = "Expect the PROXY protocol",
+		description = "Every connection must start with a PROXY protocol v1 or v2 header, whose source address is used as the client address. If trusted_proxies is set, connections from other addresses are rejected. Defaults to false."
+	)]
+	pub proxy_protocol: Option<bool>,
+	#[schemars(
+		title = "Scheme clients use to reach the frontend",
+		description = "Sent to backends as X-Forwarded-Proto. Set to 'https' when a TLS terminator in front of rebab does not send X-Forwarded-Proto. If omitted, a trusted proxy's X-Forwarded-Proto or Forwarded proto is used, otherwise 'http'."
+	)]
+	pub scheme: Option<Scheme>,
+	#[schemars(
+		title = "Allowed client addresses",
+		description = "IP addresses or CIDR ranges allowed to use any rule. If set, other clients get 403. The client address is the connection's, or the one from X-Forwarded-For when it comes from a trusted proxy.",
+		example = serde_json::json!(["127.0.0.1", "::1", "10.8.0.0/16"])
+	)]
+	#[serde(default)]
+	pub allow: Vec<String>,
+	#[schemars(
+		title = "Denied client addresses",
+		description = "IP addresses or CIDR ranges that get 403 for every rule. Takes precedence over allow.",
+		example = serde_json::json!(["192.0.2.0/24"])
+	)]
+	#[serde(default)]
+	pub deny: Vec<String>,
+	#[schemars(
+		title = "Maximum request body size in bytes",
+		description = "Requests with a larger body get 413, checked on Content-Length and while the body is streamed. Overridden per rule by max_body_size. No limit if omitted.",
+		example = 10485760
+	)]
+	pub max_body_size: Option<u64>,
+	#[schemars(
+		title = "Maximum request header size in bytes",
+		description = "Size of the buffer for the request line and headers. Larger requests get 431. Values below 8192 are raised to 8192. Defaults to about 400 KiB.",
+		example = 65536
+	)]
+	pub max_header_size: Option<usize>,
+	#[schemars(
+		title = "Maximum number of request headers",
+		description = "Requests with more headers get 431. Defaults to 100.",
+		example = 100
+	)]
+	pub max_headers: Option<usize>,
+	#[schemars(
+		title = "Error pages",
+		description = "HTML templates for errors rebab returns itself, such as no matching rule, 403, 413, 429 or 502 when the backend is unreachable, keyed by status or class ('404', '5xx'). Placeholders {status}, {reason}, {message}, {detail} and {path} are replaced with HTML-escaped values. Used for clients that accept text/html; clients that accept application/json get application/problem+json, and others plain text.",
+		example = serde_json::json!({"404": "errors/404.html", "5xx": "errors/5xx.html"})
+	)]
+	#[serde(default)]
+	pub error_pages: BTreeMap<String, PathBuf>,
+	#[schemars(
+		title = "Hide internal error details",
+		description = "Leaves internal details, such as why the backend could not be reached, out of error responses. They are still logged. Defaults to false."
+	)]
+	pub hide_error_details: Option<bool>,
+	#[schemars(
+		title = "Dashboard path",
+		description = "Serves a development dashboard at this path, taking precedence over rules. It shows the routes, the managed processes with their recent output, and recent requests, and has buttons to restart processes. Only clients in dashboard_allow can open it. Disabled if omitted.",
+		example = &"/__rebab"
+	)]
+	pub dashboard: Option<String>,
+	#[schemars(
+		title = "Clients allowed to use the dashboard",
+		description = "IP addresses or CIDR ranges that can open the dashboard and restart processes; other clients get 403. Defaults to loopback only (127.0.0.0/8 and ::1).",
+		example = serde_json::json!(["127.0.0.1", "::1", "10.8.0.0/16"])
+	)]
+	#[serde(default)]
+	pub dashboard_allow: Vec<String>,
+}
+
+/// クライアントが frontend に接続するスキーム
+#[derive(
+	Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+	Http,
+	Https,
+}
+
+impl Scheme {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Scheme::Http => "http",
+			Scheme::Https => "https",
+		}
+	}
+}
+
+impl FromStr for Scheme {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"http" => Ok(Scheme::Http),
+			"https" => Ok(Scheme::Https),
+			_ => Err(format!("unknown scheme: {}", s)),
+		}
+	}
+}
+
+/// weight で振り分けるクライアントのバケットの決め方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Sticky {
+	/// ランダムに決めて cookie に保存する
+	#[default]
+	Cookie,
+	/// クライアントの IP から決める
+	ClientIp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Rule {
+	#[schemars(
+		title = "Rule name",
+		description = "Label used to prefix the output of the managed process. Defaults to 'rule_N' (N is the rule index) if omitted.",
+		example = "api-server"
+	)]
+	pub name: Option<String>,
 	#[schemars(
 		title = "Path prefix",
 		description = "Matches request paths that start with this prefix. Matches all paths if omitted.",
@@ -24,6 +170,69 @@ pub struct Rule {
 	)]
 	#[serde(alias = "prefix")]
 	pub frontend_prefix: Option<String>,
+	#[schemars(
+		title = "Exact path",
+		description = "Matches only this request path (without the query string).",
+		example = "/healthz"
+	)]
+	pub path: Option<String>,
+	#[schemars(
+		title = "Segment-aware path prefix",
+		description = "Matches this path and paths below it: '/api' matches '/api' and '/api/users' but not '/apidocs'.",
+		example = "/api"
+	)]
+	pub path_prefix: Option<String>,
+	#[schemars(
+		title = "Path regular expression",
+		description = "Matches request paths against this regular expression (Rust regex syntax; anchor it with ^ and $ to match the whole path). Named captures can be used as {name} in rewrite, redirect and header values. A capture named 'rest' becomes {rest}.",
+		example = "^/users/(?P<id>[0-9]+)$"
+	)]
+	pub path_regex: Option<String>,
+	#[schemars(
+		title = "HTTP methods",
+		description = "Matches only these methods. Matches all methods if empty. In --rule, separate methods with spaces.",
+		example = ["GET", "HEAD"]
+	)]
+	#[serde(default, alias = "method", deserialize_with = "string_or_seq")]
+	pub methods: Vec<String>,
+	#[schemars(
+		title = "Query parameters",
+		description = "Matches only requests with these query parameters. null matches any value. In --rule, use 'name|name=value'.",
+		example = serde_json::json!({"debug": null, "lang": "ja"})
+	)]
+	#[serde(default, deserialize_with = "optional_value_map")]
+	pub query: BTreeMap<String, Option<String>>,
+	#[schemars(
+		title = "Request headers",
+		description = "Matches only requests with these headers. null matches any value. In --rule, use 'Name|Name=value'.",
+		example = serde_json::json!({"X-Canary": "1"})
+	)]
+	#[serde(default, deserialize_with = "optional_value_map")]
+	pub match_headers: BTreeMap<String, Option<String>>,
+	#[schemars(
+		title = "Cookies",
+		description = "Matches only requests with these cookies. null matches any value. In --rule, use 'name|name=value'.",
+		example = serde_json::json!({"beta": "true"})
+	)]
+	#[serde(default, deserialize_with = "optional_value_map")]
+	pub match_cookies: BTreeMap<String, Option<String>>,
+	#[schemars(
+		title = "Percentage of clients to match",
+		description = "Each client is assigned a fixed bucket from 0 to 99, and the rule matches clients whose bucket is below this value. For several variants, use increasing weights (10, then 30 sends 10% and 20%).",
+		example = 10
+	)]
+	pub weight: Option<u8>,
+	#[schemars(
+		title = "How weighted clients are kept on the same bucket",
+		description = "'cookie' assigns a random bucket stored in the rebab_bucket cookie, 'client_ip' derives it from the client IP. Defaults to 'cookie'."
+	)]
+	pub sticky: Option<Sticky>,
+	#[schemars(
+		title = "Rewrite the backend path",
+		description = "Path and query sent to the backend instead of the request's. Must start with /. Same placeholders as redirect, including named captures of path_regex.",
+		example = "/api/v2/users/{id}{query}"
+	)]
+	pub rewrite: Option<String>,
 	#[schemars(
 		title = "Backend host name or IP address",
 		description = "Examples: 10.84.1.84, google.com, etc. Defaults to 'localhost' if omitted.",
@@ -33,17 +242,688 @@ pub struct Rule {
 	pub backend_host: Option<String>,
 	#[schemars(
 		title = "Backend port number",
-		description = "Examples: 3000, 8080, etc. Defaults to the frontend port if omitted.",
+		description = "Examples: 3000, 8080, etc. If omitted, a free port is assigned when the rule has a command on localhost, otherwise defaults to the frontend port.",
 		example = "8080"
 	)]
 	#[serde(alias = "port")]
 	pub backend_port: Option<u16>,
 	#[schemars(
 		title = "Command to execute",
-		description = "Optional command to execute when this rule is loaded. PORT environment variable will be set to backend_port if specified.",
+		description = "Optional command to execute when this rule is loaded. PORT environment variable will be set to backend_port, and '{port}' in the command is replaced with it.",
 		example = "npm run dev"
 	)]
 	pub command: Option<String>,
+	#[schemars(
+		title = "Log file",
+		description = "File the stdout and stderr of the command are written to, in addition to the console. Defaults to '<log_dir>/<rule name>.log' if log_dir is set.",
+		example = "logs/api.log"
+	)]
+	pub log_file: Option<PathBuf>,
+	#[schemars(
+		title = "Rules this rule depends on",
+		description = "Names of rules whose commands are started before this one. If a dependency has a backend_port, this rule waits until the port accepts connections. In --rule, separate names with spaces.",
+		example = ["db", "migrate"]
+	)]
+	#[serde(default, deserialize_with = "string_or_seq")]
+	pub depends_on: Vec<String>,
+	#[schemars(
+		title = "Restart the command when files change",
+		description = "In --rule, a space-separated list of glob patterns."
+	)]
+	#[serde(default, deserialize_with = "watch_or_paths")]
+	pub watch: Option<Watch>,
+	#[schemars(
+		title = "Directory to serve static files from",
+		description = "Serves files from this directory instead of proxying. The frontend_prefix is stripped from the request path.",
+		example = "dist/"
+	)]
+	#[serde(alias = "dir")]
+	pub static_dir: Option<PathBuf>,
+	#[schemars(
+		title = "Index file",
+		description = "File served for requests to a directory of static_dir. Defaults to 'index.html'.",
+		example = "index.html"
+	)]
+	pub index_file: Option<String>,
+	#[schemars(
+		title = "SPA fallback",
+		description = "Serves the index file of static_dir for paths that do not exist, for single page applications. Defaults to false."
+	)]
+	pub spa_fallback: Option<bool>,
+	#[schemars(
+		title = "Redirect location",
+		description = "Responds with a redirect instead of proxying. Placeholders: {host} (request host without port), {path}, {query} ('?...' or empty), {uri} (path and query), {rest} (path after the matched prefix), {prefix} (matched prefix), {client_ip}, {request_id}, and named captures of path_regex.",
+		example = "https://{host}{uri}"
+	)]
+	pub redirect: Option<String>,
+	#[schemars(
+		title = "Fixed response status",
+		description = "Responds with this status instead of proxying. With redirect, the redirect status, 300 to 399 (defaults to 302).",
+		example = 200
+	)]
+	pub status: Option<u16>,
+	#[schemars(
+		title = "Fixed response body",
+		description = "Responds with this body instead of proxying. The status defaults to 200.",
+		example = &"OK"
+	)]
+	pub body: Option<String>,
+	#[schemars(
+		title = "Fixed response headers",
+		description = "Headers of the redirect or fixed response. In --rule, 'Name: value' pairs separated by '|'.",
+		example = serde_json::json!({"Content-Type": "text/plain"})
+	)]
+	#[serde(default, deserialize_with = "header_map")]
+	pub headers: BTreeMap<String, String>,
+	#[schemars(
+		title = "Request header operations",
+		description = "Applied to the request forwarded to the backend."
+	)]
+	pub request_headers: Option<HeaderOps>,
+	#[schemars(
+		title = "Response header operations",
+		description = "Applied to every response of this rule."
+	)]
+	pub response_headers: Option<HeaderOps>,
+	#[schemars(
+		title = "Compress responses",
+		description = "Compresses responses with br, zstd or gzip according to Accept-Encoding. Responses that are already encoded, partial, or marked Cache-Control: no-transform are passed through. Defaults to false."
+	)]
+	pub compress: Option<bool>,
+	#[schemars(
+		title = "Minimum size to compress",
+		description = "Responses whose Content-Length is smaller than this are not compressed. Responses of unknown length are always compressed. Defaults to 1024 bytes.",
+		example = 1024
+	)]
+	pub compress_min_size: Option<u64>,
+	#[schemars(
+		title = "Content types to compress",
+		description = "'*' matches any characters. Defaults to text/*, application/json, application/javascript, application/xml, application/wasm, image/svg+xml, application/*+json and application/*+xml. In --rule, separate types with spaces.",
+		example = ["text/*", "application/json"]
+	)]
+	#[serde(default, deserialize_with = "string_or_seq")]
+	pub compress_types: Vec<String>,
+	#[schemars(
+		title = "Rate limit",
+		description = "Requests per second allowed for each key (token bucket). Requests beyond it get 429 with Retry-After.",
+		example = 10
+	)]
+	pub rate_limit: Option<f64>,
+	#[schemars(
+		title = "Rate limit burst",
+		description = "Requests that can be made at once before rate_limit applies. Defaults to one second's worth of rate_limit.",
+		example = 20
+	)]
+	pub rate_limit_burst: Option<u32>,
+	#[schemars(
+		title = "What the rate limit counts",
+		description = "'client_ip' (default) counts each client, 'header:<name>' counts each value of the header (requests without it count by client IP), 'global' counts all requests to this rule together.",
+		example = "header:X-Api-Key"
+	)]
+	pub rate_limit_key: Option<String>,
+	#[schemars(
+		title = "Maximum requests in flight",
+		description = "Requests to the backend handled at the same time, until their response body is sent. Rules with the same backend host and port share the limit and must set the same max_concurrent, max_queue and queue_timeout. Requests beyond it wait in the queue or get 503.",
+		example = 8
+	)]
+	pub max_concurrent: Option<usize>,
+	#[schemars(
+		title = "Queue length",
+		description = "Requests that may wait for a free slot when max_concurrent is reached. Defaults to 0 (reject at once).",
+		example = 100
+	)]
+	pub max_queue: Option<usize>,
+	#[schemars(
+		title = "Queue timeout in seconds",
+		description = "How long a queued request waits for a free slot before 503. Defaults to 30.",
+		example = 30
+	)]
+	pub queue_timeout: Option<u64>,
+	#[schemars(
+		title = "Maximum request body size in bytes",
+		description = "Overrides the global max_body_size for this rule.",
+		example = 104857600
+	)]
+	pub max_body_size: Option<u64>,
+	#[schemars(
+		title = "Cache responses",
+		description = "Keeps GET responses that Cache-Control or Expires allow a shared cache to store, and answers later requests from it. Concurrent requests for the same URL wait for a single upstream fetch. Responses carry X-Cache: HIT, MISS, STALE, REVALIDATED or BYPASS. Defaults to false."
+	)]
+	pub cache: Option<bool>,
+	#[schemars(
+		title = "Memory cache size in bytes",
+		description = "Total size of the response bodies kept in memory. The least recently used ones are moved to cache_dir, or dropped. Defaults to 67108864 (64 MiB).",
+		example = 67108864
+	)]
+	pub cache_max_size: Option<u64>,
+	#[schemars(
+		title = "Largest cached response in bytes",
+		description = "Larger responses are passed through without being cached. Defaults to 8388608 (8 MiB).",
+		example = 8388608
+	)]
+	pub cache_max_entry_size: Option<u64>,
+	#[schemars(
+		title = "Disk cache directory",
+		description = "Responses that no longer fit in memory are written to a directory under '<cache_dir>/<rule name>/', which is removed when the config is reloaded. Directories left by a previous run are removed at startup, but not those of other running instances sharing the directory.",
+		example = ".cache/rebab"
+	)]
+	pub cache_dir: Option<PathBuf>,
+	#[schemars(
+		title = "Disk cache size in bytes",
+		description = "Total size of the response bodies kept in cache_dir. Defaults to 1073741824 (1 GiB).",
+		example = 1073741824
+	)]
+	pub cache_dir_max_size: Option<u64>,
+	#[schemars(
+		title = "Basic auth users",
+		description = "User names and their bcrypt or argon2 password hashes. When any of basic_auth, htpasswd or bearer_tokens is set, requests need valid credentials or get 401. In --rule, 'user: hash' pairs separated by '|'.",
+		example = serde_json::json!({"alice": "$2b$10$7EqJtq98hPqEX7fNZaFWoO5S0pU2SY5fMEIbbmDo9V1fpW9EJt.bG"})
+	)]
+	#[serde(default, deserialize_with = "header_map")]
+	pub basic_auth: BTreeMap<String, String>,
+	#[schemars(
+		title = "htpasswd file",
+		description = "File of 'user:hash' lines with bcrypt (htpasswd -B) or argon2 hashes, read when the config is loaded.",
+		example = ".htpasswd"
+	)]
+	pub htpasswd: Option<PathBuf>,
+	#[schemars(
+		title = "Bearer tokens",
+		description = "Tokens accepted in 'Authorization: Bearer <token>'. In --rule, separate tokens with spaces.",
+		example = ["dev-token"]
+	)]
+	#[serde(default, deserialize_with = "string_or_seq")]
+	pub bearer_tokens: Vec<String>,
+	#[schemars(
+		title = "Authentication realm",
+		description = "Realm shown by browsers when asking for credentials. Defaults to 'rebab'.",
+		example = &"staging"
+	)]
+	pub auth_realm: Option<String>,
+	#[schemars(
+		title = "Strip the Authorization header",
+		description = "Removes the Authorization header used for rebab's auth before forwarding. Defaults to false."
+	)]
+	pub strip_authorization: Option<bool>,
+	#[schemars(
+		title = "Allowed client addresses",
+		description = "IP addresses or CIDR ranges allowed to use this rule; other clients get 403. Checked after the global allow and deny. In --rule, separate them with spaces.",
+		example = ["127.0.0.1", "::1", "10.8.0.0/16"]
+	)]
+	#[serde(default, deserialize_with = "string_or_seq")]
+	pub allow: Vec<String>,
+	#[schemars(
+		title = "Denied client addresses",
+		description = "IP addresses or CIDR ranges that get 403 for this rule. Takes precedence over allow. In --rule, separate them with spaces.",
+		example = ["192.0.2.0/24"]
+	)]
+	#[serde(default, deserialize_with = "string_or_seq")]
+	pub deny: Vec<String>,
+}
+
+/// ヘッダの操作（remove → set → add の順に適用）
+///
+/// 値には template::Vars の変数を使える
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct HeaderOps {
+	#[schemars(
+		title = "Headers to set",
+		description = "Replaces existing values. Same placeholders as redirect.",
+		example = serde_json::json!({"Authorization": "Bearer staging-token"})
+	)]
+	#[serde(default)]
+	pub set: BTreeMap<String, String>,
+	#[schemars(
+		title = "Headers to add",
+		description = "Appended to existing values. Same placeholders as set.",
+		example = serde_json::json!({"X-Request-Id": "{request_id}"})
+	)]
+	#[serde(default)]
+	pub add: BTreeMap<String, String>,
+	#[schemars(title = "Headers to remove", example = ["Server"])]
+	#[serde(default)]
+	pub remove: Vec<String>,
+}
+
+impl HeaderOps {
+	fn names(&self) -> impl Iterator<Item = &String> {
+		self.set.keys().chain(self.add.keys()).chain(&self.remove)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Process {
+	#[schemars(
+		title = "Process name",
+		description = "Label used to prefix the output of the process, and referenced by depends_on.",
+		example = "queue-worker"
+	)]
+	pub name: String,
+	#[schemars(title = "Command to execute", example = "npm run worker")]
+	pub command: String,
+	#[schemars(
+		title = "Log file",
+		description = "File the stdout and stderr of the command are written to, in addition to the console. Defaults to '<log_dir>/<name>.log' if log_dir is set.",
+		example = "logs/worker.log"
+	)]
+	pub log_file: Option<PathBuf>,
+	#[schemars(
+		title = "Rules or processes this process depends on",
+		description = "In --process, separate names with spaces.",
+		example = ["db"]
+	)]
+	#[serde(default, deserialize_with = "string_or_seq")]
+	pub depends_on: Vec<String>,
+	#[schemars(
+		title = "Restart the command when files change",
+		description = "In --process, a space-separated list of glob patterns."
+	)]
+	#[serde(default, deserialize_with = "watch_or_paths")]
+	pub watch: Option<Watch>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Watch {
+	#[schemars(
+		title = "Paths to watch",
+		description = "Glob patterns relative to the working directory ('*' does not cross '/', '**' does). A plain directory watches everything below it.",
+		example = ["src/**/*.go", "templates"]
+	)]
+	pub paths: Vec<String>,
+	#[schemars(
+		title = "Paths to ignore",
+		description = "Glob patterns of changes that do not trigger a restart.",
+		example = ["**/*_test.go"]
+	)]
+	#[serde(default)]
+	pub ignore: Vec<String>,
+	#[schemars(
+		title = "Debounce in milliseconds",
+		description = "The command is restarted once no further change has happened for this long. Defaults to 300.",
+		example = 300
+	)]
+	pub debounce_ms: Option<u64>,
+}
+
+/// Watch、または空白区切りの glob 文字列（--rule 用）を受け付ける
+fn watch_or_paths<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Watch>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum WatchOrPaths {
+		String(String),
+		Seq(Vec<String>),
+		Watch(Watch),
+	}
+	Ok(Some(match WatchOrPaths::deserialize(deserializer)? {
+		WatchOrPaths::String(v) => Watch {
+			paths: v.split_whitespace().map(str::to_string).collect(),
+			ignore: vec![],
+			debounce_ms: None,
+		},
+		WatchOrPaths::Seq(paths) => Watch {
+			paths,
+			ignore: vec![],
+			debounce_ms: None,
+		},
+		WatchOrPaths::Watch(v) => v,
+	}))
+}
+
+/// ヘッダのマップ、または `|` 区切りの `Name: value`（--rule 用）を受け付ける
+fn header_map<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum MapOrString {
+		String(String),
+		Map(BTreeMap<String, String>),
+	}
+	match MapOrString::deserialize(deserializer)? {
+		MapOrString::Map(v) => Ok(v),
+		MapOrString::String(v) => v
+			.split('|')
+			.filter(|v| !v.trim().is_empty())
+			.map(|v| {
+				let (name, value) = v
+					.split_once(':')
+					.ok_or_else(|| serde::de::Error::custom(format!("invalid header: {}", v)))?;
+				Ok((name.trim().to_string(), value.trim().to_string()))
+			})
+			.collect(),
+	}
+}
+
+/// 名前と値（null は値を問わない）のマップ、または `|` 区切りの `name` / `name=value`（--rule 用）を受け付ける
+fn optional_value_map<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<BTreeMap<String, Option<String>>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum MapOrString {
+		String(String),
+		Map(BTreeMap<String, Option<String>>),
+	}
+	Ok(match MapOrString::deserialize(deserializer)? {
+		MapOrString::Map(v) => v,
+		MapOrString::String(v) => v
+			.split('|')
+			.filter(|v| !v.trim().is_empty())
+			.map(|v| match v.split_once('=') {
+				Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+				None => (v.trim().to_string(), None),
+			})
+			.collect(),
+	})
+}
+
+/// 文字列配列、または空白区切りの文字列（--rule 用）を受け付ける
+fn string_or_seq<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum StringOrSeq {
+		String(String),
+		Seq(Vec<String>),
+	}
+	Ok(match StringOrSeq::deserialize(deserializer)? {
+		StringOrSeq::String(v) => v.split_whitespace().map(str::to_string).collect(),
+		StringOrSeq::Seq(v) => v,
+	})
+}
+
+/// IP アドレスか CIDR の並び（パースできなかったものを返す）
+fn parse_nets(values: &[String]) -> Result<Vec<ipnet::IpNet>, String> {
+	values
+		.iter()
+		.map(|v| {
+			v.parse::<ipnet::IpNet>()
+				.or_else(|_| v.parse::<std::net::IpAddr>().map(ipnet::IpNet::from))
+				.map_err(|_| v.clone())
+		})
+		.collect()
+}
+
+fn ip_filter(allow: &[String], deny: &[String]) -> Result<crate::access::IpFilter, String> {
+	Ok(crate::access::IpFilter {
+		allow: parse_nets(allow).map_err(|v| format!("invalid allow address: {}", v))?,
+		deny: parse_nets(deny).map_err(|v| format!("invalid deny address: {}", v))?,
+	})
+}
+
+/// ルールの command と processes を同じように扱うためのビュー
+#[derive(Debug, Clone)]
+pub struct Managed<'a> {
+	pub label: String,
+	pub command: Option<&'a str>,
+	pub backend_host: Option<&'a str>,
+	pub backend_port: Option<u16>,
+	pub log_file: Option<&'a PathBuf>,
+	pub depends_on: &'a [String],
+	pub watch: Option<&'a Watch>,
+}
+
+impl Router {
+	/// rules（command の無いものも含む）と processes を順に並べたもの
+	///
+	/// 依存先としてはポートを持つだけのルールも参照できる
+	pub fn managed(&self) -> Vec<Managed<'_>> {
+		let rules = self.rules.iter().enumerate().map(|(index, rule)| Managed {
+			label: rule.label(index),
+			command: rule.command.as_deref(),
+			backend_host: rule.backend_host.as_deref(),
+			backend_port: rule.backend_port,
+			log_file: rule.log_file.as_ref(),
+			depends_on: &rule.depends_on,
+			watch: rule.watch.as_ref(),
+		});
+		let processes = self.processes.iter().map(|process| Managed {
+			label: process.name.clone(),
+			command: Some(&process.command),
+			backend_host: None,
+			backend_port: None,
+			log_file: process.log_file.as_ref(),
+			depends_on: &process.depends_on,
+			watch: process.watch.as_ref(),
+		});
+		rules.chain(processes).collect()
+	}
+
+	/// プロセス出力を書き込むファイル（log_file > log_dir/<label>.log）
+	pub fn log_file(&self, managed: &Managed) -> Option<PathBuf> {
+		managed.log_file.cloned().or_else(|| {
+			Some(
+				self.log_dir
+					.as_ref()?
+					.join(format!("{}.log", managed.label)),
+			)
+		})
+	}
+
+	/// 設定の検証（ルールの動作の組み合わせ、ステータス、依存関係）
+	pub fn validate(&self) -> Result<(), String> {
+		for (index, rule) in self.rules.iter().enumerate() {
+			let label = rule.label(index);
+			let actions = [
+				rule.static_dir.is_some(),
+				rule.redirect.is_some(),
+				rule.body.is_some() || (rule.status.is_some() && rule.redirect.is_none()),
+				rule.backend_host.is_some() || rule.backend_port.is_some(),
+			];
+			if actions.iter().filter(|v| **v).count() > 1 {
+				return Err(format!(
+					"rule {}: only one of backend, static_dir, redirect or status/body can be set",
+					label
+				));
+			}
+			crate::matcher::Matcher::new(rule).map_err(|e| format!("rule {}: {}", label, e))?;
+			if rule.rewrite.is_some() && !rule.is_forward() {
+				return Err(format!(
+					"rule {}: rewrite can only be used with a backend",
+					label
+				));
+			}
+			if let Some(v) = &rule.rewrite
+				&& !v.starts_with('/')
+			{
+				return Err(format!("rule {}: rewrite must start with /: {}", label, v));
+			}
+			if rule.cache.unwrap_or(false) && !rule.is_forward() {
+				return Err(format!(
+					"rule {}: cache can only be used with a backend",
+					label
+				));
+			}
+			rule.ip_filter()
+				.map_err(|e| format!("rule {}: {}", label, e))?;
+			for (user, hash) in &rule.basic_auth {
+				crate::auth::check_hash(hash)
+					.map_err(|e| format!("rule {}: user {}: {}", label, user, e))?;
+			}
+			if let Some(v) = &rule.rate_limit_key {
+				v.parse::<crate::limit::RateKey>()
+					.map_err(|e| format!("rule {}: {}", label, e))?;
+			}
+			if let Some(v) = rule.rate_limit
+				&& !(v.is_finite() && v > 0.0)
+			{
+				return Err(format!("rule {}: rate_limit must be positive", label));
+			}
+			if rule.rate_limit_burst == Some(0) || rule.max_concurrent == Some(0) {
+				return Err(format!(
+					"rule {}: rate_limit_burst and max_concurrent must be at least 1",
+					label
+				));
+			}
+			if let Some(status) = rule.status
+				&& !(100..=599).contains(&status)
+			{
+				return Err(format!("rule {}: invalid status {}", label, status));
+			}
+			if rule.redirect.is_some()
+				&& let Some(status) = rule.status
+				&& !(300..=399).contains(&status)
+			{
+				return Err(format!(
+					"rule {}: redirect status must be 3xx, not {}",
+					label, status
+				));
+			}
+			let ops = rule.request_headers.iter().chain(&rule.response_headers);
+			for name in ops.flat_map(HeaderOps::names) {
+				hyper::header::HeaderName::from_bytes(name.as_bytes())
+					.map_err(|e| format!("rule {}: invalid header name {}: {}", label, name, e))?;
+			}
+			for (name, value) in &rule.headers {
+				hyper::header::HeaderName::from_bytes(name.as_bytes())
+					.map_err(|e| format!("rule {}: invalid header name {}: {}", label, name, e))?;
+				hyper::header::HeaderValue::from_str(value).map_err(|e| {
+					format!("rule {}: invalid header value {}: {}", label, value, e)
+				})?;
+			}
+		}
+		self.trusted_proxies()?;
+		self.ip_filter()?;
+		self.dashboard_filter()?;
+		if self.max_headers == Some(0) {
+			return Err("max_headers must be at least 1".to_string());
+		}
+		for key in self.error_pages.keys() {
+			crate::error_page::check_key(key)?;
+		}
+		if let Some(path) = &self.dashboard
+			&& (!path.starts_with('/') || path.trim_end_matches('/').is_empty())
+		{
+			return Err(format!(
+				"dashboard must be a path starting with / other than /: {}",
+				path
+			));
+		}
+		self.startup_order().map(|_| ())
+	}
+
+	/// trusted_proxies をパースする。CIDR の無いアドレスは単一のホストとして扱う
+	pub fn trusted_proxies(&self) -> Result<Vec<ipnet::IpNet>, String> {
+		parse_nets(&self.trusted_proxies).map_err(|v| format!("invalid trusted proxy: {}", v))
+	}
+
+	/// すべてのルールに適用する allow と deny
+	pub fn ip_filter(&self) -> Result<crate::access::IpFilter, String> {
+		ip_filter(&self.allow, &self.deny)
+	}
+
+	/// ダッシュボードを使えるクライアント（省略したらループバックだけ）
+	pub fn dashboard_filter(&self) -> Result<crate::access::IpFilter, String> {
+		let allow = match self.dashboard_allow.is_empty() {
+			true => vec!["127.0.0.0/8".to_string(), "::1".to_string()],
+			false => self.dashboard_allow.clone(),
+		};
+		Ok(crate::access::IpFilter {
+			allow: parse_nets(&allow)
+				.map_err(|v| format!("invalid dashboard_allow address: {}", v))?,
+			deny: vec![],
+		})
+	}
+
+	/// depends_on を解決したプロセスの起動順（managed() のインデックス）
+	///
+	/// 依存関係が無ければ定義順を保つ。名前の重複、存在しない依存先、循環依存はエラー
+	pub fn startup_order(&self) -> Result<Vec<usize>, String> {
+		let managed = self.managed();
+		for (index, v) in managed.iter().enumerate() {
+			if managed[..index].iter().any(|w| w.label == v.label) {
+				return Err(format!("duplicate rule or process name: {}", v.label));
+			}
+		}
+		let mut order = Vec::with_capacity(managed.len());
+		// 0: 未訪問, 1: 訪問中, 2: 訪問済み
+		let mut state = vec![0u8; managed.len()];
+		let mut path = Vec::new();
+		for index in 0..managed.len() {
+			visit(index, &managed, &mut state, &mut path, &mut order)?;
+		}
+		Ok(order)
+	}
+}
+
+fn visit(
+	index: usize,
+	managed: &[Managed],
+	state: &mut [u8],
+	path: &mut Vec<usize>,
+	order: &mut Vec<usize>,
+) -> Result<(), String> {
+	match state[index] {
+		2 => return Ok(()),
+		1 => {
+			let start = path.iter().position(|v| *v == index).unwrap_or(0);
+			let cycle: Vec<&str> = path[start..]
+				.iter()
+				.chain([&index])
+				.map(|v| managed[*v].label.as_str())
+				.collect();
+			return Err(format!("dependency cycle: {}", cycle.join(" -> ")));
+		}
+		_ => {}
+	}
+	state[index] = 1;
+	path.push(index);
+	for name in managed[index].depends_on {
+		let dependency = managed
+			.iter()
+			.position(|v| &v.label == name)
+			.ok_or_else(|| {
+				format!(
+					"{} depends on unknown rule or process: {}",
+					managed[index].label, name
+				)
+			})?;
+		visit(dependency, managed, state, path, order)?;
+	}
+	path.pop();
+	state[index] = 2;
+	order.push(index);
+	Ok(())
+}
+
+impl Rule {
+	/// このルールの allow と deny
+	pub fn ip_filter(&self) -> Result<crate::access::IpFilter, String> {
+		ip_filter(&self.allow, &self.deny)
+	}
+
+	/// 認証が必要なルールか
+	pub fn requires_auth(&self) -> bool {
+		!self.basic_auth.is_empty() || self.htpasswd.is_some() || !self.bearer_tokens.is_empty()
+	}
+
+	/// upstream へ転送するルールか（静的ファイル、リダイレクト、固定レスポンスではない）
+	pub fn is_forward(&self) -> bool {
+		self.static_dir.is_none()
+			&& self.redirect.is_none()
+			&& self.status.is_none()
+			&& self.body.is_none()
+	}
+
+	/// 転送先の host:port（backend_port が無ければリクエストのポートになるので host だけ）
+	///
+	/// 転送しないルールは None
+	pub fn backend_authority(&self) -> Option<String> {
+		if !self.is_forward() {
+			return None;
+		}
+		let host = self.backend_host.as_deref().unwrap_or("localhost");
+		Some(match self.backend_port {
+			Some(port) => format!("{}:{}", host, port),
+			None => host.to_string(),
+		})
+	}
+
+	/// プロセス出力のラベル（name が無ければ rule_N）
+	pub fn label(&self, index: usize) -> String {
+		self.name
+			.clone()
+			.unwrap_or_else(|| format!("rule_{}", index))
+	}
 }
 
 impl FromStr for Rule {
@@ -54,6 +934,14 @@ impl FromStr for Rule {
 	}
 }
 
+impl FromStr for Process {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.replace(',', "&");
+		serde_urlencoded::from_str(&s).map_err(|e| e.to_string())
+	}
+}
+
 #[derive(Debug, Parser, Clone)]
 #[command(
 	name = "rebab",
@@ -69,7 +957,10 @@ impl FromStr for Rule {
     $ rebab --rule \"prefix=/api,port=3000\" --rule \"port=8080\"
 
     # Automatic process management (sets PORT=3000 for the command)
-    $ rebab --rule \"prefix=/api,port=3000,command=npm run dev\"
+    $ rebab --rule \"name=api,prefix=/api,port=3000,command=npm run dev\"
+
+    # Standalone process without a route
+    $ rebab --rule \"port=8080\" --process \"name=worker,command=npm run worker\"
 
     # Custom frontend address and mixed rules
     $ rebab --frontend 127.0.0.1:9000 --rule \"port=8080\""
@@ -94,9 +985,117 @@ pub struct Args {
 		long = "rule",
 		value_name = "RULE",
 		value_parser = Rule::from_str,
-		help = "Add a routing rule. Format: 'prefix=/path,host=localhost,port=80,command=...'"
+		help = "Add a routing rule. Format: 'name=api,prefix=/path,host=localhost,port=80,command=...'"
 	)]
 	pub rules: Vec<Rule>,
+
+	#[arg(
+		long = "process",
+		value_name = "PROCESS",
+		value_parser = Process::from_str,
+		help = "Add a standalone process. Format: 'name=worker,command=...'"
+	)]
+	pub processes: Vec<Process>,
+
+	#[arg(
+		long = "log-dir",
+		value_name = "DIR",
+		help = "Directory to write the output of managed processes to"
+	)]
+	pub log_dir: Option<PathBuf>,
+
+	#[arg(
+		long = "trusted-proxy",
+		value_name = "CIDR",
+		help = "Trust X-Forwarded-* headers from this address or range (repeatable)"
+	)]
+	pub trusted_proxies: Vec<String>,
+
+	#[arg(
+		long = "proxy-protocol",
+		help = "Expect a PROXY protocol v1/v2 header on every connection"
+	)]
+	pub proxy_protocol: bool,
+
+	#[arg(
+		long,
+		value_name = "SCHEME",
+		help = "Scheme clients use to reach the frontend, sent as X-Forwarded-Proto (default: detected)"
+	)]
+	pub scheme: Option<Scheme>,
+
+	#[arg(
+		long,
+		value_name = "CIDR",
+		help = "Allow only clients from this address or range (repeatable)"
+	)]
+	pub allow: Vec<String>,
+
+	#[arg(
+		long,
+		value_name = "CIDR",
+		help = "Deny clients from this address or range (repeatable)"
+	)]
+	pub deny: Vec<String>,
+
+	#[arg(
+		long = "max-body-size",
+		value_name = "BYTES",
+		help = "Reject request bodies larger than this with 413"
+	)]
+	pub max_body_size: Option<u64>,
+
+	#[arg(
+		long = "max-header-size",
+		value_name = "BYTES",
+		help = "Reject request headers larger than this with 431 (raised to at least 8192)"
+	)]
+	pub max_header_size: Option<usize>,
+
+	#[arg(
+		long = "max-headers",
+		value_name = "N",
+		help = "Reject requests with more headers than this with 431 (default: 100)"
+	)]
+	pub max_headers: Option<usize>,
+
+	#[arg(
+		long = "error-page",
+		value_name = "STATUS=FILE",
+		value_parser = parse_error_page,
+		help = "HTML template for errors rebab returns with this status or class, e.g. '5xx=errors/5xx.html' (repeatable)"
+	)]
+	pub error_pages: Vec<(String, PathBuf)>,
+
+	#[arg(
+		long = "hide-error-details",
+		help = "Leave internal details out of error responses (they are still logged)"
+	)]
+	pub hide_error_details: bool,
+
+	#[arg(
+		long,
+		value_name = "PATH",
+		num_args = 0..=1,
+		default_missing_value = crate::dashboard::DEFAULT_PATH,
+		help = "Serve the development dashboard at this path (default: /__rebab)"
+	)]
+	pub dashboard: Option<String>,
+
+	#[arg(
+		long,
+		value_name = "CIDR",
+		help = "Allow the dashboard from this address or range instead of loopback only (repeatable)"
+	)]
+	pub dashboard_allow: Vec<String>,
+}
+
+fn parse_error_page(s: &str) -> Result<(String, PathBuf), String> {
+	let (status, file) = s
+		.split_once('=')
+		.ok_or_else(|| format!("error page must be STATUS=FILE: {}", s))?;
+	crate::error_page::check_key(status)?;
+	Ok((status.to_string(), PathBuf::from(file)))
 }
 
 pub fn parse() -> Args {
@@ -107,6 +1106,22 @@ pub fn load(args: &Args) -> Result<Router, String> {
 	let mut router = Router {
 		frontend: "0.0.0.0:8080".parse().unwrap(),
 		rules: vec![],
+		processes: vec![],
+		log_dir: None,
+		log_max_size: None,
+		log_max_files: None,
+		trusted_proxies: vec![],
+		proxy_protocol: None,
+		scheme: None,
+		allow: vec![],
+		deny: vec![],
+		max_body_size: None,
+		max_header_size: None,
+		max_headers: None,
+		error_pages: BTreeMap::new(),
+		hide_error_details: None,
+		dashboard: None,
+		dashboard_allow: vec![],
 	};
 	if let Some(input) = &args.input {
 		let v = std::fs::read_to_string(input)
@@ -117,9 +1132,46 @@ pub fn load(args: &Args) -> Result<Router, String> {
 	if let Some(frontend) = args.frontend {
 		router.frontend = frontend;
 	}
+	if let Some(log_dir) = &args.log_dir {
+		router.log_dir = Some(log_dir.clone());
+	}
 
 	// CLIで指定されたルールを追加
 	router.rules.extend(args.rules.clone());
+	router.processes.extend(args.processes.clone());
+	router
+		.trusted_proxies
+		.extend(args.trusted_proxies.iter().cloned());
+	if let Some(scheme) = args.scheme {
+		router.scheme = Some(scheme);
+	}
+	if args.proxy_protocol {
+		router.proxy_protocol = Some(true);
+	}
+	router.allow.extend(args.allow.iter().cloned());
+	router.deny.extend(args.deny.iter().cloned());
+	if let Some(v) = args.max_body_size {
+		router.max_body_size = Some(v);
+	}
+	if let Some(v) = args.max_header_size {
+		router.max_header_size = Some(v);
+	}
+	if let Some(v) = args.max_headers {
+		router.max_headers = Some(v);
+	}
+	router.error_pages.extend(args.error_pages.iter().cloned());
+	if args.hide_error_details {
+		router.hide_error_details = Some(true);
+	}
+	if let Some(path) = &args.dashboard {
+		router.dashboard = Some(path.clone());
+	}
+	router
+		.dashboard_allow
+		.extend(args.dashboard_allow.iter().cloned());
+
+	// ルールと依存関係の検証（循環依存など）
+	router.validate()?;
 
 	Ok(router)
 }
@@ -0,0 +1,604 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, HOST, HeaderMap, HeaderValue, ORIGIN, REFERER};
+use hyper::http::request::Parts;
+use hyper::{Method, Request, Response};
+
+use crate::access::IpFilter;
+use crate::body::RebabBody;
+use crate::config;
+use crate::error_page::escape;
+use crate::log::Stream;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::process::ProcessManager;
+use crate::proxy::{Connection, Decision};
+
+/// `--dashboard` でパスを省略したときの値
+pub const DEFAULT_PATH: &str = "/__rebab";
+
+/// 覚えておくリクエストの数
+const RECENT_REQUESTS: usize = 100;
+
+/// ページを読み込み直す間隔（秒）
+const REFRESH: u32 = 5;
+
+/// 記録したリクエスト
+#[derive(Debug, Clone)]
+pub struct RequestRecord {
+	pub time: DateTime<Local>,
+	pub client_ip: IpAddr,
+	pub method: Method,
+	/// パスとクエリ
+	pub uri: String,
+	/// マッチしたルール（どれにもマッチしなければ None）
+	pub rule: Option<String>,
+	pub status: u16,
+	/// レスポンスのヘッダを返すまでの時間
+	pub latency: Duration,
+}
+
+/// 最近のリクエスト（設定の再読み込みをまたいで保持する）
+#[derive(Debug, Default)]
+pub struct RequestLog {
+	records: Mutex<VecDeque<RequestRecord>>,
+}
+
+impl RequestLog {
+	fn push(&self, record: RequestRecord) {
+		let mut records = self.records.lock().unwrap();
+		if records.len() >= RECENT_REQUESTS {
+			records.pop_front();
+		}
+		records.push_back(record);
+	}
+
+	/// 新しい順
+	pub fn recent(&self) -> Vec<RequestRecord> {
+		self.records.lock().unwrap().iter().rev().cloned().collect()
+	}
+}
+
+/// リクエストを [`RequestLog`] に記録する（ルートごとの処理の先頭に挟む）
+pub struct Recorder {
+	pub log: Arc<RequestLog>,
+	pub rule: Option<String>,
+}
+
+impl Middleware for Recorder {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let started = Instant::now();
+			let time = Local::now();
+			let method = req.method().clone();
+			let uri = req
+				.uri()
+				.path_and_query()
+				.map_or("/", |v| v.as_str())
+				.to_string();
+			let resp = next.run(req, conn).await;
+			self.log.push(RequestRecord {
+				time,
+				client_ip: conn.client_ip,
+				method,
+				uri,
+				rule: self.rule.clone(),
+				status: resp.status().as_u16(),
+				latency: started.elapsed(),
+			});
+			resp
+		})
+	}
+}
+
+/// 開発用のダッシュボード（ルート、プロセスとその出力、最近のリクエスト、再起動のボタン）
+///
+/// [`Dashboard::route`] がルールの代わりに応え方を決める
+pub struct Dashboard {
+	/// 末尾の `/` を除いたパス
+	path: String,
+	router: config::Router,
+	processes: Arc<ProcessManager>,
+	requests: Arc<RequestLog>,
+	/// dashboard_allow と、すべてのルールに適用する allow と deny
+	access: [IpFilter; 2],
+}
+
+impl Dashboard {
+	pub fn new(
+		path: &str,
+		router: config::Router,
+		processes: Arc<ProcessManager>,
+		requests: Arc<RequestLog>,
+	) -> Result<Self, String> {
+		let access = [router.dashboard_filter()?, router.ip_filter()?];
+		Ok(Self {
+			path: path.trim_end_matches('/').to_string(),
+			router,
+			processes,
+			requests,
+			access,
+		})
+	}
+
+	/// ダッシュボード以下のパスか
+	pub fn matches(&self, path: &str) -> bool {
+		path.strip_prefix(&self.path)
+			.is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+	}
+
+	/// ダッシュボード以下のパスへのリクエストに応える
+	///
+	/// 再起動はここで始めるので、使えないクライアントはルートの Middleware を待たずに断る
+	pub fn route(&self, parts: &Parts, conn: &Connection) -> Decision {
+		if !self.access.iter().all(|v| v.permits(conn.client_ip)) {
+			return Decision::Reject {
+				status: 403,
+				reason: format!("rebab dashboard forbidden for {}", conn.client_ip),
+			};
+		}
+		let rest = &parts.uri.path()[self.path.len()..];
+		match (&parts.method, rest) {
+			(&Method::GET | &Method::HEAD, "" | "/") => Decision::Respond {
+				status: 200,
+				headers: vec![
+					(
+						CONTENT_TYPE.to_string(),
+						"text/html; charset=utf-8".to_string(),
+					),
+					(CACHE_CONTROL.to_string(), "no-store".to_string()),
+				],
+				body: self.page(),
+			},
+			(&Method::POST, "/restart") => self.restart(parts, conn),
+			(_, "" | "/" | "/restart") => Decision::Reject {
+				status: 405,
+				reason: "rebab method not allowed".to_string(),
+			},
+			_ => Decision::Reject {
+				status: 404,
+				reason: format!("rebab no route for {}", parts.uri),
+			},
+		}
+	}
+
+	/// 再起動を受け付け、終わるのを待たずにダッシュボードに戻す
+	fn restart(&self, parts: &Parts, conn: &Connection) -> Decision {
+		// 他のサイトのフォームから送らせない
+		if !same_origin(&parts.headers) {
+			return Decision::Reject {
+				status: 403,
+				reason: "rebab dashboard cross-origin request".to_string(),
+			};
+		}
+		let name = parts
+			.uri
+			.query()
+			.and_then(|v| serde_urlencoded::from_str::<Vec<(String, String)>>(v).ok())
+			.and_then(|v| v.into_iter().find(|(k, _)| k == "name"))
+			.map(|(_, v)| v)
+			.unwrap_or_default();
+		if !self.processes.status().iter().any(|v| v.name == name) {
+			return Decision::Reject {
+				status: 404,
+				reason: format!("rebab no such process {}", name),
+			};
+		}
+		crate::log::log(format!(
+			"{}: restart of [{}] requested from the dashboard",
+			conn.client_ip, name
+		));
+		let processes = self.processes.clone();
+		tokio::task::spawn_blocking(move || {
+			if let Err(e) = processes.restart(&name) {
+				crate::log::log(format!("Failed to restart process [{}]: {}", name, e));
+			}
+		});
+		Decision::Redirect {
+			status: 303,
+			location: format!("{}/", self.path),
+			headers: vec![],
+		}
+	}
+
+	fn page(&self) -> String {
+		let mut html = String::new();
+		let _ = write!(
+			html,
+			r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta http-equiv="refresh" content="{REFRESH}">
+<title>rebab</title>
+<style>
+body{{font-family:system-ui,sans-serif;margin:1.5em;color:#222}}
+table{{border-collapse:collapse;margin-bottom:1.5em}}
+th,td{{border-bottom:1px solid #ddd;padding:.3em .6em;text-align:left;vertical-align:top}}
+code,pre{{font-family:ui-monospace,monospace;font-size:.9em}}
+pre{{background:#f6f6f6;padding:.5em;max-height:20em;overflow:auto;margin:.3em 0 1em}}
+.err{{color:#b00}}.dim{{color:#888}}
+</style></head><body>
+<h1>rebab {version}</h1>
+<p class="dim">frontend {frontend} · reloads every {REFRESH}s</p>
+"#,
+			version = crate::VERSION,
+			frontend = escape(&self.router.frontend.to_string()),
+		);
+
+		html.push_str("<h2>Routes</h2>\n<table><tr><th>#</th><th>Name</th><th>Match</th><th>Action</th><th>Options</th></tr>\n");
+		for (index, rule) in self.router.rules.iter().enumerate() {
+			let _ = writeln!(
+				html,
+				"<tr><td>{}</td><td>{}</td><td><code>{}</code></td><td><code>{}</code></td><td>{}</td></tr>",
+				index,
+				escape(&rule.label(index)),
+				escape(&describe_match(rule)),
+				escape(&describe_action(rule)),
+				escape(&describe_options(rule)),
+			);
+		}
+		html.push_str("</table>\n");
+
+		html.push_str("<h2>Processes</h2>\n");
+		let processes = self.processes.status();
+		if processes.is_empty() {
+			html.push_str("<p class=\"dim\">No managed processes</p>\n");
+		}
+		for process in processes {
+			let state = match (process.restarting, process.pid) {
+				(true, _) => "restarting".to_string(),
+				(false, Some(pid)) => format!("running (pid {})", pid),
+				(false, None) if process.watched => "stopped, waiting for changes".to_string(),
+				(false, None) => "stopped".to_string(),
+			};
+			let query = serde_urlencoded::to_string([("name", &process.name)]).unwrap_or_default();
+			let _ = write!(
+				html,
+				r#"<h3>{name} <small class="dim">{state}{port} · started {started} · {restarts} restarts</small></h3>
+<form method="post" action="{path}/restart?{query}"><code>{command}</code> <button>Restart</button></form>
+<pre>"#,
+				name = escape(&process.name),
+				state = escape(&state),
+				port = process
+					.port
+					.map(|v| format!(" · port {}", v))
+					.unwrap_or_default(),
+				started = process.started.format("%H:%M:%S"),
+				restarts = process.restarts,
+				path = escape(&self.path),
+				query = escape(&query),
+				command = escape(&process.command),
+			);
+			for (stream, line) in &process.output {
+				match stream {
+					Stream::Stdout => {
+						let _ = writeln!(html, "{}", escape(line));
+					}
+					Stream::Stderr => {
+						let _ = writeln!(html, "<span class=\"err\">{}</span>", escape(line));
+					}
+				}
+			}
+			html.push_str("</pre>\n");
+		}
+
+		html.push_str("<h2>Recent requests</h2>\n<table><tr><th>Time</th><th>Client</th><th>Method</th><th>URI</th><th>Rule</th><th>Status</th><th>Latency</th></tr>\n");
+		for record in self.requests.recent() {
+			let _ = writeln!(
+				html,
+				"<tr><td>{}</td><td>{}</td><td>{}</td><td><code>{}</code></td><td>{}</td><td{}>{}</td><td>{:.1} ms</td></tr>",
+				record.time.format("%H:%M:%S"),
+				record.client_ip,
+				escape(record.method.as_str()),
+				escape(&record.uri),
+				escape(record.rule.as_deref().unwrap_or("-")),
+				if record.status >= 500 {
+					" class=\"err\""
+				} else {
+					""
+				},
+				record.status,
+				record.latency.as_secs_f64() * 1000.0,
+			);
+		}
+		html.push_str("</table>\n</body></html>\n");
+		html
+	}
+}
+
+/// ブラウザが付ける Origin（無ければ Referer）が Host と同じか
+///
+/// どちらも無いリクエストは、送り元が分からないので断る
+fn same_origin(headers: &HeaderMap) -> bool {
+	let value = |name| {
+		headers
+			.get(name)
+			.and_then(|v: &HeaderValue| v.to_str().ok())
+	};
+	let Some(source) = value(ORIGIN).or_else(|| value(REFERER)) else {
+		return false;
+	};
+	// scheme://host[:port][/path]
+	let source = source
+		.split_once("://")
+		.map(|(_, v)| v.split('/').next().unwrap_or_default());
+	source.is_some_and(|v| !v.is_empty() && value(HOST) == Some(v))
+}
+
+/// ルールの条件（条件が無ければ `*`）
+fn describe_match(rule: &config::Rule) -> String {
+	let mut conditions = vec![];
+	if !rule.methods.is_empty() {
+		conditions.push(rule.methods.join(","));
+	}
+	if let Some(v) = rule.frontend_prefix.as_ref().or(rule.path_prefix.as_ref()) {
+		conditions.push(format!("{}*", v));
+	}
+	if let Some(v) = &rule.path {
+		conditions.push(v.clone());
+	}
+	if let Some(v) = &rule.path_regex {
+		conditions.push(format!("~{}", v));
+	}
+	let pairs = |prefix: &str, map: &std::collections::BTreeMap<String, Option<String>>| {
+		map.iter()
+			.map(|(k, v)| match v {
+				Some(v) => format!("{}{}={}", prefix, k, v),
+				None => format!("{}{}", prefix, k),
+			})
+			.collect::<Vec<_>>()
+	};
+	conditions.extend(pairs("?", &rule.query));
+	conditions.extend(pairs("header ", &rule.match_headers));
+	conditions.extend(pairs("cookie ", &rule.match_cookies));
+	if let Some(v) = rule.weight {
+		conditions.push(format!("weight {}%", v));
+	}
+	match conditions.is_empty() {
+		true => "*".to_string(),
+		false => conditions.join(" "),
+	}
+}
+
+/// マッチしたときの動作
+fn describe_action(rule: &config::Rule) -> String {
+	if let Some(location) = &rule.redirect {
+		return format!("redirect {} {}", rule.status.unwrap_or(302), location);
+	}
+	if rule.status.is_some() || rule.body.is_some() {
+		return format!("respond {}", rule.status.unwrap_or(200));
+	}
+	if let Some(dir) = &rule.static_dir {
+		return format!("static {}", dir.display());
+	}
+	let mut target = format!(
+		"http://{}",
+		rule.backend_host.as_deref().unwrap_or("localhost")
+	);
+	if let Some(port) = rule.backend_port {
+		let _ = write!(target, ":{}", port);
+	}
+	if let Some(rewrite) = &rule.rewrite {
+		target.push_str(rewrite);
+	}
+	target
+}
+
+/// ルートに挟む処理
+fn describe_options(rule: &config::Rule) -> String {
+	let mut options = vec![];
+	if !rule.allow.is_empty() || !rule.deny.is_empty() {
+		options.push("ip filter".to_string());
+	}
+	if rule.requires_auth() {
+		options.push("auth".to_string());
+	}
+	if let Some(v) = rule.max_body_size {
+		options.push(format!("max body {} bytes", v));
+	}
+	if let Some(v) = rule.rate_limit {
+		options.push(format!("rate {}/s", v));
+	}
+	if let Some(v) = rule.max_concurrent {
+		options.push(format!("max {} concurrent", v));
+	}
+	if rule.compress.unwrap_or(false) {
+		options.push("compress".to_string());
+	}
+	if rule.cache.unwrap_or(false) {
+		options.push("cache".to_string());
+	}
+	options.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rule(json: &str) -> config::Rule {
+		serde_json::from_str(json).unwrap()
+	}
+
+	#[test]
+	fn recent_requests() {
+		let log = RequestLog::default();
+		for status in 0..RECENT_REQUESTS as u16 + 5 {
+			log.push(RequestRecord {
+				time: Local::now(),
+				client_ip: IpAddr::from([127, 0, 0, 1]),
+				method: Method::GET,
+				uri: "/".to_string(),
+				rule: None,
+				status,
+				latency: Duration::ZERO,
+			});
+		}
+		let recent = log.recent();
+		assert_eq!(recent.len(), RECENT_REQUESTS);
+		assert_eq!(recent[0].status, RECENT_REQUESTS as u16 + 4);
+		assert_eq!(recent[RECENT_REQUESTS - 1].status, 5);
+	}
+
+	fn dashboard(json: &str) -> Dashboard {
+		let router: config::Router = serde_json::from_str(json).unwrap();
+		let path = router.dashboard.clone().unwrap();
+		Dashboard::new(
+			&path,
+			router,
+			Arc::new(ProcessManager::new()),
+			Arc::new(RequestLog::default()),
+		)
+		.unwrap()
+	}
+
+	fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> Parts {
+		let mut req = Request::builder()
+			.method(method)
+			.uri(uri)
+			.header(HOST, "localhost:8080");
+		for (k, v) in headers {
+			req = req.header(*k, *v);
+		}
+		req.body(()).unwrap().into_parts().0
+	}
+
+	fn conn(client_ip: &str) -> Connection {
+		let addr = "127.0.0.1:8080".parse().unwrap();
+		Connection {
+			peer: addr,
+			local: addr,
+			client_ip: client_ip.parse().unwrap(),
+			trusted: false,
+			scheme: config::Scheme::Http,
+		}
+	}
+
+	/// 断ったときのステータス（断らなければ None）
+	fn rejected(decision: Decision) -> Option<u16> {
+		match decision {
+			Decision::Reject { status, .. } => Some(status),
+			_ => None,
+		}
+	}
+
+	const ROUTER: &str = r#"{"frontend": "127.0.0.1:8080", "rules": [], "dashboard": "/__rebab/"}"#;
+
+	#[test]
+	fn paths() {
+		let dashboard = dashboard(ROUTER);
+		assert!(dashboard.matches("/__rebab"));
+		assert!(dashboard.matches("/__rebab/"));
+		assert!(dashboard.matches("/__rebab/restart"));
+		assert!(!dashboard.matches("/__rebabx"));
+		assert!(!dashboard.matches("/"));
+	}
+
+	#[test]
+	fn origins() {
+		let same = |headers: &[(&str, &str)]| same_origin(&request("POST", "/", headers).headers);
+		assert!(!same(&[]));
+		assert!(same(&[("origin", "http://localhost:8080")]));
+		assert!(!same(&[("origin", "http://evil.example")]));
+		assert!(!same(&[("origin", "http://localhost")]));
+		assert!(!same(&[("origin", "null")]));
+		assert!(same(&[("referer", "http://localhost:8080/__rebab/")]));
+		assert!(!same(&[("referer", "http://localhost:8080.evil.example/")]));
+		// Origin があればそちらで決める
+		assert!(!same(&[
+			("origin", "http://evil.example"),
+			("referer", "http://localhost:8080/"),
+		]));
+	}
+
+	#[test]
+	fn access() {
+		let local = dashboard(ROUTER);
+		let page = request("GET", "/__rebab/", &[]);
+		assert!(matches!(
+			local.route(&page, &conn("127.0.0.1")),
+			Decision::Respond { status: 200, .. }
+		));
+		assert_eq!(rejected(local.route(&page, &conn("::1"))), None);
+		assert_eq!(
+			rejected(local.route(&page, &conn("192.168.1.2"))),
+			Some(403)
+		);
+
+		let lan = dashboard(
+			r#"{"frontend": "127.0.0.1:8080", "rules": [], "dashboard": "/__rebab", "dashboard_allow": ["192.168.1.0/24"], "deny": ["192.168.1.9"]}"#,
+		);
+		assert_eq!(rejected(lan.route(&page, &conn("192.168.1.2"))), None);
+		assert_eq!(rejected(lan.route(&page, &conn("127.0.0.1"))), Some(403));
+		assert_eq!(rejected(lan.route(&page, &conn("192.168.1.9"))), Some(403));
+	}
+
+	#[test]
+	fn methods() {
+		let dashboard = dashboard(ROUTER);
+		let route =
+			|method, uri| rejected(dashboard.route(&request(method, uri, &[]), &conn("127.0.0.1")));
+		assert_eq!(route("HEAD", "/__rebab"), None);
+		assert_eq!(route("DELETE", "/__rebab/"), Some(405));
+		assert_eq!(route("GET", "/__rebab/restart"), Some(405));
+		assert_eq!(route("GET", "/__rebab/other"), Some(404));
+	}
+
+	#[tokio::test]
+	async fn restart() {
+		let dashboard = dashboard(ROUTER);
+		dashboard
+			.processes
+			.spawn_command(crate::log::Label::new("app", 0, 3), "sleep 30", None, None)
+			.unwrap();
+		let origin = [("origin", "http://localhost:8080")];
+		let restart = |uri, headers: &[(&str, &str)]| {
+			dashboard.route(&request("POST", uri, headers), &conn("127.0.0.1"))
+		};
+		assert_eq!(
+			rejected(restart("/__rebab/restart?name=app", &[])),
+			Some(403)
+		);
+		assert_eq!(
+			rejected(restart("/__rebab/restart?name=other", &origin)),
+			Some(404)
+		);
+		match restart("/__rebab/restart?name=app", &origin) {
+			Decision::Redirect {
+				status, location, ..
+			} => assert_eq!((status, location.as_str()), (303, "/__rebab/")),
+			_ => panic!("expected a redirect"),
+		}
+		// 再起動し終えてから止める
+		let start = Instant::now();
+		while !dashboard
+			.processes
+			.status()
+			.iter()
+			.any(|v| v.restarts == 1 && !v.restarting)
+		{
+			assert!(start.elapsed() < Duration::from_secs(5));
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+		dashboard.processes.terminate_all();
+	}
+
+	#[test]
+	fn describe_rules() {
+		let api = rule(
+			r#"{"frontend_prefix": "/api/", "methods": ["GET"], "query": {"debug": null}, "backend_port": 3000, "cache": true}"#,
+		);
+		assert_eq!(describe_match(&api), "GET /api/* ?debug");
+		assert_eq!(describe_action(&api), "http://localhost:3000");
+		assert_eq!(describe_options(&api), "cache");
+		let old = rule(r#"{"redirect": "/new/{rest}", "status": 301}"#);
+		assert_eq!(describe_match(&old), "*");
+		assert_eq!(describe_action(&old), "redirect 301 /new/{rest}");
+	}
+}
@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use hyper::header::{
+	ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HeaderMap, HeaderValue,
+};
+use hyper::{Request, Response, StatusCode};
+
+use crate::body::RebabBody;
+use crate::header::{add_vary, parse_q_list};
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::proxy::Connection;
+use crate::service::ProxyError;
+
+/// エラーの本文の形式（同じ q 値なら前のものを選ぶ）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+	Html,
+	Json,
+	Text,
+}
+
+impl Format {
+	/// Accept から選ぶ
+	///
+	/// HTML（ページがあるとき）と JSON は明示されたときだけ選び、`*/*` だけならテキスト
+	pub fn negotiate(headers: &HeaderMap, html: bool) -> Self {
+		let accepted = parse_q_list(headers, ACCEPT);
+		if accepted.is_empty() {
+			return Format::Text;
+		}
+		let find = |names: &[&str]| {
+			names
+				.iter()
+				.find_map(|name| accepted.iter().find(|(k, _)| k == name).map(|(_, q)| *q))
+		};
+		let html = match html {
+			true => find(&["text/html"]),
+			false => None,
+		};
+		let json = find(&["application/json", "application/problem+json"]);
+		let text = find(&["text/plain", "text/*", "*/*"]);
+		[
+			(Format::Html, html),
+			(Format::Json, json),
+			(Format::Text, text),
+		]
+		.into_iter()
+		.filter_map(|(v, q)| Some((v, q?)))
+		.filter(|(_, q)| *q > 0.0)
+		.fold(None::<(Format, f32)>, |best, (v, q)| match best {
+			Some((_, best_q)) if best_q >= q => best,
+			_ => Some((v, q)),
+		})
+		.map_or(Format::Text, |(v, _)| v)
+	}
+}
+
+/// rebab 自身が返したエラー（[`ProxyError`] の付いたレスポンス）を差し替える
+///
+/// Accept に応じて HTML のページ、`application/problem+json`、テキストのどれかにする。
+/// 内部の詳細はログに出し、hide_details ならクライアントには見せない
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+	/// ステータス（`404`）かその種類（`5xx`）ごとの HTML テンプレート
+	pub pages: BTreeMap<String, String>,
+	pub hide_details: bool,
+}
+
+impl ErrorPages {
+	/// ステータスごとのファイルを読む
+	pub fn load(files: &BTreeMap<String, PathBuf>, hide_details: bool) -> Result<Self, String> {
+		let pages = files
+			.iter()
+			.map(|(status, path)| {
+				check_key(status)?;
+				let page = std::fs::read_to_string(path)
+					.map_err(|e| format!("failed to read error page {}: {}", path.display(), e))?;
+				Ok((status.to_ascii_lowercase(), page))
+			})
+			.collect::<Result<_, String>>()?;
+		Ok(Self {
+			pages,
+			hide_details,
+		})
+	}
+
+	/// ステータスのページ（無ければその種類のページ）
+	fn page(&self, status: StatusCode) -> Option<&String> {
+		let class = format!("{}xx", status.as_u16() / 100);
+		self.pages
+			.get(status.as_str())
+			.or_else(|| self.pages.get(&class))
+	}
+
+	fn render(
+		&self,
+		resp: Response<RebabBody>,
+		error: ProxyError,
+		headers: &HeaderMap,
+		path: &str,
+	) -> Response<RebabBody> {
+		let status = resp.status();
+		let page = self.page(status);
+		let format = Format::negotiate(headers, page.is_some());
+		let hidden = self.hide_details && error.detail.is_some();
+		let (mut parts, body) = resp.into_parts();
+		// Accept によって形式が変わる
+		add_vary(&mut parts.headers, "Accept");
+		// テキストで隠すものも無ければ本文はそのまま（`message: detail`）
+		if format == Format::Text && !hidden {
+			return Response::from_parts(parts, body);
+		}
+		let detail = error.detail.filter(|_| !self.hide_details);
+		let reason = status.canonical_reason().unwrap_or_default();
+		let (content_type, body) = match (format, page) {
+			(Format::Html, Some(page)) => {
+				let vars = [
+					("status", status.as_str()),
+					("reason", reason),
+					("message", &error.message),
+					("detail", detail.as_deref().unwrap_or_default()),
+					("path", path),
+				];
+				("text/html; charset=utf-8", expand(page, &vars))
+			}
+			(Format::Json, _) => {
+				let mut problem = serde_json::json!({
+					"type": "about:blank",
+					"title": reason,
+					"status": status.as_u16(),
+					"detail": error.message,
+					"instance": path,
+				});
+				if let Some(detail) = &detail {
+					problem["error"] = detail.clone().into();
+				}
+				("application/problem+json", problem.to_string())
+			}
+			_ => ("text/plain; charset=utf-8", error.message),
+		};
+		// 元の本文（Compression で圧縮したものもある）のヘッダは合わなくなる
+		parts.headers.remove(CONTENT_LENGTH);
+		parts.headers.remove(CONTENT_ENCODING);
+		parts.headers.remove(ETAG);
+		parts
+			.headers
+			.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+		Response::from_parts(parts, RebabBody::from(body))
+	}
+}
+
+impl Middleware for ErrorPages {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let headers = req.headers().clone();
+			let uri = req.uri().clone();
+			let resp = next.run(req, conn).await;
+			let Some(error) = resp.extensions().get::<ProxyError>().cloned() else {
+				return resp;
+			};
+			if let Some(detail) = &error.detail {
+				crate::log::log(format!(
+					"{}: {} for {}: {}",
+					conn.client_ip,
+					resp.status().as_u16(),
+					uri.path(),
+					detail
+				));
+			}
+			self.render(resp, error, &headers, uri.path())
+		})
+	}
+}
+
+/// error_pages のキー（`404` のようなステータスか `5xx` のような種類）か
+pub fn check_key(key: &str) -> Result<(), String> {
+	let valid = match key.to_ascii_lowercase().strip_suffix("xx") {
+		Some(class) => matches!(class, "4" | "5"),
+		None => key.parse::<u16>().is_ok_and(|v| (400..600).contains(&v)),
+	};
+	match valid {
+		true => Ok(()),
+		false => Err(format!(
+			"error page key must be a status from 400 to 599, 4xx or 5xx: {}",
+			key
+		)),
+	}
+}
+
+/// `{name}` を HTML エスケープした値に置き換える（知らない名前はそのまま残す）
+fn expand(template: &str, vars: &[(&str, &str)]) -> String {
+	let mut out = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		out.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let value = rest
+			.find('}')
+			.and_then(|end| vars.iter().find(|(k, _)| *k == &rest[1..end]));
+		match value {
+			Some((name, value)) => {
+				out.push_str(&escape(value));
+				rest = &rest[name.len() + 2..];
+			}
+			None => {
+				out.push('{');
+				rest = &rest[1..];
+			}
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+/// HTML の本文と属性値に入れられるようにする
+pub(crate) fn escape(s: &str) -> String {
+	s.chars()
+		.fold(String::with_capacity(s.len()), |mut out, c| {
+			match c {
+				'&' => out.push_str("&amp;"),
+				'<' => out.push_str("&lt;"),
+				'>' => out.push_str("&gt;"),
+				'"' => out.push_str("&quot;"),
+				'\'' => out.push_str("&#39;"),
+				_ => out.push(c),
+			}
+			out
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hyper::header::VARY;
+
+	fn negotiate(accept: &str, html: bool) -> Format {
+		let mut headers = HeaderMap::new();
+		headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+		Format::negotiate(&headers, html)
+	}
+
+	#[test]
+	fn accept() {
+		assert_eq!(Format::negotiate(&HeaderMap::new(), true), Format::Text);
+		assert_eq!(negotiate("*/*", true), Format::Text);
+		assert_eq!(negotiate("application/json", false), Format::Json);
+		assert_eq!(
+			negotiate("application/json, text/plain, */*", true),
+			Format::Json
+		);
+		let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+		assert_eq!(negotiate(browser, true), Format::Html);
+		assert_eq!(negotiate(browser, false), Format::Text);
+		assert_eq!(
+			negotiate("text/plain, application/json;q=0.5", false),
+			Format::Text
+		);
+	}
+
+	#[test]
+	fn template() {
+		let vars = [("status", "404"), ("path", "/<script>")];
+		assert_eq!(
+			expand("<h1>{status}</h1><p>{path}</p>{unknown}{", &vars),
+			"<h1>404</h1><p>/&lt;script&gt;</p>{unknown}{"
+		);
+	}
+
+	#[test]
+	fn render_varies_on_accept() {
+		let pages = ErrorPages::default();
+		let error = ProxyError {
+			message: "Rebab Bad Gateway".to_string(),
+			detail: Some("refused".to_string()),
+		};
+		let render = |accept: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+			let resp = Response::builder()
+				.status(502)
+				.body(RebabBody::from("Rebab Bad Gateway: refused".to_string()))
+				.unwrap();
+			pages.render(resp, error.clone(), &headers, "/api")
+		};
+		for accept in ["*/*", "application/json"] {
+			let resp = render(accept);
+			assert_eq!(resp.headers().get(VARY).unwrap(), "Accept");
+		}
+		assert_eq!(
+			render("application/json")
+				.headers()
+				.get(CONTENT_TYPE)
+				.unwrap(),
+			"application/problem+json"
+		);
+	}
+
+	#[test]
+	fn render_drops_body_headers() {
+		let error = ProxyError {
+			message: "Rebab Bad Gateway".to_string(),
+			detail: None,
+		};
+		let mut headers = HeaderMap::new();
+		headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+		let resp = Response::builder()
+			.status(502)
+			.header(CONTENT_ENCODING, "gzip")
+			.header(CONTENT_LENGTH, "37")
+			.header(ETAG, "\"v1\"")
+			.body(RebabBody::from("compressed".to_string()))
+			.unwrap();
+		let resp = ErrorPages::default().render(resp, error, &headers, "/api");
+		for name in [CONTENT_ENCODING, CONTENT_LENGTH, ETAG] {
+			assert!(!resp.headers().contains_key(&name), "{}", name);
+		}
+		assert_eq!(resp.headers()[CONTENT_TYPE], "application/problem+json");
+	}
+
+	#[test]
+	fn keys() {
+		assert!(check_key("404").is_ok());
+		assert!(check_key("5xx").is_ok());
+		assert!(check_key("5XX").is_ok());
+		assert!(check_key("200").is_err());
+		assert!(check_key("3xx").is_err());
+		assert!(check_key("default").is_err());
+	}
+}
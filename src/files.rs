@@ -0,0 +1,431 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{
+	ACCEPT_ENCODING, ACCEPT_RANGES, ALLOW, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+	CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+	VARY,
+};
+use hyper::http::request::Parts;
+use hyper::{Method, Response};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::body::RebabBody;
+use crate::service::response;
+
+/// 事前圧縮ファイルの拡張子と Content-Encoding（優先順）
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// 静的ファイル配信の設定とリクエストされたパス
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+	/// 配信するディレクトリ
+	pub root: PathBuf,
+	/// prefix を除いたリクエストパス（percent-encoded のまま）
+	pub path: String,
+	/// ディレクトリへのリクエストで返すファイル名
+	pub index: String,
+	/// 見つからないときに root の index を返す（SPA 向け）
+	pub spa_fallback: bool,
+}
+
+/// ディレクトリ内のファイルを返す
+pub async fn serve(files: &StaticFiles, parts: &Parts) -> Response<RebabBody> {
+	if parts.method != Method::GET && parts.method != Method::HEAD {
+		let mut resp = response(405, "rebab method not allowed".to_string());
+		resp.headers_mut()
+			.insert(ALLOW, "GET, HEAD".parse().unwrap());
+		return resp;
+	}
+	// ルート外を指すパスは存在しないものとして扱う
+	let Some(relative) = sanitize(&files.path) else {
+		return not_found(parts);
+	};
+	let root = match tokio::fs::canonicalize(&files.root).await {
+		Ok(v) => v,
+		Err(e) => {
+			crate::log::log(format!("static dir {}: {}", files.root.display(), e));
+			return not_found(parts);
+		}
+	};
+
+	let mut target = root.join(&relative);
+	if tokio::fs::metadata(&target).await.is_ok_and(|v| v.is_dir()) {
+		// 相対リンクが壊れないよう、ディレクトリは末尾 / 付きにリダイレクトする
+		if !parts.uri.path().ends_with('/') {
+			let location = match parts.uri.query() {
+				Some(q) => format!("{}/?{}", parts.uri.path(), q),
+				None => format!("{}/", parts.uri.path()),
+			};
+			let mut resp = response(301, String::new());
+			if let Ok(v) = location.parse() {
+				resp.headers_mut().insert(LOCATION, v);
+			}
+			return resp;
+		}
+		target.push(&files.index);
+	}
+
+	let resolved = match resolve(&root, &target).await {
+		Some(v) => v,
+		None if files.spa_fallback => match resolve(&root, &root.join(&files.index)).await {
+			Some(v) => v,
+			None => return not_found(parts),
+		},
+		None => return not_found(parts),
+	};
+	match send_file(&resolved, parts).await {
+		Ok(v) => v,
+		Err(e) => {
+			crate::log::log(format!("static file {}: {}", resolved.display(), e));
+			response(500, "rebab failed to read file".to_string())
+		}
+	}
+}
+
+fn not_found(parts: &Parts) -> Response<RebabBody> {
+	response(404, format!("rebab file not found: {}", parts.uri.path()))
+}
+
+/// percent-decode して正規化する。`..` などルート外へ出るパスは None
+fn sanitize(path: &str) -> Option<PathBuf> {
+	let decoded = percent_decode_str(path).decode_utf8().ok()?;
+	let mut out = PathBuf::new();
+	for segment in decoded.split('/') {
+		match segment {
+			"" | "." => continue,
+			".." => return None,
+			s if s.contains(['\\', '\0', ':']) => return None,
+			s => out.push(s),
+		}
+	}
+	Some(out)
+}
+
+/// シンボリックリンクを解決し、ルート内の通常ファイルなら返す
+async fn resolve(root: &Path, target: &Path) -> Option<PathBuf> {
+	let resolved = tokio::fs::canonicalize(target).await.ok()?;
+	let is_file = tokio::fs::metadata(&resolved).await.ok()?.is_file();
+	(is_file && resolved.starts_with(root)).then_some(resolved)
+}
+
+async fn send_file(path: &Path, parts: &Parts) -> std::io::Result<Response<RebabBody>> {
+	let content_type = content_type(path);
+
+	// Accept-Encoding が許せば .br / .gz を代わりに返す
+	let mut encoding = None;
+	let mut has_variant = false;
+	let mut file_path = path.to_path_buf();
+	for (name, ext) in PRECOMPRESSED {
+		let candidate = PathBuf::from(format!("{}.{}", path.display(), ext));
+		if !tokio::fs::metadata(&candidate)
+			.await
+			.is_ok_and(|v| v.is_file())
+		{
+			continue;
+		}
+		has_variant = true;
+		if encoding.is_none() && accepts_encoding(parts, name) {
+			encoding = Some(name);
+			file_path = candidate;
+		}
+	}
+
+	let metadata = tokio::fs::metadata(&file_path).await?;
+	let len = metadata.len();
+	let modified = metadata.modified().ok();
+	let etag = etag(len, modified, encoding);
+
+	let mut builder = Response::builder()
+		.header(CONTENT_TYPE, content_type)
+		.header(ACCEPT_RANGES, "bytes")
+		.header(ETAG, &etag);
+	if let Some(modified) = modified {
+		builder = builder.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+	}
+	if let Some(encoding) = encoding {
+		builder = builder.header(CONTENT_ENCODING, encoding);
+	}
+	if has_variant {
+		builder = builder.header(VARY, "Accept-Encoding");
+	}
+
+	if not_modified(parts, &etag, modified) {
+		return Ok(builder.status(304).body(RebabBody::Static(None)).unwrap());
+	}
+
+	let (status, start, count) = match range(parts, &etag, modified, len) {
+		None => (200, 0, len),
+		Some(Ok((start, end))) => {
+			builder = builder.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+			(206, start, end - start + 1)
+		}
+		Some(Err(())) => {
+			return Ok(builder
+				.status(416)
+				.header(CONTENT_RANGE, format!("bytes */{}", len))
+				.body(RebabBody::Static(None))
+				.unwrap());
+		}
+	};
+	let builder = builder.status(status).header(CONTENT_LENGTH, count);
+	if parts.method == Method::HEAD {
+		return Ok(builder.body(RebabBody::Static(None)).unwrap());
+	}
+	let mut file = tokio::fs::File::open(&file_path).await?;
+	if start > 0 {
+		file.seek(std::io::SeekFrom::Start(start)).await?;
+	}
+	Ok(builder.body(RebabBody::file(file.take(count))).unwrap())
+}
+
+fn etag(len: u64, modified: Option<SystemTime>, encoding: Option<&str>) -> String {
+	let modified = modified
+		.and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+		.unwrap_or_default();
+	match encoding {
+		Some(encoding) => format!("\"{:x}-{:x}-{}\"", modified.as_nanos(), len, encoding),
+		None => format!("\"{:x}-{:x}\"", modified.as_nanos(), len),
+	}
+}
+
+/// If-None-Match が一致する、または If-Modified-Since 以降に更新が無い
+fn not_modified(parts: &Parts, etag: &str, modified: Option<SystemTime>) -> bool {
+	if let Some(v) = header_str(parts, IF_NONE_MATCH) {
+		return v
+			.split(',')
+			.map(|v| v.trim().trim_start_matches("W/"))
+			.any(|v| v == "*" || v == etag);
+	}
+	match (header_str(parts, IF_MODIFIED_SINCE), modified) {
+		(Some(v), Some(modified)) => match httpdate::parse_http_date(v) {
+			Ok(since) => truncate_secs(modified) <= since,
+			Err(_) => false,
+		},
+		_ => false,
+	}
+}
+
+/// Range ヘッダを解釈する
+///
+/// None は全体を返す（Range 無し、複数範囲、If-Range 不一致など）、Err は 416
+fn range(
+	parts: &Parts,
+	etag: &str,
+	modified: Option<SystemTime>,
+	len: u64,
+) -> Option<Result<(u64, u64), ()>> {
+	if parts.method != Method::GET {
+		return None;
+	}
+	let spec = header_str(parts, RANGE)?.trim().strip_prefix("bytes=")?;
+	if let Some(v) = header_str(parts, IF_RANGE) {
+		let fresh = if v.starts_with('"') {
+			v == etag
+		} else {
+			matches!((httpdate::parse_http_date(v), modified), (Ok(date), Some(modified)) if truncate_secs(modified) == date)
+		};
+		if !fresh {
+			return None;
+		}
+	}
+	if spec.contains(',') {
+		return None;
+	}
+	let (start, end) = spec.split_once('-')?;
+	let (start, end) = (start.trim(), end.trim());
+	if start.is_empty() {
+		// bytes=-N: 末尾 N バイト
+		let suffix: u64 = end.parse().ok()?;
+		if suffix == 0 || len == 0 {
+			return Some(Err(()));
+		}
+		return Some(Ok((len.saturating_sub(suffix), len - 1)));
+	}
+	let start: u64 = start.parse().ok()?;
+	let end: u64 = if end.is_empty() {
+		u64::MAX
+	} else {
+		end.parse().ok()?
+	};
+	if end < start {
+		return None;
+	}
+	if start >= len {
+		return Some(Err(()));
+	}
+	Some(Ok((start, end.min(len - 1))))
+}
+
+fn accepts_encoding(parts: &Parts, name: &str) -> bool {
+	let Some(v) = header_str(parts, ACCEPT_ENCODING) else {
+		return false;
+	};
+	v.split(',').any(|item| {
+		let mut params = item.split(';');
+		let coding = params.next().unwrap_or("").trim();
+		let rejected = params.any(|p| {
+			p.trim()
+				.strip_prefix("q=")
+				.and_then(|q| q.trim().parse::<f32>().ok())
+				== Some(0.0)
+		});
+		!rejected && (coding.eq_ignore_ascii_case(name) || coding == "*")
+	})
+}
+
+fn header_str(parts: &Parts, name: hyper::header::HeaderName) -> Option<&str> {
+	parts.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// HTTP-date は秒精度なので比較前に切り捨てる
+fn truncate_secs(time: SystemTime) -> SystemTime {
+	let secs = time
+		.duration_since(UNIX_EPOCH)
+		.map(|v| v.as_secs())
+		.unwrap_or(0);
+	UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn content_type(path: &Path) -> &'static str {
+	let ext = path
+		.extension()
+		.and_then(|v| v.to_str())
+		.unwrap_or("")
+		.to_ascii_lowercase();
+	match ext.as_str() {
+		"html" | "htm" => "text/html; charset=utf-8",
+		"css" => "text/css; charset=utf-8",
+		"js" | "mjs" => "text/javascript; charset=utf-8",
+		"json" | "map" => "application/json",
+		"txt" => "text/plain; charset=utf-8",
+		"md" => "text/markdown; charset=utf-8",
+		"csv" => "text/csv; charset=utf-8",
+		"xml" => "application/xml",
+		"svg" => "image/svg+xml",
+		"png" => "image/png",
+		"jpg" | "jpeg" => "image/jpeg",
+		"gif" => "image/gif",
+		"webp" => "image/webp",
+		"avif" => "image/avif",
+		"ico" => "image/x-icon",
+		"woff" => "font/woff",
+		"woff2" => "font/woff2",
+		"ttf" => "font/ttf",
+		"otf" => "font/otf",
+		"wasm" => "application/wasm",
+		"pdf" => "application/pdf",
+		"zip" => "application/zip",
+		"mp4" => "video/mp4",
+		"webm" => "video/webm",
+		"mp3" => "audio/mpeg",
+		"wav" => "audio/wav",
+		_ => "application/octet-stream",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parts(method: Method, headers: &[(&str, &str)]) -> Parts {
+		let mut builder = hyper::Request::builder().method(method).uri("/a.txt");
+		for (k, v) in headers {
+			builder = builder.header(*k, *v);
+		}
+		builder.body(()).unwrap().into_parts().0
+	}
+
+	#[test]
+	fn sanitize_paths() {
+		assert_eq!(sanitize("a/./b//c.txt"), Some(PathBuf::from("a/b/c.txt")));
+		// 絶対パスもルートからの相対パスになる
+		assert_eq!(sanitize("/etc/passwd"), Some(PathBuf::from("etc/passwd")));
+		assert_eq!(sanitize("%E3%81%82.txt"), Some(PathBuf::from("あ.txt")));
+		assert_eq!(sanitize("../etc/passwd"), None);
+		assert_eq!(sanitize("a/../../b"), None);
+		assert_eq!(sanitize("%2e%2e/etc/passwd"), None);
+		assert_eq!(sanitize("a%2F%2E%2E%2F..%2Fb"), None);
+		assert_eq!(sanitize("..%5Cwindows"), None);
+		assert_eq!(sanitize("C:/windows"), None);
+		assert_eq!(sanitize("a%00.txt"), None);
+		assert_eq!(sanitize("%FF"), None);
+	}
+
+	#[tokio::test]
+	async fn resolve_stays_in_root() {
+		let dir = std::env::temp_dir().join(format!("rebab-files-{}", std::process::id()));
+		let root = dir.join("root");
+		std::fs::create_dir_all(root.join("sub")).unwrap();
+		std::fs::write(root.join("a.txt"), "a").unwrap();
+		std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+		let root = root.canonicalize().unwrap();
+		assert_eq!(
+			resolve(&root, &root.join("a.txt")).await,
+			Some(root.join("a.txt"))
+		);
+		// ディレクトリと存在しないファイルは返さない
+		assert_eq!(resolve(&root, &root.join("sub")).await, None);
+		assert_eq!(resolve(&root, &root.join("b.txt")).await, None);
+		#[cfg(unix)]
+		{
+			std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+			assert_eq!(resolve(&root, &root.join("link.txt")).await, None);
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn ranges() {
+		let etag = "\"1-a\"";
+		let get = |range: &str| range_of(Method::GET, &[("range", range)]);
+		assert_eq!(get("bytes=0-3"), Some(Ok((0, 3))));
+		assert_eq!(get("bytes=5-"), Some(Ok((5, 9))));
+		assert_eq!(get("bytes=5-100"), Some(Ok((5, 9))));
+		assert_eq!(get("bytes=-3"), Some(Ok((7, 9))));
+		assert_eq!(get("bytes=-20"), Some(Ok((0, 9))));
+		// 範囲外は 416
+		assert_eq!(get("bytes=10-"), Some(Err(())));
+		assert_eq!(get("bytes=-0"), Some(Err(())));
+		// 複数範囲と解釈できないものは全体を返す
+		assert_eq!(get("bytes=0-1,3-4"), None);
+		assert_eq!(get("bytes=3-1"), None);
+		assert_eq!(get("bytes=a-b"), None);
+		assert_eq!(get("items=0-1"), None);
+		assert_eq!(range_of(Method::HEAD, &[("range", "bytes=0-3")]), None);
+		// If-Range が一致しなければ全体
+		let if_range = |v: &str| range_of(Method::GET, &[("range", "bytes=0-3"), ("if-range", v)]);
+		assert_eq!(if_range(etag), Some(Ok((0, 3))));
+		assert_eq!(if_range("\"other\""), None);
+
+		fn range_of(method: Method, headers: &[(&str, &str)]) -> Option<Result<(u64, u64), ()>> {
+			range(&parts(method, headers), "\"1-a\"", None, 10)
+		}
+	}
+
+	#[test]
+	fn conditional() {
+		let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+		let etag = "\"1-a\"";
+		let check = |headers: &[(&str, &str)]| {
+			not_modified(&parts(Method::GET, headers), etag, Some(modified))
+		};
+		assert!(!check(&[]));
+		assert!(check(&[("if-none-match", "\"x\", W/\"1-a\"")]));
+		assert!(check(&[("if-none-match", "*")]));
+		assert!(!check(&[("if-none-match", "\"x\"")]));
+		let date = |v: SystemTime| httpdate::fmt_http_date(v);
+		// 秒未満は切り捨てて比べる
+		assert!(check(&[("if-modified-since", &date(modified))]));
+		assert!(!check(&[(
+			"if-modified-since",
+			&date(modified - Duration::from_secs(1))
+		)]));
+		// If-None-Match があれば If-Modified-Since は見ない
+		assert!(!check(&[
+			("if-none-match", "\"x\""),
+			("if-modified-since", &date(modified))
+		]));
+	}
+}
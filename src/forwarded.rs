@@ -0,0 +1,320 @@
+use std::net::IpAddr;
+
+use hyper::header::{FORWARDED, HOST, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::http::uri::Authority;
+use ipnet::IpNet;
+
+use crate::config::Scheme;
+use crate::proxy::Connection;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// 転送元の情報を運ぶヘッダ（信頼できない接続からのものは捨てる）
+const FORWARDING_HEADERS: [HeaderName; 6] = [
+	X_FORWARDED_FOR,
+	X_FORWARDED_HOST,
+	X_FORWARDED_PROTO,
+	X_FORWARDED_PORT,
+	FORWARDED,
+	X_REAL_IP,
+];
+
+/// 転送ヘッダを信頼するプロキシのアドレス範囲
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+	pub fn new(nets: Vec<IpNet>) -> Self {
+		Self(nets)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		let ip = ip.to_canonical();
+		self.0.iter().any(|v| v.contains(&ip))
+	}
+
+	/// クライアントの IP を求める
+	///
+	/// 接続元が信頼できるプロキシなら X-Forwarded-For を右から辿り、最初の信頼できないアドレスを返す
+	pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+		let peer = peer.to_canonical();
+		if !self.contains(peer) {
+			return peer;
+		}
+		let chain = forwarded_for(headers);
+		chain
+			.iter()
+			.rev()
+			.find(|v| !self.contains(**v))
+			.or(chain.first())
+			.copied()
+			.unwrap_or(peer)
+	}
+}
+
+/// X-Forwarded-For のアドレス列（複数のヘッダは連結、パースできない要素は無視）
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+	headers
+		.get_all(X_FORWARDED_FOR)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.filter_map(|v| v.trim().parse::<IpAddr>().ok())
+		.map(|v| v.to_canonical())
+		.collect()
+}
+
+/// 転送するリクエストに X-Forwarded-*、Forwarded、X-Real-IP を付ける
+///
+/// 信頼できる接続からのヘッダは引き継いで追記し、それ以外は捨てて作り直す
+pub fn apply(parts: &Parts, dst: &mut HeaderMap, conn: &Connection) {
+	let proto = conn.scheme.as_str();
+	let peer = conn.peer.ip().to_canonical();
+	if !conn.trusted {
+		for name in FORWARDING_HEADERS {
+			dst.remove(name);
+		}
+	}
+
+	// X-Forwarded-For: client, proxy1, proxy2
+	let chain = dst
+		.get_all(X_FORWARDED_FOR)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.map(str::trim)
+		.filter(|v| !v.is_empty())
+		.chain([peer.to_string().as_str()])
+		.collect::<Vec<_>>()
+		.join(", ");
+	if let Ok(v) = HeaderValue::from_str(&chain) {
+		dst.insert(X_FORWARDED_FOR, v);
+	}
+	dst.insert(
+		X_REAL_IP,
+		HeaderValue::from_str(&conn.client_ip.to_string()).unwrap(),
+	);
+
+	// Forwarded: for=192.0.2.1;proto=http;host="localhost:8080"
+	let authority = original_authority(parts);
+	let mut element = format!("for={};proto={}", forwarded_node(peer), proto);
+	if let Some(authority) = &authority {
+		element.push_str(&format!(r#";host="{}""#, authority.as_str()));
+	}
+	if let Ok(v) = HeaderValue::from_str(&element) {
+		dst.append(FORWARDED, v);
+	}
+
+	// 信頼できるプロキシが付けたものがあればそのまま使う
+	if let Some(authority) = &authority {
+		if !dst.contains_key(X_FORWARDED_HOST) {
+			// 例: localhost:8080
+			dst.insert(
+				X_FORWARDED_HOST,
+				HeaderValue::from_str(authority.as_str()).unwrap(),
+			);
+		}
+		if let Some(port) = authority.port_u16()
+			&& !dst.contains_key(X_FORWARDED_PORT)
+		{
+			dst.insert(X_FORWARDED_PORT, HeaderValue::from(port));
+		}
+	}
+	// scheme() が信頼できるヘッダを反映済みなので上書きする
+	dst.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+}
+
+/// クライアントが接続したスキーム
+///
+/// 優先順: listener の明示的な設定、信頼できるプロキシの X-Forwarded-Proto / Forwarded の proto、
+/// listener 自体のスキーム（rebab は TLS を終端しないので http）
+pub fn scheme(headers: &HeaderMap, trusted: bool, configured: Option<Scheme>) -> Scheme {
+	if let Some(v) = configured {
+		return v;
+	}
+	if trusted && let Some(v) = forwarded_proto(headers) {
+		return v;
+	}
+	Scheme::Http
+}
+
+/// 最初のプロキシが見たスキーム（X-Forwarded-Proto は左端、Forwarded は最初の要素）
+fn forwarded_proto(headers: &HeaderMap) -> Option<Scheme> {
+	let header = |name| {
+		headers
+			.get_all(name)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.map(str::trim)
+			.find(|v| !v.is_empty())
+	};
+	if let Some(v) = header(X_FORWARDED_PROTO) {
+		return v.parse().ok();
+	}
+	header(FORWARDED)?
+		.split(';')
+		.filter_map(|v| v.split_once('='))
+		.find(|(k, _)| k.trim().eq_ignore_ascii_case("proto"))
+		.and_then(|(_, v)| v.trim().trim_matches('"').parse().ok())
+}
+
+/// Forwarded の node（IPv6 は `"[2001:db8::1]"`）
+fn forwarded_node(ip: IpAddr) -> String {
+	match ip {
+		IpAddr::V4(v) => v.to_string(),
+		IpAddr::V6(v) => format!("\"[{}]\"", v),
+	}
+}
+
+pub fn original_authority(parts: &Parts) -> Option<Authority> {
+	// 1) 絶対URIなら URI の authority を優先
+	if let Some(a) = parts.uri.authority().cloned() {
+		return Some(a);
+	}
+	// 2) 通常は Host ヘッダ
+	parts
+		.headers
+		.get(HOST)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|s| s.parse::<Authority>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::serve::Listener;
+	use std::net::SocketAddr;
+
+	fn listener(trusted: &[&str], scheme: Option<Scheme>) -> Listener {
+		Listener {
+			addr: "127.0.0.1:8080".parse().unwrap(),
+			trusted_proxies: TrustedProxies::new(
+				trusted.iter().map(|v| v.parse().unwrap()).collect(),
+			),
+			proxy_protocol: false,
+			scheme,
+			max_header_size: None,
+			max_headers: None,
+			middleware: vec![],
+		}
+	}
+
+	fn request(host: &str, headers: &[(&str, &str)]) -> Parts {
+		let mut builder = hyper::Request::builder().uri("/path").header(HOST, host);
+		for (name, value) in headers {
+			builder = builder.header(*name, *value);
+		}
+		builder.body(()).unwrap().into_parts().0
+	}
+
+	/// 転送先に付くヘッダ
+	fn forwarded(peer: &str, parts: &Parts, listener: &Listener) -> HeaderMap {
+		let peer: SocketAddr = peer.parse().unwrap();
+		let conn = Connection::new(peer, &parts.headers, listener);
+		let mut dst = parts.headers.clone();
+		apply(parts, &mut dst, &conn);
+		dst
+	}
+
+	fn get<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+		headers.get(name).map(|v| v.to_str().unwrap())
+	}
+
+	#[test]
+	fn ipv6_host_with_port() {
+		let parts = request("[2001:db8::1]:8080", &[]);
+		let headers = forwarded("[2001:db8::2]:50000", &parts, &listener(&[], None));
+		assert_eq!(
+			get(&headers, "x-forwarded-host"),
+			Some("[2001:db8::1]:8080")
+		);
+		assert_eq!(get(&headers, "x-forwarded-port"), Some("8080"));
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"));
+		assert_eq!(get(&headers, "x-forwarded-for"), Some("2001:db8::2"));
+		assert_eq!(
+			get(&headers, "forwarded"),
+			Some(r#"for="[2001:db8::2]";proto=http;host="[2001:db8::1]:8080""#)
+		);
+	}
+
+	#[test]
+	fn ipv6_host_without_port() {
+		let parts = request("[fe80::1]", &[]);
+		let headers = forwarded("[fe80::2]:50000", &parts, &listener(&[], None));
+		assert_eq!(get(&headers, "x-forwarded-host"), Some("[fe80::1]"));
+		assert_eq!(get(&headers, "x-forwarded-port"), None);
+		// localhost 以外でも https とはみなさない
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"));
+	}
+
+	#[test]
+	fn ipv6_loopback_host() {
+		let parts = request("[::1]:8080", &[]);
+		let headers = forwarded("[::1]:50000", &parts, &listener(&[], None));
+		assert_eq!(get(&headers, "x-forwarded-host"), Some("[::1]:8080"));
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"));
+		assert_eq!(get(&headers, "x-real-ip"), Some("::1"));
+	}
+
+	#[test]
+	fn lan_hosts_are_http() {
+		for host in ["192.168.1.10:8080", "api:8080", "example.com"] {
+			let parts = request(host, &[]);
+			let headers = forwarded("192.168.1.20:50000", &parts, &listener(&[], None));
+			assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"), "{}", host);
+		}
+	}
+
+	#[test]
+	fn configured_scheme_wins() {
+		let parts = request("[2001:db8::1]", &[("x-forwarded-proto", "http")]);
+		let listener = listener(&["::1/128"], Some(Scheme::Https));
+		let headers = forwarded("[::1]:50000", &parts, &listener);
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("https"));
+	}
+
+	#[test]
+	fn trusted_forwarded_proto() {
+		let parts = request("[2001:db8::1]:443", &[("x-forwarded-proto", "https, http")]);
+		let headers = forwarded("[::1]:50000", &parts, &listener(&["::1/128"], None));
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("https"));
+
+		let parts = request(
+			"[2001:db8::1]:443",
+			&[("forwarded", r#"for="[2001:db8::3]";proto=https"#)],
+		);
+		let headers = forwarded("[::1]:50000", &parts, &listener(&["::1/128"], None));
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("https"));
+	}
+
+	#[test]
+	fn untrusted_forwarded_proto_is_ignored() {
+		let parts = request("[2001:db8::1]:443", &[("x-forwarded-proto", "https")]);
+		let headers = forwarded("[2001:db8::2]:50000", &parts, &listener(&["::1/128"], None));
+		assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"));
+	}
+
+	#[test]
+	fn ipv4_mapped_ipv6_peer() {
+		let parts = request("localhost:8080", &[("x-forwarded-for", "2001:db8::5")]);
+		let headers = forwarded(
+			"[::ffff:10.0.0.1]:50000",
+			&parts,
+			&listener(&["10.0.0.0/8"], None),
+		);
+		assert_eq!(get(&headers, "x-real-ip"), Some("2001:db8::5"));
+		assert_eq!(
+			get(&headers, "x-forwarded-for"),
+			Some("2001:db8::5, 10.0.0.1")
+		);
+	}
+}
@@ -0,0 +1,72 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, VARY};
+
+/// Accept や Accept-Encoding のような `name;q=0.5` のリストを、小文字の名前と q 値にする
+///
+/// q 値の読めない項目は捨てる
+pub(crate) fn parse_q_list(headers: &HeaderMap, name: HeaderName) -> Vec<(String, f32)> {
+	headers
+		.get_all(name)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.filter_map(|v| {
+			let mut params = v.split(';');
+			let name = params.next()?.trim().to_ascii_lowercase();
+			let q = params
+				.filter_map(|v| v.split_once('='))
+				.find(|(k, _)| k.trim().eq_ignore_ascii_case("q"))
+				.map_or(Some(1.0), |(_, v)| v.trim().parse::<f32>().ok())?;
+			(!name.is_empty()).then_some((name, q))
+		})
+		.collect()
+}
+
+/// Vary に name が無ければ追加する
+pub(crate) fn add_vary(headers: &mut HeaderMap, name: &'static str) {
+	let listed = headers
+		.get_all(VARY)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.any(|v| {
+			let v = v.trim();
+			v == "*" || v.eq_ignore_ascii_case(name)
+		});
+	if !listed {
+		headers.append(VARY, HeaderValue::from_static(name));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hyper::header::ACCEPT;
+
+	#[test]
+	fn q_list() {
+		let mut headers = HeaderMap::new();
+		headers.append(ACCEPT, HeaderValue::from_static("Text/HTML, */*;q=0.8"));
+		headers.append(ACCEPT, HeaderValue::from_static("a; Q=0 ,b;q=x, ;q=1"));
+		assert_eq!(
+			parse_q_list(&headers, ACCEPT),
+			[
+				("text/html".to_string(), 1.0),
+				("*/*".to_string(), 0.8),
+				("a".to_string(), 0.0),
+			]
+		);
+	}
+
+	#[test]
+	fn vary() {
+		let mut headers = HeaderMap::new();
+		add_vary(&mut headers, "Accept");
+		add_vary(&mut headers, "Accept-Encoding");
+		add_vary(&mut headers, "accept");
+		assert_eq!(headers.get_all(VARY).iter().count(), 2);
+		let mut headers = HeaderMap::new();
+		headers.insert(VARY, HeaderValue::from_static("*"));
+		add_vary(&mut headers, "Accept");
+		assert_eq!(headers.get_all(VARY).iter().count(), 1);
+	}
+}
@@ -1 +1,74 @@
+//! rebab をライブラリとして使う
+//!
+//! 設定ファイルと同じ [`config::Router`] をコードで組み立てるなら、[`Rebab`] がプロセスの起動から待ち受けまでを行い、
+//! 再読み込み・停止できるハンドルを返す（rebab コマンドもこれを使う）。
+//!
+//! 振り分けを自分で書くなら [`Proxy`] を実装して、リクエストごとにどう応えるか（転送、ファイル、リダイレクト、固定レスポンス、拒否）を
+//! [`Decision`] で返す。[`serve()`] がそれを frontend で待ち受ける。
+//!
+//! ```no_run
+//! use rebab::{Connection, Decision, Proxy, Routing, Upstream};
+//!
+//! struct Backend;
+//!
+//! impl Proxy for Backend {
+//!     fn route(&self, parts: &hyper::http::request::Parts, conn: &Connection) -> Routing {
+//!         if parts.headers.contains_key("x-canary") {
+//!             let uri = format!("http://localhost:3001{}", parts.uri.path());
+//!             return Decision::Forward(Upstream::from(uri.parse::<hyper::Uri>().unwrap())).into();
+//!         }
+//!         Decision::Reject {
+//!             status: 403,
+//!             reason: format!("{} is not allowed", conn.client_ip),
+//!         }
+//!         .into()
+//!     }
+//! }
+//!
+//! # async fn run() {
+//! let addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+//! let listener = rebab::Listener::from(addr);
+//! rebab::serve(listener, Backend).await.unwrap();
+//! # }
+//! ```
+//!
+//! 止める必要があれば [`Server::bind`] を使う。
+//!
+//! 認証やログなど、リクエストとレスポンスの間に挟む処理は [`Middleware`] で書き、
+//! すべてのリクエストに挟むなら [`Listener`] に、ルートごとなら [`Routing`] に渡す。
+//!
+//! # バージョン
+//!
+//! クレートのルートから辿れるものが公開 API で、semver に従う。0.x の間は互換性のない変更でマイナーバージョンを上げる。
+
+pub mod access;
+mod app;
+pub mod auth;
+pub mod body;
+pub mod cache;
+pub mod compress;
 pub mod config;
+mod dashboard;
+pub mod error_page;
+pub mod files;
+pub mod forwarded;
+mod header;
+pub mod limit;
+pub mod log;
+pub mod matcher;
+pub mod middleware;
+mod process;
+pub mod proxy;
+mod proxy_protocol;
+pub mod serve;
+pub mod service;
+mod template;
+mod watch;
+
+pub use app::Rebab;
+pub use middleware::{Middleware, Next};
+pub use proxy::{Connection, Decision, HeaderOp, Proxy, Routing, Upstream};
+pub use serve::{Listener, Server, serve};
+
+/// このクレートのバージョン
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::{CONTENT_LENGTH, HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::{Request, Response};
+use tokio::sync::Semaphore;
+
+use crate::body::RebabBody;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::proxy::Connection;
+use crate::service::response;
+
+/// バケットの数の上限（満タンに戻ったものを捨て、それでも多ければ古いものから捨てる）
+const MAX_BUCKETS: usize = 10_000;
+
+/// 待ち行列で待つ時間の既定値
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// リクエストを数える単位
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateKey {
+	/// クライアントの IP ごと
+	ClientIp,
+	/// ヘッダの値ごと（ヘッダが無ければクライアントの IP）
+	Header(HeaderName),
+	/// ルール全体で一つ
+	Global,
+}
+
+impl FromStr for RateKey {
+	type Err = String;
+
+	/// `client_ip`、`global`、`header:<name>`
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"client_ip" => Ok(RateKey::ClientIp),
+			"global" => Ok(RateKey::Global),
+			_ => match s.strip_prefix("header:") {
+				Some(name) => HeaderName::from_bytes(name.trim().as_bytes())
+					.map(RateKey::Header)
+					.map_err(|_| format!("invalid header name in rate limit key: {}", name)),
+				None => Err(format!(
+					"rate limit key must be client_ip, global or header:<name>: {}",
+					s
+				)),
+			},
+		}
+	}
+}
+
+/// トークンバケット
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+/// 一定の割合を超えたリクエストを 429 で断る（トークンバケット）
+///
+/// バケットはルールごとにメモリに持ち、設定を再読み込みすると作り直す（数え直しになる）
+pub struct RateLimit {
+	/// 1 秒あたりに補充するリクエスト数
+	rate: f64,
+	/// 溜められるリクエスト数（一度に通せる数）
+	burst: f64,
+	key: RateKey,
+	buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimit {
+	pub fn new(rate: f64, burst: u32, key: RateKey) -> Result<Self, String> {
+		if !(rate.is_finite() && rate > 0.0) {
+			return Err(format!("rate limit must be positive: {}", rate));
+		}
+		if burst == 0 {
+			return Err("rate limit burst must be at least 1".to_string());
+		}
+		Ok(Self {
+			rate,
+			burst: burst as f64,
+			key,
+			buckets: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// burst を省略したときの値（1 秒分、少なくとも 1）
+	pub fn default_burst(rate: f64) -> u32 {
+		rate.ceil().max(1.0) as u32
+	}
+
+	fn key(&self, req: &Request<RebabBody>, conn: &Connection) -> String {
+		match &self.key {
+			RateKey::ClientIp => conn.client_ip.to_string(),
+			RateKey::Header(name) => match req.headers().get(name) {
+				Some(v) => format!("{}={}", name, String::from_utf8_lossy(v.as_bytes())),
+				None => conn.client_ip.to_string(),
+			},
+			RateKey::Global => String::new(),
+		}
+	}
+
+	/// 1 リクエスト分のトークンを取る。足りなければ次に取れるまでの時間
+	fn take(&self, key: String) -> Result<(), Duration> {
+		let now = Instant::now();
+		let mut buckets = self.buckets.lock().unwrap();
+		if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+			let (rate, burst) = (self.rate, self.burst);
+			buckets.retain(|_, v| {
+				v.tokens + now.duration_since(v.updated).as_secs_f64() * rate < burst
+			});
+			// キーが次々に変わるとどれも満タンにならないので、長く使われていない半分を捨てる
+			// （捨てたキーは満タンからやり直す）
+			if buckets.len() >= MAX_BUCKETS {
+				let mut updated: Vec<Instant> = buckets.values().map(|v| v.updated).collect();
+				let (_, median, _) = updated.select_nth_unstable(MAX_BUCKETS / 2);
+				let median = *median;
+				buckets.retain(|_, v| v.updated > median);
+			}
+		}
+		let bucket = buckets.entry(key).or_insert(Bucket {
+			tokens: self.burst,
+			updated: now,
+		});
+		let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+		bucket.updated = now;
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			Ok(())
+		} else {
+			Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+		}
+	}
+}
+
+impl Middleware for RateLimit {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			match self.take(self.key(&req, conn)) {
+				Ok(()) => next.run(req, conn).await,
+				Err(wait) => retry_after(429, "rebab rate limit exceeded", wait),
+			}
+		})
+	}
+}
+
+/// upstream への同時リクエスト数を制限し、超えた分は待たせるか 503 で断る
+///
+/// 同じ upstream に転送するルールは一つを共有する（app::concurrency_limits）。
+/// 設定を再読み込みすると作り直すので、そのとき処理中のリクエストは新しい制限には数えない
+pub struct ConcurrencyLimit {
+	permits: Arc<Semaphore>,
+	/// 空きを待てるリクエスト数（0 なら待たずに断る）
+	queue: usize,
+	waiting: AtomicUsize,
+	/// 空きを待つ最大時間
+	timeout: Duration,
+}
+
+impl ConcurrencyLimit {
+	pub fn new(max: usize, queue: usize, timeout: Duration) -> Result<Self, String> {
+		if max == 0 {
+			return Err("max_concurrent must be at least 1".to_string());
+		}
+		Ok(Self {
+			permits: Arc::new(Semaphore::new(max)),
+			queue,
+			waiting: AtomicUsize::new(0),
+			timeout,
+		})
+	}
+}
+
+impl Middleware for ConcurrencyLimit {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let permit = match self.permits.clone().try_acquire_owned() {
+				Ok(v) => v,
+				Err(_) => {
+					if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.queue {
+						self.waiting.fetch_sub(1, Ordering::SeqCst);
+						return retry_after(
+							503,
+							"rebab too many requests in flight",
+							Duration::ZERO,
+						);
+					}
+					let acquired =
+						tokio::time::timeout(self.timeout, self.permits.clone().acquire_owned())
+							.await;
+					self.waiting.fetch_sub(1, Ordering::SeqCst);
+					match acquired {
+						Ok(Ok(v)) => v,
+						_ => {
+							return retry_after(
+								503,
+								"rebab timed out waiting for a free upstream slot",
+								Duration::ZERO,
+							);
+						}
+					}
+				}
+			};
+			// レスポンスのボディを送り終えるまで枠を使う
+			next.run(req, conn)
+				.await
+				.map(|body| RebabBody::guarded(body, permit))
+		})
+	}
+}
+
+/// リクエストのボディが大きすぎれば 413 で断る
+///
+/// Content-Length で先に断り、無い（chunked）か偽っていれば読みながら数える。
+/// upstream がボディを読み終える前に返したレスポンスは、その時点で超えていないのでそのまま返す
+pub struct BodyLimit {
+	max: u64,
+}
+
+impl BodyLimit {
+	pub fn new(max: u64) -> Self {
+		Self { max }
+	}
+
+	fn too_large(&self, uri: &hyper::Uri, conn: &Connection) -> Response<RebabBody> {
+		crate::log::log(format!(
+			"{}: request body over {} bytes for {}",
+			conn.client_ip,
+			self.max,
+			uri.path()
+		));
+		response(413, "rebab request body too large".to_string())
+	}
+}
+
+impl Middleware for BodyLimit {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let len = req
+				.headers()
+				.get(CONTENT_LENGTH)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.parse::<u64>().ok());
+			if len.is_some_and(|v| v > self.max) {
+				return self.too_large(req.uri(), conn);
+			}
+			let uri = req.uri().clone();
+			let exceeded = Arc::new(AtomicBool::new(false));
+			let req = req.map(|body| body.limited(self.max, exceeded.clone()));
+			// 超えたところで upstream への転送はエラーになるので、そのレスポンスを差し替える
+			let resp = next.run(req, conn).await;
+			match exceeded.load(Ordering::SeqCst) {
+				true => self.too_large(&uri, conn),
+				false => resp,
+			}
+		})
+	}
+}
+
+/// Retry-After 付きのエラー（秒に切り上げ、少なくとも 1）
+fn retry_after(status: u16, reason: &str, wait: Duration) -> Response<RebabBody> {
+	let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+	let mut resp = response(status, reason.to_string());
+	resp.headers_mut()
+		.insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+	resp
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use http_body_util::BodyExt;
+
+	#[test]
+	fn rate_keys() {
+		assert_eq!("client_ip".parse(), Ok(RateKey::ClientIp));
+		assert_eq!("global".parse(), Ok(RateKey::Global));
+		assert_eq!(
+			"header:X-Api-Key".parse(),
+			Ok(RateKey::Header(HeaderName::from_static("x-api-key")))
+		);
+		assert!("ip".parse::<RateKey>().is_err());
+		assert!("header:bad name".parse::<RateKey>().is_err());
+	}
+
+	#[test]
+	fn burst_then_refill() {
+		let limit = RateLimit::new(10.0, 2, RateKey::Global).unwrap();
+		assert!(limit.take(String::new()).is_ok());
+		assert!(limit.take(String::new()).is_ok());
+		let wait = limit.take(String::new()).unwrap_err();
+		assert!(wait <= Duration::from_millis(100), "{:?}", wait);
+		// 別のキーは別のバケット
+		assert!(limit.take("other".to_string()).is_ok());
+		std::thread::sleep(Duration::from_millis(110));
+		assert!(limit.take(String::new()).is_ok());
+	}
+
+	#[test]
+	fn bucket_cap() {
+		let limit = RateLimit::new(0.001, 1, RateKey::Global).unwrap();
+		for i in 0..MAX_BUCKETS * 3 {
+			assert!(limit.take(i.to_string()).is_ok());
+			assert!(limit.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+		}
+		// 最近のキーは残っている
+		assert!(limit.take((MAX_BUCKETS * 3 - 1).to_string()).is_err());
+	}
+
+	/// limit を通して、ボディを返さずに（枠を使ったまま）レスポンスを返す
+	async fn request(limit: Arc<ConcurrencyLimit>) -> Response<RebabBody> {
+		let addr = "127.0.0.1:8080".parse().unwrap();
+		let conn = Connection {
+			peer: addr,
+			local: addr,
+			client_ip: addr.ip(),
+			trusted: false,
+			scheme: crate::config::Scheme::Http,
+		};
+		let next = Next::new(
+			&[],
+			Box::new(|_, _| Box::pin(async { Response::new(RebabBody::from("ok".to_string())) })),
+		);
+		let req = Request::new(RebabBody::from(String::new()));
+		limit.call(req, &conn, next).await
+	}
+
+	fn rejected(resp: &Response<RebabBody>) -> &str {
+		assert_eq!(resp.status(), 503);
+		assert_eq!(resp.headers()[RETRY_AFTER], "1");
+		&resp
+			.extensions()
+			.get::<crate::service::ProxyError>()
+			.unwrap()
+			.message
+	}
+
+	#[tokio::test]
+	async fn concurrency_queue() {
+		let limit = Arc::new(ConcurrencyLimit::new(1, 1, Duration::from_millis(200)).unwrap());
+		let first = request(limit.clone()).await;
+		assert_eq!(first.status(), 200);
+		// 空くまで待つ
+		let queued = tokio::spawn(request(limit.clone()));
+		while limit.waiting.load(Ordering::SeqCst) == 0 {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		// 待ち行列も埋まっている
+		let full = request(limit.clone()).await;
+		assert_eq!(rejected(&full), "rebab too many requests in flight");
+		// ボディを送り終えると枠が空く
+		assert_eq!(first.into_body().collect().await.unwrap().to_bytes(), "ok");
+		let second = queued.await.unwrap();
+		assert_eq!(second.status(), 200);
+		assert_eq!(limit.waiting.load(Ordering::SeqCst), 0);
+		// 空かなければ queue_timeout で断る
+		let timed_out = request(limit.clone()).await;
+		assert_eq!(
+			rejected(&timed_out),
+			"rebab timed out waiting for a free upstream slot"
+		);
+		drop(second);
+		assert_eq!(request(limit).await.status(), 200);
+	}
+
+	#[tokio::test]
+	async fn body_limit_while_streaming() {
+		let body = |max: u64| {
+			let exceeded = Arc::new(AtomicBool::new(false));
+			let body = RebabBody::from("0123456789".to_string()).limited(max, exceeded.clone());
+			(body, exceeded)
+		};
+		let (ok, exceeded) = body(10);
+		assert_eq!(ok.collect().await.unwrap().to_bytes(), "0123456789");
+		assert!(!exceeded.load(Ordering::SeqCst));
+		let (over, exceeded) = body(9);
+		assert!(over.collect().await.is_err());
+		assert!(exceeded.load(Ordering::SeqCst));
+	}
+}
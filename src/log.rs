@@ -1,3 +1,8 @@
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
 pub fn log(message: impl AsRef<str>) {
 	println!("rebab: {}", message.as_ref())
 }
@@ -15,3 +20,148 @@ pub fn addr_to_url(addr: std::net::SocketAddr) -> String {
 	};
 	format!("http://{}:{}", host, addr.port())
 }
+
+/// 子プロセス出力のストリーム種別
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+	Stdout,
+	Stderr,
+}
+
+impl Stream {
+	/// ラベルと本文の区切り（stdout は `|`、stderr は `!`）
+	fn marker(self) -> char {
+		match self {
+			Stream::Stdout => '|',
+			Stream::Stderr => '!',
+		}
+	}
+}
+
+// cyan, yellow, green, magenta, blue, red
+const COLORS: [&str; 6] = ["36", "33", "32", "35", "34", "31"];
+
+/// stdout が TTY で、かつ NO_COLOR が未設定のときだけ色を付ける
+fn color_enabled() -> bool {
+	static ENABLED: OnceLock<bool> = OnceLock::new();
+	*ENABLED
+		.get_or_init(|| std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none())
+}
+
+/// 管理プロセスの出力に付けるラベル（foreman / docker-compose 風）
+///
+/// Format: `12:34:56 api    | line content`
+#[derive(Debug, Clone)]
+pub struct Label {
+	name: String,
+	width: usize,
+	color: &'static str,
+}
+
+impl Label {
+	/// # Arguments
+	/// * `name` - プロセス名
+	/// * `index` - 色の選択に使う連番
+	/// * `width` - 揃えるラベル幅（全プロセス名の最大長）
+	pub fn new(name: impl Into<String>, index: usize, width: usize) -> Self {
+		Self {
+			name: name.into(),
+			width,
+			color: COLORS[index % COLORS.len()],
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// 子プロセスの1行をラベル付きで出力する
+	pub fn line(&self, stream: Stream, line: &str) {
+		let prefix = format!(
+			"{} {:<width$} {}",
+			chrono::Local::now().format("%H:%M:%S"),
+			self.name,
+			stream.marker(),
+			width = self.width
+		);
+		if color_enabled() {
+			println!("\x1b[{}m{}\x1b[0m {}", self.color, prefix, line)
+		} else {
+			println!("{} {}", prefix, line)
+		}
+	}
+}
+
+/// サイズでローテーションするログファイル
+///
+/// `api.log` が `max_size` を超えそうになると `api.log.1`, `api.log.2`, ... へずらし、
+/// `max_files` 個を超えた古いものは削除する
+#[derive(Debug)]
+pub struct RotatingFile {
+	path: PathBuf,
+	file: File,
+	size: u64,
+	max_size: u64,
+	max_files: usize,
+}
+
+impl RotatingFile {
+	pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+	pub const DEFAULT_MAX_FILES: usize = 5;
+
+	pub fn open(path: impl AsRef<Path>, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+		let path = path.as_ref().to_path_buf();
+		if let Some(dir) = path.parent()
+			&& !dir.as_os_str().is_empty()
+		{
+			std::fs::create_dir_all(dir)?;
+		}
+		let file = OpenOptions::new().create(true).append(true).open(&path)?;
+		let size = file.metadata()?.len();
+		Ok(Self {
+			path,
+			file,
+			size,
+			max_size,
+			max_files,
+		})
+	}
+
+	/// 子プロセスの1行をタイムスタンプ付きで追記する
+	///
+	/// Format: `2025-01-01T12:34:56.789+09:00 | line content`
+	pub fn line(&mut self, stream: Stream, line: &str) -> std::io::Result<()> {
+		let entry = format!(
+			"{} {} {}\n",
+			chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+			stream.marker(),
+			line
+		);
+		if self.size > 0 && self.size + entry.len() as u64 > self.max_size {
+			self.rotate()?;
+		}
+		self.file.write_all(entry.as_bytes())?;
+		self.size += entry.len() as u64;
+		Ok(())
+	}
+
+	fn rotate(&mut self) -> std::io::Result<()> {
+		let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+		if self.max_files == 0 {
+			// 世代を残さない場合は切り詰めるだけ
+			self.file.set_len(0)?;
+		} else {
+			let _ = std::fs::remove_file(rotated(self.max_files));
+			for n in (1..self.max_files).rev() {
+				let _ = std::fs::rename(rotated(n), rotated(n + 1));
+			}
+			std::fs::rename(&self.path, rotated(1))?;
+			self.file = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(&self.path)?;
+		}
+		self.size = 0;
+		Ok(())
+	}
+}
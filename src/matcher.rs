@@ -0,0 +1,470 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+
+use hyper::Method;
+use hyper::header::{COOKIE, HeaderName};
+use hyper::http::request::Parts;
+use regex::Regex;
+
+use crate::config::{Rule, Sticky};
+
+/// weight のバケットを保存する cookie
+pub const BUCKET_COOKIE: &str = "rebab_bucket";
+
+/// ルールのパスの条件
+#[derive(Debug, Clone)]
+enum PathMatcher {
+	/// 条件なし（すべてにマッチ）
+	Any,
+	/// frontend_prefix: パスとクエリの前方一致
+	Prefix(String),
+	/// path_prefix: セグメント単位の前方一致
+	Segment(String),
+	/// path: 完全一致
+	Exact(String),
+	/// path_regex
+	Regex(Regex),
+}
+
+/// 設定のロード時にコンパイルしたルールの条件
+#[derive(Debug, Clone)]
+pub struct Matcher {
+	path: PathMatcher,
+	methods: Vec<Method>,
+	query: Vec<(String, Option<String>)>,
+	headers: Vec<(HeaderName, Option<String>)>,
+	cookies: Vec<(String, Option<String>)>,
+	weight: Option<(u8, Sticky)>,
+}
+
+/// weight で振り分けるためのクライアントのバケット（0〜99）
+#[derive(Debug, Clone, Copy)]
+pub struct Buckets {
+	/// cookie に保存された、または新しく割り当てたバケット
+	pub cookie: u8,
+	/// cookie が無く、新しく割り当てた（Set-Cookie が必要）
+	pub assigned: bool,
+	/// クライアントの IP から決めたバケット
+	pub client_ip: u8,
+}
+
+impl Buckets {
+	pub fn new(parts: &Parts, client_ip: IpAddr) -> Self {
+		let stored = cookies(parts)
+			.find(|(k, _)| *k == BUCKET_COOKIE)
+			.and_then(|(_, v)| v.parse::<u8>().ok())
+			.filter(|v| *v < 100);
+		Self {
+			cookie: stored.unwrap_or_else(|| (RandomState::new().hash_one(0u8) % 100) as u8),
+			assigned: stored.is_none(),
+			client_ip: (fnv1a(client_ip.to_string().as_bytes()) % 100) as u8,
+		}
+	}
+
+	/// 新しく割り当てたバケットを保存する Set-Cookie の値
+	pub fn set_cookie(&self) -> Option<String> {
+		self.assigned.then(|| {
+			format!(
+				"{}={}; Path=/; Max-Age=31536000; SameSite=Lax",
+				BUCKET_COOKIE, self.cookie
+			)
+		})
+	}
+}
+
+/// 再起動しても同じ値になるハッシュ（同じ IP を同じバケットに割り当てるため）
+fn fnv1a(bytes: &[u8]) -> u64 {
+	bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+		(hash ^ *b as u64).wrapping_mul(0x100000001b3)
+	})
+}
+
+/// Cookie ヘッダの `name=value` の組
+fn cookies(parts: &Parts) -> impl Iterator<Item = (&str, &str)> {
+	parts
+		.headers
+		.get_all(COOKIE)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(';'))
+		.filter_map(|v| v.split_once('='))
+		.map(|(k, v)| (k.trim(), v.trim().trim_matches('"')))
+}
+
+/// マッチした結果（テンプレートの変数になる）
+#[derive(Debug, Clone, Default)]
+pub struct Match {
+	/// マッチしたパスの先頭部分
+	pub prefix: String,
+	/// prefix を除いたパス
+	pub rest: String,
+	/// path_regex の名前付きキャプチャ
+	pub captures: Vec<(String, String)>,
+}
+
+impl Matcher {
+	pub fn new(rule: &Rule) -> Result<Self, String> {
+		let paths = [
+			rule.frontend_prefix.is_some(),
+			rule.path.is_some(),
+			rule.path_prefix.is_some(),
+			rule.path_regex.is_some(),
+		];
+		if paths.iter().filter(|v| **v).count() > 1 {
+			return Err(
+				"only one of frontend_prefix, path, path_prefix or path_regex can be set"
+					.to_string(),
+			);
+		}
+		let path = if let Some(v) = &rule.frontend_prefix {
+			PathMatcher::Prefix(v.clone())
+		} else if let Some(v) = &rule.path {
+			PathMatcher::Exact(v.clone())
+		} else if let Some(v) = &rule.path_prefix {
+			// 末尾の / は無くても同じ意味
+			PathMatcher::Segment(v.trim_end_matches('/').to_string())
+		} else if let Some(v) = &rule.path_regex {
+			PathMatcher::Regex(
+				Regex::new(v).map_err(|e| format!("invalid path_regex {}: {}", v, e))?,
+			)
+		} else {
+			PathMatcher::Any
+		};
+		let methods = rule
+			.methods
+			.iter()
+			.map(|v| {
+				Method::from_bytes(v.to_ascii_uppercase().as_bytes())
+					.map_err(|_| format!("invalid method: {}", v))
+			})
+			.collect::<Result<_, _>>()?;
+		let query = rule
+			.query
+			.iter()
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect();
+		let headers = rule
+			.match_headers
+			.iter()
+			.map(|(k, v)| {
+				let name = HeaderName::from_bytes(k.as_bytes())
+					.map_err(|_| format!("invalid header name: {}", k))?;
+				Ok((name, v.clone()))
+			})
+			.collect::<Result<_, String>>()?;
+		let cookies = rule
+			.match_cookies
+			.iter()
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect();
+		let weight = match rule.weight {
+			Some(v) if v > 100 => return Err(format!("weight must be 0 to 100: {}", v)),
+			Some(v) => Some((v, rule.sticky.unwrap_or_default())),
+			None => None,
+		};
+		Ok(Self {
+			path,
+			methods,
+			query,
+			headers,
+			cookies,
+			weight,
+		})
+	}
+
+	/// cookie のバケットでクライアントを振り分けるルールか
+	pub fn uses_bucket_cookie(&self) -> bool {
+		matches!(self.weight, Some((_, Sticky::Cookie)))
+	}
+
+	pub fn matches(&self, parts: &Parts, buckets: &Buckets) -> Option<Match> {
+		if !self.methods.is_empty() && !self.methods.contains(&parts.method) {
+			return None;
+		}
+		for (name, value) in &self.headers {
+			let found = parts.headers.get_all(name).iter().any(|v| {
+				value
+					.as_ref()
+					.is_none_or(|value| v.to_str().is_ok_and(|v| v == value))
+			});
+			if !found {
+				return None;
+			}
+		}
+		for (name, value) in &self.cookies {
+			let found = cookies(parts)
+				.any(|(k, v)| k == name && value.as_ref().is_none_or(|value| value == v));
+			if !found {
+				return None;
+			}
+		}
+		if let Some((weight, sticky)) = self.weight {
+			let bucket = match sticky {
+				Sticky::Cookie => buckets.cookie,
+				Sticky::ClientIp => buckets.client_ip,
+			};
+			if bucket >= weight {
+				return None;
+			}
+		}
+		if !self.query.is_empty() {
+			let pairs: Vec<(String, String)> =
+				serde_urlencoded::from_str(parts.uri.query().unwrap_or("")).unwrap_or_default();
+			let found = self.query.iter().all(|(name, value)| {
+				pairs
+					.iter()
+					.any(|(k, v)| k == name && value.as_ref().is_none_or(|value| value == v))
+			});
+			if !found {
+				return None;
+			}
+		}
+		let path = parts.uri.path();
+		let matched = |prefix: &str| Match {
+			prefix: prefix.to_string(),
+			rest: path[prefix.len()..].to_string(),
+			captures: vec![],
+		};
+		match &self.path {
+			PathMatcher::Any => Some(matched("")),
+			PathMatcher::Prefix(prefix) => {
+				let path_q = parts
+					.uri
+					.path_and_query()
+					.map(|pq| pq.as_str())
+					.unwrap_or("/");
+				if !path_q.starts_with(prefix.as_str()) {
+					return None;
+				}
+				// prefix がクエリまで含む場合はパス全体を rest とする
+				Some(Match {
+					prefix: prefix.clone(),
+					rest: path
+						.strip_prefix(prefix.as_str())
+						.unwrap_or(path)
+						.to_string(),
+					captures: vec![],
+				})
+			}
+			PathMatcher::Segment(prefix) => {
+				let rest = path.strip_prefix(prefix.as_str())?;
+				(rest.is_empty() || rest.starts_with('/')).then(|| matched(prefix))
+			}
+			PathMatcher::Exact(v) => (path == v).then(|| matched(path)),
+			PathMatcher::Regex(regex) => {
+				let captures = regex.captures(path)?;
+				let whole = captures.get(0)?;
+				let rest = match captures.name("rest") {
+					Some(v) => v.as_str().to_string(),
+					None => path[whole.end()..].to_string(),
+				};
+				Some(Match {
+					prefix: path[..whole.end()].to_string(),
+					rest,
+					captures: regex
+						.capture_names()
+						.flatten()
+						.filter_map(|name| {
+							Some((name.to_string(), captures.name(name)?.as_str().to_string()))
+						})
+						.collect(),
+				})
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hyper::Request;
+
+	fn matcher(json: &str) -> Matcher {
+		Matcher::new(&serde_json::from_str(json).unwrap()).unwrap()
+	}
+
+	fn request(method: &str, uri: &str) -> Parts {
+		Request::builder()
+			.method(method)
+			.uri(uri)
+			.body(())
+			.unwrap()
+			.into_parts()
+			.0
+	}
+
+	fn buckets() -> Buckets {
+		Buckets {
+			cookie: 0,
+			assigned: false,
+			client_ip: 0,
+		}
+	}
+
+	fn matched(m: &Matcher, uri: &str) -> Option<(String, String)> {
+		m.matches(&request("GET", uri), &buckets())
+			.map(|v| (v.prefix, v.rest))
+	}
+
+	#[test]
+	fn paths() {
+		let m = matcher(r#"{"path_prefix": "/api/"}"#);
+		assert_eq!(matched(&m, "/api"), Some(("/api".into(), "".into())));
+		assert_eq!(
+			matched(&m, "/api/users?page=2"),
+			Some(("/api".into(), "/users".into()))
+		);
+		assert_eq!(matched(&m, "/apidocs"), None);
+
+		let m = matcher(r#"{"frontend_prefix": "/api"}"#);
+		assert_eq!(
+			matched(&m, "/apidocs"),
+			Some(("/api".into(), "docs".into()))
+		);
+
+		let m = matcher(r#"{"path": "/healthz"}"#);
+		assert_eq!(
+			matched(&m, "/healthz?full=1"),
+			Some(("/healthz".into(), "".into()))
+		);
+		assert_eq!(matched(&m, "/healthz/"), None);
+		assert_eq!(matched(&m, "/"), None);
+
+		let m = matcher("{}");
+		assert_eq!(matched(&m, "/any"), Some(("".into(), "/any".into())));
+	}
+
+	#[test]
+	fn regex() {
+		let m = matcher(r#"{"path_regex": "^/users/(?P<id>[0-9]+)"}"#);
+		let v = m
+			.matches(&request("GET", "/users/42/posts"), &buckets())
+			.unwrap();
+		assert_eq!(v.prefix, "/users/42");
+		assert_eq!(v.rest, "/posts");
+		assert_eq!(v.captures, vec![("id".to_string(), "42".to_string())]);
+		assert!(
+			m.matches(&request("GET", "/users/me"), &buckets())
+				.is_none()
+		);
+
+		let m = matcher(r#"{"path_regex": "^/(?P<lang>en|ja)(?P<rest>/.*)?$"}"#);
+		let v = m.matches(&request("GET", "/ja/about"), &buckets()).unwrap();
+		assert_eq!(v.prefix, "/ja/about");
+		assert_eq!(v.rest, "/about");
+	}
+
+	#[test]
+	fn invalid() {
+		let new = |json: &str| Matcher::new(&serde_json::from_str(json).unwrap());
+		assert!(new(r#"{"path_regex": "^/(unclosed"}"#).is_err());
+		assert!(new(r#"{"path": "/a", "path_prefix": "/b"}"#).is_err());
+		assert!(new(r#"{"methods": ["NOT A METHOD"]}"#).is_err());
+	}
+
+	#[test]
+	fn methods_and_query() {
+		let m = matcher(r#"{"methods": ["get", "HEAD"]}"#);
+		assert!(m.matches(&request("GET", "/"), &buckets()).is_some());
+		assert!(m.matches(&request("HEAD", "/"), &buckets()).is_some());
+		assert!(m.matches(&request("POST", "/"), &buckets()).is_none());
+
+		let m = matcher(r#"{"query": {"debug": null, "v": "2"}}"#);
+		assert!(matched(&m, "/?debug&v=2").is_some());
+		assert!(matched(&m, "/?v=2&debug=1").is_some());
+		assert!(matched(&m, "/?debug=1&v=3").is_none());
+		assert!(matched(&m, "/?v=2").is_none());
+		assert!(matched(&m, "/").is_none());
+	}
+
+	fn with_headers(headers: &[(&str, &str)]) -> Parts {
+		let mut req = Request::builder().uri("/");
+		for (k, v) in headers {
+			req = req.header(*k, *v);
+		}
+		req.body(()).unwrap().into_parts().0
+	}
+
+	#[test]
+	fn headers_and_cookies() {
+		let m = matcher(r#"{"match_headers": {"x-beta": "1", "authorization": null}}"#);
+		let ok = with_headers(&[("x-beta", "1"), ("authorization", "Bearer t")]);
+		assert!(m.matches(&ok, &buckets()).is_some());
+		let wrong = with_headers(&[("x-beta", "2"), ("authorization", "Bearer t")]);
+		assert!(m.matches(&wrong, &buckets()).is_none());
+		let missing = with_headers(&[("x-beta", "1")]);
+		assert!(m.matches(&missing, &buckets()).is_none());
+		// 同じヘッダが複数あればどれかが一致すればよい
+		let repeated = with_headers(&[("x-beta", "0"), ("x-beta", "1"), ("authorization", "")]);
+		assert!(m.matches(&repeated, &buckets()).is_some());
+
+		let m = matcher(r#"{"match_cookies": {"beta": "yes", "session": null}}"#);
+		let ok = with_headers(&[("cookie", "session=abc; beta=\"yes\"")]);
+		assert!(m.matches(&ok, &buckets()).is_some());
+		let split = with_headers(&[("cookie", "session=abc"), ("cookie", "beta=yes")]);
+		assert!(m.matches(&split, &buckets()).is_some());
+		let wrong = with_headers(&[("cookie", "session=abc; beta=no")]);
+		assert!(m.matches(&wrong, &buckets()).is_none());
+		let missing = with_headers(&[("cookie", "beta=yes")]);
+		assert!(m.matches(&missing, &buckets()).is_none());
+	}
+
+	#[test]
+	fn weight() {
+		let parts = request("GET", "/");
+		let bucket = |cookie, client_ip| Buckets {
+			cookie,
+			assigned: false,
+			client_ip,
+		};
+		let m = matcher(r#"{"weight": 10}"#);
+		assert!(m.uses_bucket_cookie());
+		assert!(m.matches(&parts, &bucket(9, 99)).is_some());
+		assert!(m.matches(&parts, &bucket(10, 0)).is_none());
+
+		let m = matcher(r#"{"weight": 10, "sticky": "client_ip"}"#);
+		assert!(!m.uses_bucket_cookie());
+		assert!(m.matches(&parts, &bucket(99, 9)).is_some());
+		assert!(m.matches(&parts, &bucket(0, 10)).is_none());
+
+		assert!(
+			matcher(r#"{"weight": 0}"#)
+				.matches(&parts, &bucket(0, 0))
+				.is_none()
+		);
+		assert!(
+			matcher(r#"{"weight": 100}"#)
+				.matches(&parts, &bucket(99, 99))
+				.is_some()
+		);
+		assert!(Matcher::new(&serde_json::from_str(r#"{"weight": 101}"#).unwrap()).is_err());
+	}
+
+	#[test]
+	fn buckets_from_request() {
+		let ip: IpAddr = "192.0.2.1".parse().unwrap();
+		let stored = Buckets::new(&with_headers(&[("cookie", "rebab_bucket=42")]), ip);
+		assert_eq!(stored.cookie, 42);
+		assert!(!stored.assigned);
+		assert_eq!(stored.set_cookie(), None);
+		// 同じ IP は常に同じバケット
+		assert_eq!(
+			stored.client_ip,
+			Buckets::new(&request("GET", "/"), ip).client_ip
+		);
+
+		for cookie in ["", "rebab_bucket=100", "rebab_bucket=x"] {
+			let v = Buckets::new(&with_headers(&[("cookie", cookie)]), ip);
+			assert!(v.assigned);
+			assert!(v.cookie < 100);
+			assert_eq!(
+				v.set_cookie().unwrap(),
+				format!(
+					"rebab_bucket={}; Path=/; Max-Age=31536000; SameSite=Lax",
+					v.cookie
+				)
+			);
+		}
+	}
+}
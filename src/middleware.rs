@@ -0,0 +1,92 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::{Request, Response};
+
+use crate::body::RebabBody;
+use crate::proxy::Connection;
+
+/// Middleware が返す Future
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// リクエストとレスポンスの間に挟む処理
+///
+/// `next.run(req, conn)` を呼べば後ろの Middleware と転送などの本体に進み、呼ばずにレスポンスを返せば打ち切る。
+/// リクエストごとの状態は async ブロックのローカル変数か、リクエストの extensions に持つ
+/// （extensions は後ろの Middleware と [`crate::Proxy::route`] からも見える）。
+///
+/// ```
+/// use rebab::middleware::{BoxFuture, Middleware, Next};
+/// use rebab::{Connection, body::RebabBody};
+/// use hyper::{Request, Response};
+///
+/// /// X-Api-Key の無いリクエストを断り、かかった時間をヘッダで返す
+/// struct ApiKey;
+///
+/// impl Middleware for ApiKey {
+///     fn call<'a>(
+///         &'a self,
+///         req: Request<RebabBody>,
+///         conn: &'a Connection,
+///         next: Next<'a>,
+///     ) -> BoxFuture<'a, Response<RebabBody>> {
+///         Box::pin(async move {
+///             if !req.headers().contains_key("x-api-key") {
+///                 return rebab::service::response(401, "api key required".to_string());
+///             }
+///             let started = std::time::Instant::now();
+///             let mut resp = next.run(req, conn).await;
+///             let elapsed = started.elapsed().as_millis().to_string();
+///             resp.headers_mut().insert("x-elapsed-ms", elapsed.parse().unwrap());
+///             resp
+///         })
+///     }
+/// }
+/// ```
+pub trait Middleware: Send + Sync + 'static {
+	/// # Arguments
+	/// * `req` - リクエスト（変更してから next に渡せる）
+	/// * `conn` - 接続の情報
+	/// * `next` - 後ろの処理
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>>;
+}
+
+/// Middleware の列の最後で呼ばれる本体（転送など）
+pub(crate) type Endpoint<'a> = Box<
+	dyn FnOnce(Request<RebabBody>, &'a Connection) -> BoxFuture<'a, Response<RebabBody>>
+		+ Send
+		+ 'a,
+>;
+
+/// 残りの Middleware と本体
+pub struct Next<'a> {
+	middleware: &'a [Arc<dyn Middleware>],
+	endpoint: Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+	pub(crate) fn new(middleware: &'a [Arc<dyn Middleware>], endpoint: Endpoint<'a>) -> Self {
+		Self {
+			middleware,
+			endpoint,
+		}
+	}
+
+	/// 次の Middleware（無ければ本体）にリクエストを渡す
+	pub fn run(
+		self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		match self.middleware.split_first() {
+			Some((first, rest)) => first.call(req, conn, Next::new(rest, self.endpoint)),
+			None => (self.endpoint)(req, conn),
+		}
+	}
+}
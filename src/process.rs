@@ -1,100 +1,203 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::io::{BufRead, BufReader};
 use std::process::{Child, Command, Stdio};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+use crate::log::{Label, RotatingFile, Stream};
+
+/// 依存先や再起動したプロセスの起動を待つ上限
+pub const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 停止時に SIGTERM から SIGKILL までの猶予
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// プロセスごとに覚えておく出力の行数
+const OUTPUT_LINES: usize = 100;
+
+/// プロセスの最近の出力（再起動をまたいで保持する）
+type Output = Arc<Mutex<VecDeque<(Stream, String)>>>;
+
+/// 管理下のプロセス（再起動できるよう起動条件も保持する）
+struct Entry {
+	label: Label,
+	command: String,
+	port: Option<u16>,
+	log_file: Option<Arc<Mutex<RotatingFile>>>,
+	output: Output,
+	/// 停止中・再起動中は None
+	child: Option<Child>,
+	/// 最後に起動した時刻
+	started: DateTime<Local>,
+	restarts: u32,
+	/// 再起動してからポートが接続を受け付けるまで true
+	restarting: bool,
+	/// watch 対象のプロセスは終了しても全体を止めず、次の変更で再起動する
+	watched: bool,
+}
+
+/// 管理下のプロセスの状態（ダッシュボード用）
+#[derive(Debug, Clone)]
+pub struct ProcessStatus {
+	pub name: String,
+	pub command: String,
+	pub port: Option<u16>,
+	/// 実行中なら pid（停止中・再起動中は None）
+	pub pid: Option<u32>,
+	pub restarting: bool,
+	pub watched: bool,
+	/// 最後に起動した時刻
+	pub started: DateTime<Local>,
+	pub restarts: u32,
+	/// 最近の出力（古い順）
+	pub output: Vec<(Stream, String)>,
+}
 
 /// プロセス管理構造体
 pub struct ProcessManager {
-	processes: Arc<Mutex<HashMap<String, Child>>>,
+	/// 起動順に保持し、終了時は逆順に停止する
+	processes: Arc<Mutex<Vec<Entry>>>,
+	/// プロセス名ごとのファイル監視
+	watchers: Mutex<Vec<(String, notify::RecommendedWatcher)>>,
+}
+
+impl Default for ProcessManager {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl ProcessManager {
 	pub fn new() -> Self {
 		Self {
-			processes: Arc::new(Mutex::new(HashMap::new())),
+			processes: Arc::new(Mutex::new(Vec::new())),
+			watchers: Mutex::new(Vec::new()),
 		}
 	}
 
 	/// コマンドを実行し、プロセスを管理下に置く
 	///
 	/// # Arguments
-	/// * `rule_id` - ルールの識別子（ログ用）
-	/// * `command` - 実行するコマンド
+	/// * `label` - プロセスのラベル（ログ用）
+	/// * `command` - 実行するコマンド（`{port}` は port に置換される）
 	/// * `port` - PORT環境変数に設定する値（Optionの場合あり）
+	/// * `log_file` - 出力をコンソールに加えて書き込むファイル（Optionの場合あり）
 	///
 	/// # Returns
 	/// 成功時はOk(()), 失敗時はエラーメッセージ
 	pub fn spawn_command(
 		&self,
-		rule_id: String,
+		label: Label,
 		command: &str,
 		port: Option<u16>,
+		log_file: Option<RotatingFile>,
 	) -> Result<(), String> {
-		// Format: rebab: PORT=3000 echo Frontend server started
-		let log_message = if let Some(port_value) = port {
-			format!("PORT={} {}", port_value, command)
-		} else {
-			command.to_string()
+		// Substitute placeholders
+		let command = match port {
+			Some(port_value) => command.replace("{port}", &port_value.to_string()),
+			None => command.to_string(),
 		};
-		crate::log::log(&log_message);
-
-		// Parse command into program and arguments
-		let parts: Vec<&str> = command.split_whitespace().collect();
-		if parts.is_empty() {
-			return Err("Empty command".to_string());
-		}
-
-		let program = parts[0];
-		let args = &parts[1..];
-
-		// Build command
-		let mut cmd = Command::new(program);
-		cmd.args(args);
-		cmd.stdout(Stdio::piped());
-		cmd.stderr(Stdio::piped());
-		cmd.stdin(Stdio::null());
-
-		// Set PORT environment variable
-		if let Some(port_value) = port {
-			cmd.env("PORT", port_value.to_string());
-		}
-
-		// Spawn process
-		match cmd.spawn() {
-			Ok(mut child) => {
-				// Take stdout and stderr for streaming
-				let stdout = child.stdout.take();
-				let stderr = child.stderr.take();
-
-				// Spawn thread to stream stdout
-				if let Some(stdout) = stdout {
-					let rule_id_clone = rule_id.clone();
-					thread::spawn(move || {
-						stream_output(BufReader::new(stdout), rule_id_clone);
-					});
-				}
+		// stdout と stderr で同じファイルに書き込む
+		let log_file = log_file.map(|v| Arc::new(Mutex::new(v)));
+		let output = Output::default();
 
-				// Spawn thread to stream stderr
-				if let Some(stderr) = stderr {
-					let rule_id_clone = rule_id.clone();
-					thread::spawn(move || {
-						stream_output(BufReader::new(stderr), rule_id_clone);
-					});
-				}
+		let child = spawn(&label, &command, port, &log_file, &output)?;
+		let mut processes = self.processes.lock().unwrap();
+		processes.push(Entry {
+			label,
+			command,
+			port,
+			log_file,
+			output,
+			child: Some(child),
+			started: Local::now(),
+			restarts: 0,
+			restarting: false,
+			watched: false,
+		});
+		Ok(())
+	}
 
-				let mut processes = self.processes.lock().unwrap();
-				processes.insert(rule_id, child);
-				Ok(())
-			}
-			Err(e) => {
-				let error_msg = format!("Failed to execute command [{}]: {}", rule_id, e);
-				crate::log::log(&error_msg);
-				Err(error_msg)
+	/// ファイル変更を監視し、変更があればプロセスを再起動する
+	pub fn watch(&self, name: &str, watch: &crate::config::Watch) -> Result<(), String> {
+		{
+			let mut processes = self.processes.lock().unwrap();
+			let entry = find(&mut processes, name)?;
+			entry.watched = true;
+		}
+		let processes = self.processes.clone();
+		let label = name.to_string();
+		let watcher = crate::watch::watch(watch, move |path| {
+			crate::log::log(format!("{}: {} changed", label, path.display()));
+			if let Err(e) = restart(&processes, &label) {
+				crate::log::log(format!("Failed to restart process [{}]: {}", label, e));
 			}
+		})?;
+		self.watchers
+			.lock()
+			.unwrap()
+			.push((name.to_string(), watcher));
+		Ok(())
+	}
+
+	/// プロセスを止めて管理下から外す（設定の再読み込みで消えた・変わったもの）
+	pub fn remove(&self, name: &str) {
+		self.watchers.lock().unwrap().retain(|(v, _)| v != name);
+		let entry = {
+			let mut processes = self.processes.lock().unwrap();
+			let Some(index) = processes.iter().position(|v| v.label.name() == name) else {
+				return;
+			};
+			processes.remove(index)
+		};
+		crate::log::log(format!("Terminating process [{}]...", name));
+		if let Some(mut child) = entry.child {
+			stop(&mut child);
 		}
 	}
 
+	/// 同じコマンドで再起動する（ポートが接続を受け付けるまでブロックする）
+	pub fn restart(&self, name: &str) -> Result<(), String> {
+		restart(&self.processes, name)
+	}
+
+	/// 起動順のプロセスの状態
+	pub fn status(&self) -> Vec<ProcessStatus> {
+		let processes = self.processes.lock().unwrap();
+		processes
+			.iter()
+			.map(|v| ProcessStatus {
+				name: v.label.name().to_string(),
+				command: v.command.clone(),
+				port: v.port,
+				pid: v.child.as_ref().map(Child::id),
+				restarting: v.restarting,
+				watched: v.watched,
+				started: v.started,
+				restarts: v.restarts,
+				output: v.output.lock().unwrap().iter().cloned().collect(),
+			})
+			.collect()
+	}
+
+	/// 再起動中のプロセスがこのポートで接続を受け付けるまで待つ
+	pub async fn wait_restarted(&self, port: u16, timeout: Duration) {
+		let deadline = tokio::time::Instant::now() + timeout;
+		while self.is_restarting(port) && tokio::time::Instant::now() < deadline {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+	}
+
+	fn is_restarting(&self, port: u16) -> bool {
+		let processes = self.processes.lock().unwrap();
+		processes
+			.iter()
+			.any(|v| v.restarting && v.port == Some(port))
+	}
+
 	/// Check all process states and fail if any has exited
 	///
 	/// # Returns
@@ -103,7 +206,15 @@ impl ProcessManager {
 		let mut processes = self.processes.lock().unwrap();
 		let mut exited_rules = Vec::new();
 
-		for (rule_id, child) in processes.iter_mut() {
+		for entry in processes.iter_mut() {
+			let rule_id = entry.label.name().to_string();
+			let Some(child) = entry.child.as_mut() else {
+				// 起動に失敗したまま再起動されていない
+				if !entry.restarting && !entry.watched {
+					exited_rules.push(rule_id);
+				}
+				continue;
+			};
 			match child.try_wait() {
 				Ok(Some(status)) => {
 					// Any process exit (success or failure) triggers shutdown
@@ -121,7 +232,13 @@ impl ProcessManager {
 						)
 					};
 					crate::log::log(&msg);
-					exited_rules.push(rule_id.clone());
+					if entry.watched {
+						// 次のファイル変更で再起動する
+						crate::log::log(format!("Waiting for changes to restart [{}]", rule_id));
+						entry.child = None;
+					} else {
+						exited_rules.push(rule_id);
+					}
 				}
 				Ok(None) => {
 					// Process still running
@@ -129,7 +246,7 @@ impl ProcessManager {
 				Err(e) => {
 					let msg = format!("Failed to check process [{}]: {}", rule_id, e);
 					crate::log::log(&msg);
-					exited_rules.push(rule_id.clone());
+					exited_rules.push(rule_id);
 				}
 			}
 		}
@@ -144,8 +261,39 @@ impl ProcessManager {
 		}
 	}
 
-	/// Terminate all processes
+	/// Wait until a backend accepts TCP connections
+	///
+	/// Fails if the timeout elapses or any managed process exits while waiting
+	pub async fn wait_ready(
+		&self,
+		name: &str,
+		host: &str,
+		port: u16,
+		timeout: Duration,
+	) -> Result<(), String> {
+		let deadline = tokio::time::Instant::now() + timeout;
+		crate::log::log(format!("Waiting for [{}] on {}:{}...", name, host, port));
+		loop {
+			if tokio::net::TcpStream::connect((host, port)).await.is_ok() {
+				crate::log::log(format!("[{}] is ready", name));
+				return Ok(());
+			}
+			self.check_all()?;
+			if tokio::time::Instant::now() >= deadline {
+				return Err(format!(
+					"[{}] did not become ready on {}:{} within {:?}",
+					name, host, port, timeout
+				));
+			}
+			tokio::time::sleep(Duration::from_millis(200)).await;
+		}
+	}
+
+	/// Terminate all processes in reverse startup order
 	pub fn terminate_all(&self) {
+		// 停止中に再起動されないよう、先に監視をやめる
+		self.watchers.lock().unwrap().clear();
+
 		let mut processes = self.processes.lock().unwrap();
 		if processes.is_empty() {
 			return;
@@ -153,23 +301,11 @@ impl ProcessManager {
 
 		crate::log::log("Terminating all processes...");
 
-		for (rule_id, mut child) in processes.drain() {
-			crate::log::log(&format!("Terminating process [{}]...", rule_id));
-
-			#[cfg(windows)]
-			{
-				// Windows では child.kill() だけでは子プロセス（npm等）が生き残るため、
-				// taskkill を使ってプロセスツリー全体を強制終了する
-				let pid = child.id();
-				let _ = std::process::Command::new("taskkill")
-					.args(["/F", "/T", "/PID", &pid.to_string()])
-					.stdout(std::process::Stdio::null())
-					.stderr(std::process::Stdio::null())
-					.status();
+		for entry in processes.drain(..).rev() {
+			crate::log::log(format!("Terminating process [{}]...", entry.label.name()));
+			if let Some(mut child) = entry.child {
+				stop(&mut child);
 			}
-
-			let _ = child.kill();
-			let _ = child.wait();
 		}
 
 		crate::log::log("All processes terminated");
@@ -182,13 +318,243 @@ impl Drop for ProcessManager {
 	}
 }
 
+fn find<'a>(processes: &'a mut [Entry], name: &str) -> Result<&'a mut Entry, String> {
+	processes
+		.iter_mut()
+		.find(|v| v.label.name() == name)
+		.ok_or_else(|| format!("No such process: {}", name))
+}
+
+/// Restart a managed process with the same command, port and log file
+fn restart(processes: &Mutex<Vec<Entry>>, name: &str) -> Result<(), String> {
+	// 停止・起動の間はロックを手放し、check_all などを止めない
+	let (child, label, command, port, log_file, output) = {
+		let mut processes = processes.lock().unwrap();
+		let entry = find(&mut processes, name)?;
+		if entry.restarting {
+			return Err(format!("Process [{}] is already restarting", name));
+		}
+		entry.restarting = true;
+		(
+			entry.child.take(),
+			entry.label.clone(),
+			entry.command.clone(),
+			entry.port,
+			entry.log_file.clone(),
+			entry.output.clone(),
+		)
+	};
+	crate::log::log(format!("Restarting process [{}]...", name));
+	if let Some(mut child) = child {
+		stop(&mut child);
+	}
+	let spawned = spawn(&label, &command, port, &log_file, &output);
+	{
+		let mut processes = processes.lock().unwrap();
+		// 再起動の間に terminate_all か remove で外されたら、起動したものも止める
+		let entry = match find(&mut processes, name) {
+			Ok(v) => v,
+			Err(e) => {
+				drop(processes);
+				if let Ok(mut child) = spawned {
+					stop(&mut child);
+				}
+				return Err(e);
+			}
+		};
+		match spawned {
+			Ok(child) => {
+				entry.child = Some(child);
+				entry.started = Local::now();
+				entry.restarts += 1;
+			}
+			Err(e) => {
+				entry.restarting = false;
+				return Err(e);
+			}
+		}
+	}
+	// ポートが開くまでプロキシはリクエストを保留する
+	if let Some(port) = port
+		&& !wait_port(port, READY_TIMEOUT)
+	{
+		crate::log::log(format!("[{}] did not become ready on port {}", name, port));
+	}
+	let mut processes = processes.lock().unwrap();
+	find(&mut processes, name)?.restarting = false;
+	Ok(())
+}
+
+/// Spawn a command and stream its output
+fn spawn(
+	label: &Label,
+	command: &str,
+	port: Option<u16>,
+	log_file: &Option<Arc<Mutex<RotatingFile>>>,
+	output: &Output,
+) -> Result<Child, String> {
+	// Format: rebab: frontend: PORT=3000 echo Frontend server started
+	let log_message = if let Some(port_value) = port {
+		format!("PORT={} {}", port_value, command)
+	} else {
+		command.to_string()
+	};
+	crate::log::log(format!("{}: {}", label.name(), log_message));
+
+	// Parse command into program and arguments
+	let parts: Vec<&str> = command.split_whitespace().collect();
+	if parts.is_empty() {
+		return Err("Empty command".to_string());
+	}
+
+	let program = parts[0];
+	let args = &parts[1..];
+
+	// Build command
+	let mut cmd = Command::new(program);
+	cmd.args(args);
+	cmd.stdout(Stdio::piped());
+	cmd.stderr(Stdio::piped());
+	cmd.stdin(Stdio::null());
+
+	// 子孫プロセスごと止められるよう、プロセスグループを分ける
+	#[cfg(unix)]
+	std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+	// Set PORT environment variable
+	if let Some(port_value) = port {
+		cmd.env("PORT", port_value.to_string());
+	}
+
+	// Spawn process
+	match cmd.spawn() {
+		Ok(mut child) => {
+			// Take stdout and stderr for streaming
+			let stdout = child.stdout.take();
+			let stderr = child.stderr.take();
+
+			// Spawn thread to stream stdout
+			if let Some(stdout) = stdout {
+				let label = label.clone();
+				let log_file = log_file.clone();
+				let output = output.clone();
+				thread::spawn(move || {
+					stream_output(
+						BufReader::new(stdout),
+						label,
+						Stream::Stdout,
+						log_file,
+						output,
+					);
+				});
+			}
+
+			// Spawn thread to stream stderr
+			if let Some(stderr) = stderr {
+				let label = label.clone();
+				let log_file = log_file.clone();
+				let output = output.clone();
+				thread::spawn(move || {
+					stream_output(
+						BufReader::new(stderr),
+						label,
+						Stream::Stderr,
+						log_file,
+						output,
+					);
+				});
+			}
+
+			Ok(child)
+		}
+		Err(e) => {
+			let error_msg = format!("Failed to execute command [{}]: {}", label.name(), e);
+			crate::log::log(&error_msg);
+			Err(error_msg)
+		}
+	}
+}
+
+/// Stop a child process and its descendants
+fn stop(child: &mut Child) {
+	#[cfg(unix)]
+	{
+		// プロセスグループ全体に SIGTERM を送り、猶予内に終わらなければ SIGKILL
+		// process_group(0) で起動したので pid がそのままプロセスグループ ID になる
+		let pgid = child.id() as libc::pid_t;
+		// SAFETY: kill はメモリに触れない。pgid は spawn したこの子プロセスのもので、まだ wait していない
+		// （ゾンビとして残る）ので、別のプロセスグループに再利用されていない
+		unsafe { libc::kill(-pgid, libc::SIGTERM) };
+		let deadline = Instant::now() + STOP_GRACE;
+		while Instant::now() < deadline && matches!(child.try_wait(), Ok(None)) {
+			thread::sleep(Duration::from_millis(50));
+		}
+		// SAFETY: try_wait で回収していても、グループに子孫が残る間はその ID は再利用されない。
+		// 誰も残っていなければ ESRCH で失敗するだけ
+		unsafe { libc::kill(-pgid, libc::SIGKILL) };
+	}
+
+	#[cfg(windows)]
+	{
+		// Windows では child.kill() だけでは子プロセス（npm等）が生き残るため、
+		// taskkill を使ってプロセスツリー全体を強制終了する
+		let pid = child.id();
+		let _ = std::process::Command::new("taskkill")
+			.args(["/F", "/T", "/PID", &pid.to_string()])
+			.stdout(std::process::Stdio::null())
+			.stderr(std::process::Stdio::null())
+			.status();
+	}
+
+	let _ = child.kill();
+	let _ = child.wait();
+}
+
+/// Block until localhost accepts TCP connections on the port
+fn wait_port(port: u16, timeout: Duration) -> bool {
+	let deadline = Instant::now() + timeout;
+	while Instant::now() < deadline {
+		if std::net::TcpStream::connect(("localhost", port)).is_ok() {
+			return true;
+		}
+		thread::sleep(Duration::from_millis(100));
+	}
+	false
+}
+
+/// Ask the OS for a free ephemeral port
+///
+/// The listener is closed immediately, so the port is only reserved until another process takes it
+pub fn free_port() -> Result<u16, String> {
+	std::net::TcpListener::bind(("127.0.0.1", 0))
+		.and_then(|listener| listener.local_addr())
+		.map(|addr| addr.port())
+		.map_err(|e| format!("Failed to allocate a free port: {}", e))
+}
+
 /// Stream output from a child process
-fn stream_output<R: std::io::Read>(reader: BufReader<R>, rule_id: String) {
+fn stream_output<R: std::io::Read>(
+	reader: BufReader<R>,
+	label: Label,
+	stream: Stream,
+	log_file: Option<Arc<Mutex<RotatingFile>>>,
+	output: Output,
+) {
 	for line in reader.lines() {
 		match line {
 			Ok(line) => {
-				// Format: rebab: rule_id: line content
-				crate::log::log(&format!("{}: {}", rule_id, line));
+				// Format: 12:34:56 label | line content
+				label.line(stream, &line);
+				if let Some(log_file) = &log_file
+					&& let Err(e) = log_file.lock().unwrap().line(stream, &line)
+				{
+					crate::log::log(format!("{}: failed to write log file: {}", label.name(), e));
+				}
+				let mut output = output.lock().unwrap();
+				if output.len() >= OUTPUT_LINES {
+					output.pop_front();
+				}
+				output.push_back((stream, line));
 			}
 			Err(_) => break,
 		}
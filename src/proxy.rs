@@ -1,3 +1,146 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// ルーティングの判断
+pub enum Decision {
+	/// upstream へ転送する
+	Forward(Upstream),
+	/// ディレクトリのファイルを返す
+	Static(crate::files::StaticFiles),
+	/// リダイレクトを返す
+	Redirect {
+		status: u16,
+		location: String,
+		headers: Vec<(String, String)>,
+	},
+	/// 固定のレスポンスを返す
+	Respond {
+		status: u16,
+		headers: Vec<(String, String)>,
+		body: String,
+	},
+	/// エラーとして断る（ルートが無い、許可されていないなど）
+	Reject { status: u16, reason: String },
+}
+
+/// 転送先と転送の設定
+#[derive(Clone)]
+pub struct Upstream {
+	pub uri: hyper::Uri,
+	/// 転送するリクエストに適用するヘッダ操作
+	pub request_headers: Vec<HeaderOp>,
+	/// upstream のレスポンスを待つ時間。超えたら 504（None なら待ち続ける）
+	pub timeout: Option<std::time::Duration>,
+}
+
+impl From<hyper::Uri> for Upstream {
+	fn from(uri: hyper::Uri) -> Self {
+		Self {
+			uri,
+			request_headers: vec![],
+			timeout: None,
+		}
+	}
+}
+
+/// ヘッダの操作（値は展開済み）
+#[derive(Clone)]
+pub enum HeaderOp {
+	Set(HeaderName, HeaderValue),
+	Add(HeaderName, HeaderValue),
+	Remove(HeaderName),
+}
+
+impl HeaderOp {
+	pub fn apply_all(ops: &[HeaderOp], headers: &mut HeaderMap) {
+		for op in ops {
+			match op {
+				HeaderOp::Set(name, value) => {
+					headers.insert(name, value.clone());
+				}
+				HeaderOp::Add(name, value) => {
+					headers.append(name, value.clone());
+				}
+				HeaderOp::Remove(name) => {
+					headers.remove(name);
+				}
+			}
+		}
+	}
+}
+
+/// ルーティングの判断と、返すレスポンスのヘッダ操作
+pub struct Routing {
+	pub decision: Decision,
+	/// 返すレスポンスに適用する
+	pub response_headers: Vec<HeaderOp>,
+	/// このルートだけに挟む処理（前から順に通り、最後に decision を実行する）
+	pub middleware: Vec<std::sync::Arc<dyn crate::middleware::Middleware>>,
+}
+
+impl From<Decision> for Routing {
+	fn from(decision: Decision) -> Self {
+		Self {
+			decision,
+			response_headers: vec![],
+			middleware: vec![],
+		}
+	}
+}
+
+/// リクエストを受けた接続の情報
+#[derive(Debug, Clone, Copy)]
+pub struct Connection {
+	/// 接続元のアドレス（PROXY protocol があればその送信元）
+	pub peer: std::net::SocketAddr,
+	/// 接続を受けた frontend のアドレス
+	pub local: std::net::SocketAddr,
+	/// クライアントの IP（信頼できるプロキシ経由なら X-Forwarded-For から求めたもの）
+	pub client_ip: std::net::IpAddr,
+	/// 接続元が信頼できるプロキシか
+	pub trusted: bool,
+	/// クライアントが接続したスキーム
+	pub scheme: crate::config::Scheme,
+}
+
+impl Connection {
+	pub fn new(
+		peer: std::net::SocketAddr,
+		headers: &HeaderMap,
+		listener: &crate::serve::Listener,
+	) -> Self {
+		let trusted = listener.trusted_proxies.contains(peer.ip());
+		Self {
+			peer,
+			local: listener.addr,
+			client_ip: listener.trusted_proxies.client_ip(peer.ip(), headers),
+			trusted,
+			scheme: crate::forwarded::scheme(headers, trusted, listener.scheme),
+		}
+	}
+}
+
+/// リクエストごとに、どう応えるかを決める
+///
+/// rebab を組み込む側が実装して `serve::serve` に渡す
 pub trait Proxy: Send + Sync + 'static {
-	fn uri2uri(&self, uri: &hyper::Uri) -> Option<hyper::Uri>;
+	/// # Arguments
+	/// * `parts` - リクエスト（メソッド、URI、ヘッダ）
+	/// * `conn` - 接続の情報
+	fn route(&self, parts: &hyper::http::request::Parts, conn: &Connection) -> Routing;
+
+	/// 転送前に upstream の準備ができるまで待つ（管理プロセスの再起動中など）
+	fn wait_upstream(&self, _uri: &hyper::Uri) -> impl Future<Output = ()> + Send {
+		async {}
+	}
+}
+
+/// 共有している Proxy をそのまま使う（差し替えられる Proxy を組み込む側でも保持するため）
+impl<T: Proxy> Proxy for std::sync::Arc<T> {
+	fn route(&self, parts: &hyper::http::request::Parts, conn: &Connection) -> Routing {
+		self.as_ref().route(parts, conn)
+	}
+
+	fn wait_upstream(&self, uri: &hyper::Uri) -> impl Future<Output = ()> + Send {
+		self.as_ref().wait_upstream(uri)
+	}
 }
@@ -0,0 +1,227 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// ヘッダを待つ最大時間
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// v1 ヘッダの最大長（CRLF を含む）
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// 接続の先頭の PROXY protocol ヘッダを読み、送信元のアドレスを返す
+///
+/// HTTP の先頭を読みすぎないよう、ヘッダの分だけを読む。
+/// LOCAL（ヘルスチェックなど）や UNKNOWN の場合は None
+///
+/// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+pub async fn read_header(
+	stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<SocketAddr>, String> {
+	tokio::time::timeout(TIMEOUT, read(stream))
+		.await
+		.map_err(|_| "PROXY protocol header timed out".to_string())?
+}
+
+async fn read(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, String> {
+	let mut head = [0u8; 5];
+	stream
+		.read_exact(&mut head)
+		.await
+		.map_err(|e| e.to_string())?;
+	if &head == b"PROXY" {
+		return read_v1(stream).await;
+	}
+	if head == V2_SIGNATURE[..5] {
+		return read_v2(stream).await;
+	}
+	Err("missing PROXY protocol header".to_string())
+}
+
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`
+async fn read_v1(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, String> {
+	let mut line = b"PROXY".to_vec();
+	while !line.ends_with(b"\r\n") {
+		if line.len() >= V1_MAX_LEN {
+			return Err("PROXY protocol v1 header too long".to_string());
+		}
+		line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+	}
+	let line = std::str::from_utf8(&line[..line.len() - 2])
+		.map_err(|_| "invalid PROXY protocol v1 header".to_string())?;
+	parse_v1(line).ok_or_else(|| format!("invalid PROXY protocol v1 header: {}", line))
+}
+
+fn parse_v1(line: &str) -> Option<Option<SocketAddr>> {
+	let mut fields = line.split(' ');
+	if fields.next()? != "PROXY" {
+		return None;
+	}
+	let family = fields.next()?;
+	if family == "UNKNOWN" {
+		return Some(None);
+	}
+	let src: IpAddr = fields.next()?.parse().ok()?;
+	let _dst: IpAddr = fields.next()?.parse().ok()?;
+	let src_port: u16 = fields.next()?.parse().ok()?;
+	let _dst_port: u16 = fields.next()?.parse().ok()?;
+	match (family, src) {
+		("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+			Some(Some(SocketAddr::new(src, src_port)))
+		}
+		_ => None,
+	}
+}
+
+/// 12 バイトの署名、バージョンとコマンド、アドレスファミリ、長さ、アドレス（と TLV）
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, String> {
+	let mut header = [0u8; 16];
+	header[..5].copy_from_slice(&V2_SIGNATURE[..5]);
+	stream
+		.read_exact(&mut header[5..])
+		.await
+		.map_err(|e| e.to_string())?;
+	if header[..12] != V2_SIGNATURE {
+		return Err("invalid PROXY protocol v2 signature".to_string());
+	}
+	let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+	let mut payload = vec![0u8; len];
+	stream
+		.read_exact(&mut payload)
+		.await
+		.map_err(|e| e.to_string())?;
+	parse_v2(header[12], header[13], &payload)
+		.ok_or_else(|| "invalid PROXY protocol v2 header".to_string())
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> Option<Option<SocketAddr>> {
+	if version_command >> 4 != 2 {
+		return None;
+	}
+	match version_command & 0x0f {
+		// LOCAL: プロキシ自身の接続
+		0 => return Some(None),
+		// PROXY
+		1 => {}
+		_ => return None,
+	}
+	// 上位 4 bit がアドレスファミリ、下位 4 bit がプロトコル（STREAM / DGRAM）
+	match family >> 4 {
+		// AF_INET: src(4) dst(4) src_port(2) dst_port(2)
+		1 => {
+			let v = payload.get(..12)?;
+			let ip = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
+			let port = u16::from_be_bytes([v[8], v[9]]);
+			Some(Some(SocketAddr::new(ip.into(), port)))
+		}
+		// AF_INET6: src(16) dst(16) src_port(2) dst_port(2)
+		2 => {
+			let v = payload.get(..36)?;
+			let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&v[..16]).ok()?);
+			let port = u16::from_be_bytes([v[32], v[33]]);
+			Some(Some(SocketAddr::new(ip.into(), port)))
+		}
+		// AF_UNSPEC, AF_UNIX
+		_ => Some(None),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// ヘッダを読んだ結果と、読まずに残ったバイト列
+	async fn read_from(input: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+		let mut stream = input;
+		let result = read_header(&mut stream).await;
+		(result, stream.to_vec())
+	}
+
+	fn v2(command: u8, family: u8, len: u16, payload: &[u8]) -> Vec<u8> {
+		let mut v = V2_SIGNATURE.to_vec();
+		v.extend([0x20 | command, family]);
+		v.extend(len.to_be_bytes());
+		v.extend(payload);
+		v
+	}
+
+	#[tokio::test]
+	async fn v1() {
+		let (addr, rest) = read_from(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /").await;
+		assert_eq!(addr, Ok(Some("192.0.2.1:56324".parse().unwrap())));
+		assert_eq!(rest, b"GET /");
+		let (addr, _) = read_from(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+		assert_eq!(addr, Ok(Some("[2001:db8::1]:4000".parse().unwrap())));
+		let (addr, rest) = read_from(b"PROXY UNKNOWN ffff 0 0\r\nGET /").await;
+		assert_eq!(addr, Ok(None));
+		assert_eq!(rest, b"GET /");
+	}
+
+	#[tokio::test]
+	async fn v1_invalid() {
+		// 途中で切れている
+		assert!(read_from(b"PROXY TCP4 192.0.2.1").await.0.is_err());
+		assert!(read_from(b"PROX").await.0.is_err());
+		// ファミリとアドレスが合わない、ポートが範囲外
+		assert!(
+			read_from(b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2\r\n")
+				.await
+				.0
+				.is_err()
+		);
+		assert!(
+			read_from(b"PROXY TCP4 192.0.2.1 192.0.2.2 70000 443\r\n")
+				.await
+				.0
+				.is_err()
+		);
+		// CRLF が来ないまま最大長を超える
+		let long = format!("PROXY TCP4 {}\r\n", "1".repeat(200));
+		let (addr, rest) = read_from(long.as_bytes()).await;
+		assert_eq!(addr, Err("PROXY protocol v1 header too long".to_string()));
+		assert!(!rest.is_empty());
+		assert!(read_from(b"GET / HTTP/1.1\r\n").await.0.is_err());
+	}
+
+	#[tokio::test]
+	async fn v2_addresses() {
+		let mut payload = vec![192, 0, 2, 1, 192, 0, 2, 2];
+		payload.extend(56324u16.to_be_bytes());
+		payload.extend(443u16.to_be_bytes());
+		let mut input = v2(1, 0x11, 12, &payload);
+		input.extend(b"GET /");
+		let (addr, rest) = read_from(&input).await;
+		assert_eq!(addr, Ok(Some("192.0.2.1:56324".parse().unwrap())));
+		assert_eq!(rest, b"GET /");
+
+		let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+		let mut payload = src.octets().to_vec();
+		payload.extend([0; 16]);
+		payload.extend(4000u16.to_be_bytes());
+		payload.extend(443u16.to_be_bytes());
+		let (addr, _) = read_from(&v2(1, 0x21, 36, &payload)).await;
+		assert_eq!(addr, Ok(Some("[2001:db8::1]:4000".parse().unwrap())));
+	}
+
+	#[tokio::test]
+	async fn v2_local_and_invalid() {
+		// LOCAL はアドレスを持たない（ペイロードは読み飛ばす）
+		let mut input = v2(0, 0x11, 12, &[0; 12]);
+		input.extend(b"GET /");
+		let (addr, rest) = read_from(&input).await;
+		assert_eq!(addr, Ok(None));
+		assert_eq!(rest, b"GET /");
+		// 長さが AF_INET6 のアドレスに足りない
+		assert!(read_from(&v2(1, 0x21, 12, &[0; 12])).await.0.is_err());
+		// 長さの分だけ届かない
+		assert!(read_from(&v2(1, 0x11, 100, &[0; 12])).await.0.is_err());
+		// 未知のコマンドとバージョン
+		assert!(read_from(&v2(2, 0x11, 12, &[0; 12])).await.0.is_err());
+		let mut bad_version = v2(1, 0x11, 12, &[0; 12]);
+		bad_version[12] = 0x11;
+		assert!(read_from(&bad_version).await.0.is_err());
+		let mut bad_signature = v2(1, 0x11, 12, &[0; 12]);
+		bad_signature[8] = b'X';
+		assert!(read_from(&bad_signature).await.0.is_err());
+	}
+}
@@ -4,31 +4,169 @@ use tokio::net::TcpListener;
 use hyper::server;
 use hyper_util::rt::TokioIo;
 
+use crate::config::Scheme;
+use crate::forwarded::TrustedProxies;
+
+/// max_header_size の最小値（hyper の HTTP/1 の読み込みバッファの最小値）
+pub const MIN_HEADER_SIZE: usize = 8192;
+
+/// 待ち受けの設定
+///
+/// フィールドは今後も増えるので、[`Listener::from`] でアドレスから作って必要なものを書き換える
+#[non_exhaustive]
+pub struct Listener {
+	pub addr: SocketAddr,
+	/// X-Forwarded-* を信頼する接続元
+	pub trusted_proxies: TrustedProxies,
+	/// 接続の先頭に PROXY protocol のヘッダを要求する
+	pub proxy_protocol: bool,
+	/// クライアントが接続するスキーム（None なら検出する）
+	pub scheme: Option<Scheme>,
+	/// リクエスト行とヘッダに使えるバイト数（None なら hyper の既定値、[`MIN_HEADER_SIZE`] より小さければ切り上げる）
+	pub max_header_size: Option<usize>,
+	/// ヘッダの最大数（None なら hyper の既定値の 100）
+	pub max_headers: Option<usize>,
+	/// すべてのリクエストに、ルーティングの前に挟む処理
+	pub middleware: Vec<Arc<dyn crate::middleware::Middleware>>,
+}
+
+impl From<SocketAddr> for Listener {
+	fn from(addr: SocketAddr) -> Self {
+		Self {
+			addr,
+			trusted_proxies: TrustedProxies::default(),
+			proxy_protocol: false,
+			scheme: None,
+			max_header_size: None,
+			max_headers: None,
+			middleware: vec![],
+		}
+	}
+}
+
+/// 待ち受けを終えるまで返らない
+///
+/// 止める必要があれば [`Server::bind`] を使う
 pub async fn serve(
-	addr: SocketAddr,
+	listener: Listener,
 	proxy: impl crate::proxy::Proxy,
 ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
-	crate::log::log(format!("start listen {}", addr));
-	crate::log::log(format!("open {}", crate::log::addr_to_url(addr)));
-	let listener = match TcpListener::bind(addr).await {
-		Ok(v) => Ok(v),
-		Err(e) => {
-			crate::log::log(format!("port already used {}", addr));
-			Err(e)
+	let mut server = Server::bind(listener, proxy).await?;
+	server.wait().await?;
+	Ok(())
+}
+
+/// 待ち受け中のサーバ
+///
+/// drop すると新しい接続の受け付けをやめる
+pub struct Server {
+	local_addr: SocketAddr,
+	shutdown: tokio::sync::watch::Sender<bool>,
+	task: Option<tokio::task::JoinHandle<std::io::Result<()>>>,
+}
+
+impl Server {
+	/// ポートを確保し、バックグラウンドで接続を受け付ける
+	pub async fn bind(
+		listener: Listener,
+		proxy: impl crate::proxy::Proxy,
+	) -> std::io::Result<Self> {
+		let addr = listener.addr;
+		crate::log::log(format!("start listen {}", addr));
+		crate::log::log(format!("open {}", crate::log::addr_to_url(addr)));
+		let tcp = match TcpListener::bind(addr).await {
+			Ok(v) => Ok(v),
+			Err(e) => {
+				crate::log::log(format!("port already used {}", addr));
+				Err(e)
+			}
+		}?;
+		// ポート 0 なら割り当てられたポートにする
+		let local_addr = tcp.local_addr()?;
+		let listener = Listener {
+			addr: local_addr,
+			..listener
+		};
+		let (shutdown, stopped) = tokio::sync::watch::channel(false);
+		let task = tokio::spawn(accept(tcp, listener, proxy, stopped));
+		Ok(Self {
+			local_addr,
+			shutdown,
+			task: Some(task),
+		})
+	}
+
+	/// 実際に待ち受けているアドレス
+	pub fn local_addr(&self) -> SocketAddr {
+		self.local_addr
+	}
+
+	/// 待ち受けが終わるまで待つ（accept の失敗か、stop）
+	pub async fn wait(&mut self) -> std::io::Result<()> {
+		match self.task.as_mut() {
+			Some(task) => {
+				let result = task.await.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+				self.task = None;
+				result
+			}
+			None => Ok(()),
 		}
-	}?;
+	}
+
+	/// 新しい接続の受け付けをやめる（処理中のリクエストはそのまま続く）
+	pub async fn stop(mut self) {
+		let _ = self.shutdown.send(true);
+		let _ = self.wait().await;
+	}
+}
+
+async fn accept(
+	tcp: TcpListener,
+	listener: Listener,
+	proxy: impl crate::proxy::Proxy,
+	mut stopped: tokio::sync::watch::Receiver<bool>,
+) -> std::io::Result<()> {
 	// https://github.com/hyperium/hyper/discussions/3471
 	let proxy = Arc::new(proxy);
+	let listener = Arc::new(listener);
 	loop {
-		let (stream, _) = listener.accept().await?;
-		let io = TokioIo::new(stream);
+		let (mut stream, mut peer) = tokio::select! {
+			accepted = tcp.accept() => accepted?,
+			_ = stopped.changed() => return Ok(()),
+		};
 		let proxy = proxy.clone();
+		let listener = listener.clone();
 		tokio::task::spawn(async move {
-			let svc = crate::service::ProxyHandler { proxy };
-			if let Err(err) = server::conn::http1::Builder::new()
-				.serve_connection(io, svc)
-				.await
-			{
+			if listener.proxy_protocol {
+				// trusted_proxies があれば、それ以外からのヘッダは偽装とみなす
+				let trusted = &listener.trusted_proxies;
+				if !trusted.is_empty() && !trusted.contains(peer.ip()) {
+					crate::log::log(format!("{}: PROXY protocol from untrusted address", peer));
+					return;
+				}
+				match crate::proxy_protocol::read_header(&mut stream).await {
+					Ok(Some(v)) => peer = v,
+					Ok(None) => {}
+					Err(e) => {
+						crate::log::log(format!("{}: {}", peer, e));
+						return;
+					}
+				}
+			}
+			let io = TokioIo::new(stream);
+			let mut builder = server::conn::http1::Builder::new();
+			if let Some(v) = listener.max_header_size {
+				builder.max_buf_size(v.max(MIN_HEADER_SIZE));
+			}
+			if let Some(v) = listener.max_headers {
+				builder.max_headers(v);
+			}
+			let svc = crate::service::ProxyHandler {
+				proxy,
+				peer,
+				listener,
+			};
+			if let Err(err) = builder.serve_connection(io, svc).await {
 				eprintln!("server error: {}", err);
 			}
 		});
@@ -1,19 +1,23 @@
-use crate::proxy::Proxy;
-use hyper::http::uri::Authority;
-use hyper::{Request, Response, body::Incoming, header::FORWARDED};
+use crate::body::RebabBody;
+use crate::middleware::{Endpoint, Next};
+use crate::proxy::{Connection, Decision, HeaderOp, Proxy, Upstream};
+use hyper::{Request, Response, StatusCode, body::Incoming, header::LOCATION};
 use hyper_util::{
 	client::legacy::{Client, connect::HttpConnector},
 	rt::TokioExecutor,
 };
-use std::{convert::Infallible, future::Future, pin::Pin, sync::Arc};
+use std::{convert::Infallible, future::Future, net::SocketAddr, pin::Pin, sync::Arc};
 
 use hyper::http::header::{
-	CONNECTION, HOST, HeaderName, HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE,
-	TRAILER, TRANSFER_ENCODING, UPGRADE,
+	CONNECTION, HOST, HeaderMap, HeaderName, HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION,
+	TE, TRAILER, TRANSFER_ENCODING, UPGRADE, VIA,
 };
 
-const HOP_HEADERS: [HeaderName; 7] = [
+/// 常に hop-by-hop として扱うヘッダ（RFC 9110 7.6.1 と、慣習的なもの）
+const HOP_HEADERS: [HeaderName; 9] = [
 	CONNECTION,
+	HeaderName::from_static("keep-alive"),
+	HeaderName::from_static("proxy-connection"),
 	TE,
 	TRAILER,
 	TRANSFER_ENCODING,
@@ -22,45 +26,128 @@ const HOP_HEADERS: [HeaderName; 7] = [
 	PROXY_AUTHORIZATION,
 ];
 
+/// Via に載せる名前
+const VIA_PSEUDONYM: &str = "rebab";
+
 // 状態を持つハンドラ構造体
 pub struct ProxyHandler<T: Proxy> {
 	pub proxy: Arc<T>,
+	/// 接続元のアドレス
+	pub peer: SocketAddr,
+	pub listener: Arc<crate::serve::Listener>,
 }
 // Service トレイトを実装
 impl<T: Proxy> hyper::service::Service<Request<Incoming>> for ProxyHandler<T> {
-	type Response = Response<crate::body::RebabBody>;
+	type Response = Response<RebabBody>;
 	type Error = Infallible;
 	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
 	fn call(&self, req: Request<Incoming>) -> Self::Future {
 		let args = self.proxy.clone();
+		let peer = self.peer;
+		let listener = self.listener.clone();
 		Box::pin(async move {
-			let resp = proxy(args.as_ref(), req).await;
+			let req = req.map(RebabBody::from);
+			let conn = Connection::new(peer, req.headers(), &listener);
+			// listener の Middleware を通してからルーティングする
+			let endpoint: Endpoint =
+				Box::new(|req, conn| Box::pin(proxy(args.as_ref(), req, conn)));
+			let resp = Next::new(&listener.middleware, endpoint)
+				.run(req, &conn)
+				.await;
 			Ok(resp)
 		})
 	}
 }
 
-pub async fn proxy(proxy: &impl Proxy, req: Request<Incoming>) -> Response<crate::body::RebabBody> {
+/// ルーティングし、ルートの Middleware を通して応える
+pub async fn proxy(
+	proxy: &impl Proxy,
+	req: Request<RebabBody>,
+	conn: &Connection,
+) -> Response<RebabBody> {
 	//https://hyper.rs/guides/1/server/middleware/
-	//Ok(Response::new(req.uri().to_string()))
-	// クライアント（接続再利用したいなら外に出して Arc 共有してOK）
-	let mut connector = HttpConnector::new();
-	connector.enforce_http(true);
-	let client: Client<_, Incoming> = Client::builder(TokioExecutor::new()).build(connector);
 
+	let (mut parts, body) = req.into_parts();
+	let routing = proxy.route(&parts, conn);
+	let decision = routing.decision;
+	// キャッシュがレスポンスを返した後で再検証できるように、転送先を渡しておく
+	if let Decision::Forward(upstream) = &decision {
+		parts
+			.extensions
+			.insert(crate::cache::Origin(upstream.clone()));
+	}
+	let endpoint: Endpoint =
+		Box::new(move |req, conn| Box::pin(dispatch(proxy, decision, req, conn)));
+	let mut resp = Next::new(&routing.middleware, endpoint)
+		.run(Request::from_parts(parts, body), conn)
+		.await;
+	HeaderOp::apply_all(&routing.response_headers, resp.headers_mut());
+	resp
+}
+
+/// ルーティングの判断どおりに応える
+async fn dispatch(
+	proxy: &impl Proxy,
+	decision: Decision,
+	req: Request<RebabBody>,
+	conn: &Connection,
+) -> Response<RebabBody> {
 	// 元リクエストをパーツに分解
 	let (parts, body) = req.into_parts();
+	match decision {
+		Decision::Forward(upstream) => {
+			proxy.wait_upstream(&upstream.uri).await;
+			forward(upstream, parts, body, conn).await
+		}
+		Decision::Static(files) => crate::files::serve(&files, &parts).await,
+		Decision::Redirect {
+			status,
+			location,
+			headers,
+		} => {
+			let mut resp = respond(status, &headers, String::new());
+			match HeaderValue::from_str(&location) {
+				Ok(v) => {
+					resp.headers_mut().insert(LOCATION, v);
+				}
+				Err(_) => {
+					return internal_error(500, "rebab invalid redirect location", location);
+				}
+			}
+			resp
+		}
+		Decision::Respond {
+			status,
+			headers,
+			body,
+		} => respond(status, &headers, body),
+		Decision::Reject { status, reason } => response(status, reason),
+	}
+}
 
-	let new_uri = match proxy.uri2uri(&parts.uri) {
-		Some(v) => v,
-		None => return response(404, format!("rebab no route for {}", parts.uri.to_string())),
+/// upstream へ転送する
+pub(crate) async fn forward(
+	upstream: Upstream,
+	parts: hyper::http::request::Parts,
+	body: RebabBody,
+	conn: &Connection,
+) -> Response<RebabBody> {
+	let new_uri = upstream.uri;
+	// バックエンドのホストが無い URI には転送できない
+	let Some(host) = new_uri.host().map(str::to_string) else {
+		return internal_error(502, "Rebab Bad Gateway", format!("no host in {}", new_uri));
 	};
+	// クライアント（接続再利用したいなら外に出して Arc 共有してOK）
+	let mut connector = HttpConnector::new();
+	connector.enforce_http(true);
+	let client: Client<_, RebabBody> = Client::builder(TokioExecutor::new()).build(connector);
+
 	// 新しいリクエストを作成（メソッド/URIはコピー）
 	let mut out_req = Request::builder()
 		.method(&parts.method)
 		.uri(&new_uri)
-		.header(HOST, new_uri.host().unwrap().to_string())
+		.header(HOST, host)
 		.body(body)
 		.expect("building forwarded request");
 
@@ -70,96 +157,145 @@ pub async fn proxy(proxy: &impl Proxy, req: Request<Incoming>) -> Response<crate
 		let dst = out_req.headers_mut();
 
 		for (name, value) in src.iter() {
-			if HOP_HEADERS.iter().all(|v| v != name) && name != &HOST {
+			if name != HOST {
 				dst.append(name, value.clone());
 			}
 		}
-		// ==== ここから追記：元のホスト情報を転送 ====
-		if let Some(orig) = original_authority(&parts)
-			&& let Some(orig_proto) = proto(&parts)
-		{
-			// 例: localhost:8080
-			let _orig_host = orig.host();
-			let orig_port = orig.port_u16();
-			dst.insert(
-				HeaderName::from_static("x-forwarded-host"),
-				HeaderValue::from_str(orig.as_str()).unwrap(),
-			); // 例: localhost:8080
-			dst.insert(
-				HeaderName::from_static("x-forwarded-proto"),
-				HeaderValue::from_str(&orig_proto).unwrap(),
-			);
-			if let Some(p) = orig_port {
-				dst.insert(
-					HeaderName::from_static("x-forwarded-port"),
-					HeaderValue::from_str(&p.to_string()).unwrap(),
-				);
-			}
-			// Forwarded: proto=http;host="localhost:8080"
-			dst.append(
-				FORWARDED,
-				HeaderValue::from_str(&format!(r#"proto={};host="{}""#, orig_proto, orig.as_str()))
-					.unwrap(),
-			);
-		}
+		remove_hop_headers(dst);
+		append_via(dst, parts.version);
+		// 元のホスト情報とクライアントの IP を転送
+		crate::forwarded::apply(&parts, dst, conn);
+		// ルールのヘッダ操作
+		HeaderOp::apply_all(&upstream.request_headers, dst);
 	}
 	// 転送してレスポンスを受け取る
-	let resp = match client.request(out_req).await {
+	let request = client.request(out_req);
+	let result = match upstream.timeout {
+		Some(timeout) => match tokio::time::timeout(timeout, request).await {
+			Ok(v) => v,
+			Err(_) => {
+				return internal_error(504, "Rebab Gateway Timeout", new_uri.to_string());
+			}
+		},
+		None => request.await,
+	};
+	let resp = match result {
 		Ok(resp) => resp,
 		Err(e) => {
-			return response(502, format!("Rebab Bad Gateway: {e:?}"));
+			return internal_error(502, "Rebab Bad Gateway", format!("{e:?}"));
 		}
 	};
 
 	// レスポンスから hop-by-hop ヘッダ除去
 	let (mut parts, body) = resp.into_parts();
-	// RFC的には Connection ヘッダに列挙されたフィールドも落とすべきだが、
-	// まずは代表的 hop-by-hop を除去
-	for name in HOP_HEADERS {
-		parts.headers.remove(name);
+	remove_hop_headers(&mut parts.headers);
+	append_via(&mut parts.headers, parts.version);
+	Response::from_parts(parts, RebabBody::Incoming(body))
+}
+
+/// hop-by-hop ヘッダと、Connection に列挙されたヘッダを取り除く
+fn remove_hop_headers(headers: &mut HeaderMap) {
+	let listed: Vec<HeaderName> = headers
+		.get_all(CONNECTION)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.filter_map(|v| HeaderName::from_bytes(v.trim().as_bytes()).ok())
+		.collect();
+	for name in HOP_HEADERS.into_iter().chain(listed) {
+		headers.remove(name);
 	}
-	Response::from_parts(parts, crate::body::RebabBody::Incoming(body))
 }
 
-fn response(status: u16, body: String) -> Response<crate::body::RebabBody> {
-	Response::builder()
-		.status(status)
-		.body(crate::body::RebabBody::from(body))
-		.unwrap()
+/// `Via: 1.1 rebab` を追記する
+fn append_via(headers: &mut HeaderMap, version: hyper::Version) {
+	let protocol = match version {
+		hyper::Version::HTTP_09 => "0.9",
+		hyper::Version::HTTP_10 => "1.0",
+		hyper::Version::HTTP_2 => "2",
+		hyper::Version::HTTP_3 => "3",
+		_ => "1.1",
+	};
+	let value = format!("{} {}", protocol, VIA_PSEUDONYM);
+	headers.append(VIA, HeaderValue::from_str(&value).unwrap());
 }
 
-fn original_authority(parts: &hyper::http::request::Parts) -> Option<Authority> {
-	// 1) 絶対URIなら URI の authority を優先
-	if let Some(a) = parts.uri.authority().cloned() {
-		return Some(a);
+/// rebab 自身が返したエラー（4xx と 5xx の [`response`] の extensions に入る）
+///
+/// [`crate::error_page::ErrorPages`] がこれを見てエラーページに差し替える。upstream のエラーとルールの固定のレスポンスには入らない
+#[derive(Debug, Clone)]
+pub struct ProxyError {
+	/// クライアントに返す説明
+	pub message: String,
+	/// 内部の詳細（upstream に接続できなかった理由など）
+	pub detail: Option<String>,
+}
+
+/// ステータスと本文だけのレスポンス（エラーなら [`ProxyError`] を付ける）
+pub fn response(status: u16, body: String) -> Response<RebabBody> {
+	let mut resp = plain(status, body.clone());
+	// ステータスが不正なら plain が付けた 500 のエラーのまま
+	if status >= 400 && resp.extensions().get::<ProxyError>().is_none() {
+		resp.extensions_mut().insert(ProxyError {
+			message: body,
+			detail: None,
+		});
 	}
-	// 2) 通常は Host ヘッダ
-	parts
-		.headers
-		.get(HOST)
-		.and_then(|v| v.to_str().ok())
-		.and_then(|s| s.parse::<Authority>().ok())
+	resp
+}
+
+/// 内部の詳細を含むエラー（本文は `message: detail`）
+pub fn internal_error(status: u16, message: &str, detail: String) -> Response<RebabBody> {
+	let mut resp = plain(status, format!("{}: {}", message, detail));
+	resp.extensions_mut().insert(ProxyError {
+		message: message.to_string(),
+		detail: Some(detail),
+	});
+	resp
 }
 
-pub fn proto(parts: &hyper::http::request::Parts) -> Option<String> {
-	let host = parts
-		.headers
-		.get(HOST)
-		.and_then(|h| h.to_str().ok())
-		.map(str::trim)
-		.filter(|s| !s.is_empty())?
-		.to_string();
-	let hostname = host
-		.trim_start_matches('[')
-		.split(']')
-		.next()
-		.unwrap_or(&host)
-		.split(':')
-		.next()
-		.unwrap_or(&host);
-	let scheme = match hostname {
-		"localhost" | "127.0.0.1" | "::1" => "http",
-		_ => "https",
+/// ステータスが 100〜999 でなければ（Proxy の実装の誤り）500 にする
+fn plain(status: u16, body: String) -> Response<RebabBody> {
+	let Ok(status) = StatusCode::from_u16(status) else {
+		return internal_error(500, "rebab invalid status", status.to_string());
 	};
-	Some(scheme.to_string())
+	let mut resp = Response::new(RebabBody::from(body));
+	*resp.status_mut() = status;
+	resp
+}
+
+/// ルールで指定された固定のレスポンス（ヘッダは検証済み）
+fn respond(status: u16, headers: &[(String, String)], body: String) -> Response<RebabBody> {
+	let mut resp = plain(status, body);
+	for (name, value) in headers {
+		if let (Ok(name), Ok(value)) = (
+			HeaderName::from_bytes(name.as_bytes()),
+			HeaderValue::from_str(value),
+		) {
+			resp.headers_mut().append(name, value);
+		}
+	}
+	resp
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn invalid_status() {
+		let resp = response(404, "rebab no route".to_string());
+		assert_eq!(resp.status(), 404);
+		assert_eq!(
+			resp.extensions().get::<ProxyError>().unwrap().message,
+			"rebab no route"
+		);
+		for status in [0, 99, 1000] {
+			let resp = response(status, "rebab rejected".to_string());
+			assert_eq!(resp.status(), 500);
+			let error = resp.extensions().get::<ProxyError>().unwrap();
+			assert_eq!(error.detail.as_deref(), Some(status.to_string().as_str()));
+			assert_eq!(respond(status, &[], String::new()).status(), 500);
+		}
+	}
 }
@@ -0,0 +1,112 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+
+use hyper::http::request::Parts;
+use hyper::http::uri::Authority;
+
+use crate::matcher::Match;
+
+/// ルールのテンプレート（リダイレクト先、ヘッダの値）で使える変数
+///
+/// `{host}` `{path}` `{query}` `{uri}` `{rest}` `{prefix}` `{client_ip}` `{request_id}` と、
+/// path_regex の名前付きキャプチャ
+pub struct Vars<'a> {
+	parts: &'a Parts,
+	host: String,
+	query: String,
+	matched: &'a Match,
+	client_ip: String,
+	request_id: String,
+}
+
+impl<'a> Vars<'a> {
+	/// # Arguments
+	/// * `matched` - マッチしたルールの prefix、rest、キャプチャ
+	/// * `client_ip` - クライアントの IP
+	pub fn new(parts: &'a Parts, matched: &'a Match, client_ip: IpAddr) -> Self {
+		let host = match parts.uri.host() {
+			Some(v) => v.to_string(),
+			None => parts
+				.headers
+				.get(hyper::header::HOST)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.parse::<Authority>().ok())
+				.map(|v| v.host().to_string())
+				.unwrap_or_else(|| "localhost".to_string()),
+		};
+		let query = parts
+			.uri
+			.query()
+			.map(|v| format!("?{}", v))
+			.unwrap_or_default();
+		// 受け取った X-Request-Id があれば引き継ぐ
+		let request_id = parts
+			.headers
+			.get("x-request-id")
+			.and_then(|v| v.to_str().ok())
+			.map(str::to_string)
+			.unwrap_or_else(request_id);
+		Self {
+			parts,
+			host,
+			query,
+			matched,
+			client_ip: client_ip.to_string(),
+			request_id,
+		}
+	}
+
+	/// `{name}` を変数の値に置き換える（未知の名前はそのまま残す）
+	pub fn expand(&self, template: &str) -> String {
+		let mut out = String::with_capacity(template.len());
+		let mut rest = template;
+		while let Some(start) = rest.find('{') {
+			out.push_str(&rest[..start]);
+			let after = &rest[start + 1..];
+			match after
+				.find('}')
+				.and_then(|end| Some((self.get(&after[..end])?, end)))
+			{
+				Some((value, end)) => {
+					out.push_str(&value);
+					rest = &after[end + 1..];
+				}
+				None => {
+					out.push('{');
+					rest = after;
+				}
+			}
+		}
+		out.push_str(rest);
+		out
+	}
+
+	fn get(&self, name: &str) -> Option<String> {
+		let path = self.parts.uri.path();
+		Some(match name {
+			"host" => self.host.clone(),
+			"path" => path.to_string(),
+			"query" => self.query.clone(),
+			"uri" => format!("{}{}", path, self.query),
+			"rest" => self.matched.rest.clone(),
+			"prefix" => self.matched.prefix.clone(),
+			"client_ip" => self.client_ip.clone(),
+			"request_id" => self.request_id.clone(),
+			_ => self
+				.matched
+				.captures
+				.iter()
+				.find(|(k, _)| k == name)
+				.map(|(_, v)| v.clone())?,
+		})
+	}
+}
+
+/// 128 bit のランダムなリクエスト ID（16進数32文字）
+fn request_id() -> String {
+	// RandomState は生成ごとに異なる鍵を持つので、乱数源として使う
+	let a = RandomState::new().hash_one(0u8);
+	let b = RandomState::new().hash_one(1u8);
+	format!("{:016x}{:016x}", a, b)
+}
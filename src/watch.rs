@@ -0,0 +1,177 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::thread;
+use std::time::Duration;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::Watch;
+
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// ファイル変更を監視し、変更が落ち着いてから `on_change` を呼ぶ
+///
+/// 返り値の watcher を drop すると監視を終了する
+pub fn watch(
+	watch: &Watch,
+	on_change: impl Fn(&Path) + Send + 'static,
+) -> Result<RecommendedWatcher, String> {
+	let include = glob_set(watch.paths.iter().map(|v| directory_glob(v)))?;
+	let ignore = glob_set(watch.ignore.iter().map(|v| normalize(Path::new(v))))?;
+	let debounce = watch
+		.debounce_ms
+		.map(Duration::from_millis)
+		.unwrap_or(DEFAULT_DEBOUNCE);
+	let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+
+	let (tx, rx) = channel::<PathBuf>();
+	let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+		let Ok(event) = res else {
+			return;
+		};
+		if event.kind.is_access() {
+			return;
+		}
+		for path in event.paths {
+			// イベントのパスは絶対パスのこともあるので、作業ディレクトリからの相対パスで照合する
+			let relative = normalize(path.strip_prefix(&cwd).unwrap_or(&path));
+			if include.is_match(&relative) && !ignore.is_match(&relative) {
+				let _ = tx.send(path);
+			}
+		}
+	})
+	.map_err(|e| e.to_string())?;
+
+	for pattern in &watch.paths {
+		let (base, mode) = base_dir(pattern);
+		watcher
+			.watch(&base, mode)
+			.map_err(|e| format!("Failed to watch {}: {}", base.display(), e))?;
+	}
+
+	// デバウンス: 最初の変更から、debounce の間なにも起きなくなるまで待つ
+	thread::spawn(move || {
+		while let Ok(path) = rx.recv() {
+			loop {
+				match rx.recv_timeout(debounce) {
+					Ok(_) => continue,
+					Err(RecvTimeoutError::Timeout) => break,
+					Err(RecvTimeoutError::Disconnected) => return,
+				}
+			}
+			on_change(&path);
+		}
+	});
+	Ok(watcher)
+}
+
+fn glob_set(patterns: impl Iterator<Item = String>) -> Result<GlobSet, String> {
+	let mut builder = GlobSetBuilder::new();
+	for pattern in patterns {
+		let glob = GlobBuilder::new(&pattern)
+			.literal_separator(true)
+			.build()
+			.map_err(|e| format!("invalid watch pattern {}: {}", pattern, e))?;
+		builder.add(glob);
+	}
+	builder.build().map_err(|e| e.to_string())
+}
+
+/// `./src` → `src`（照合用に先頭の `./` を落とす）
+fn normalize(path: &Path) -> String {
+	path.components()
+		.filter(|v| !matches!(v, Component::CurDir))
+		.collect::<PathBuf>()
+		.to_string_lossy()
+		.replace('\\', "/")
+}
+
+fn is_glob(s: &str) -> bool {
+	s.contains(['*', '?', '[', '{'])
+}
+
+/// glob を含まないディレクトリは配下すべてにマッチさせる
+fn directory_glob(pattern: &str) -> String {
+	let normalized = normalize(Path::new(pattern));
+	if !is_glob(pattern) && Path::new(pattern).is_dir() {
+		format!("{}/**", normalized)
+	} else {
+		normalized
+	}
+}
+
+/// パターンのうち glob を含まない先頭部分を監視対象のディレクトリとする
+fn base_dir(pattern: &str) -> (PathBuf, RecursiveMode) {
+	let path = Path::new(pattern);
+	if !is_glob(pattern) {
+		return if path.is_dir() {
+			(path.to_path_buf(), RecursiveMode::Recursive)
+		} else {
+			// エディタはファイルを置き換えることがあるので、親ディレクトリを監視する
+			let parent = path.parent().filter(|v| !v.as_os_str().is_empty());
+			(
+				parent.unwrap_or(Path::new(".")).to_path_buf(),
+				RecursiveMode::NonRecursive,
+			)
+		};
+	}
+	let base: PathBuf = path
+		.components()
+		.take_while(|v| !is_glob(&v.as_os_str().to_string_lossy()))
+		.collect();
+	if base.as_os_str().is_empty() {
+		(PathBuf::from("."), RecursiveMode::Recursive)
+	} else {
+		(base, RecursiveMode::Recursive)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matches(patterns: &[&str], path: &str) -> bool {
+		glob_set(patterns.iter().map(|v| directory_glob(v)))
+			.unwrap()
+			.is_match(normalize(Path::new(path)))
+	}
+
+	#[test]
+	fn globs() {
+		// `*` は `/` をまたがず、`**` はまたぐ
+		assert!(matches(&["src/*.rs"], "src/main.rs"));
+		assert!(!matches(&["src/*.rs"], "src/a/main.rs"));
+		assert!(matches(&["src/**/*.rs"], "src/a/b/main.rs"));
+		assert!(matches(&["./src/*.rs"], "./src/main.rs"));
+		// glob を含まないディレクトリは配下すべて
+		assert!(matches(&["src"], "src/a/b.txt"));
+		assert!(!matches(&["src"], "tests/a.rs"));
+		assert!(glob_set(["src/[".to_string()].into_iter()).is_err());
+	}
+
+	#[test]
+	fn base_dirs() {
+		assert_eq!(
+			base_dir("src/**/*.rs"),
+			(PathBuf::from("src"), RecursiveMode::Recursive)
+		);
+		assert_eq!(
+			base_dir("**/*.go"),
+			(PathBuf::from("."), RecursiveMode::Recursive)
+		);
+		assert_eq!(
+			base_dir("src"),
+			(PathBuf::from("src"), RecursiveMode::Recursive)
+		);
+		// ファイルは親ディレクトリを監視する
+		assert_eq!(
+			base_dir("Cargo.toml"),
+			(PathBuf::from("."), RecursiveMode::NonRecursive)
+		);
+		assert_eq!(
+			base_dir("src/main.rs"),
+			(PathBuf::from("src"), RecursiveMode::NonRecursive)
+		);
+	}
+}
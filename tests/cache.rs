@@ -0,0 +1,388 @@
+// キャッシュを有効にしたルールで rebab をライブラリとして起動し、
+// upstream に届いたリクエストの数と X-Cache で振る舞いを確かめる
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// パスごとにリクエストを数える upstream
+///
+/// * `/slow`: 少し待ってから返す
+/// * `/swr`: max-age=1, stale-while-revalidate=30 で、何回目かを返す
+/// * `/etag`: ETag を付け、If-None-Match が合えば 304
+/// * `/broken`: Content-Length より短いボディで接続を切る
+/// * `/disk/*`: 60 バイトのボディ
+/// * そのほか: max-age=60 でパスを返す
+struct Upstream {
+	addr: SocketAddr,
+	counts: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Upstream {
+	fn start() -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let counts = Arc::new(Mutex::new(HashMap::new()));
+		let counter = counts.clone();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let Ok(stream) = stream else {
+					return;
+				};
+				let counts = counter.clone();
+				thread::spawn(move || respond(stream, &counts));
+			}
+		});
+		Self { addr, counts }
+	}
+
+	fn count(&self, path: &str) -> usize {
+		self.counts.lock().unwrap().get(path).copied().unwrap_or(0)
+	}
+}
+
+fn respond(mut stream: TcpStream, counts: &Mutex<HashMap<String, usize>>) {
+	let mut reader = BufReader::new(stream.try_clone().unwrap());
+	let mut line = String::new();
+	reader.read_line(&mut line).unwrap();
+	let path = line.split(' ').nth(1).unwrap_or("/").to_string();
+	let mut if_none_match = None;
+	loop {
+		let mut header = String::new();
+		if reader.read_line(&mut header).unwrap() == 0 || header.trim().is_empty() {
+			break;
+		}
+		if let Some((name, value)) = header.split_once(':')
+			&& name.eq_ignore_ascii_case("if-none-match")
+		{
+			if_none_match = Some(value.trim().to_string());
+		}
+	}
+	let count = {
+		let mut counts = counts.lock().unwrap();
+		let count = counts.entry(path.clone()).or_insert(0);
+		*count += 1;
+		*count
+	};
+	let ok = |headers: &str, body: &str| {
+		format!(
+			"HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+			headers,
+			body.len(),
+			body
+		)
+	};
+	let resp = match path.as_str() {
+		"/slow" => {
+			thread::sleep(Duration::from_millis(300));
+			ok("Cache-Control: max-age=60\r\n", "slow")
+		}
+		"/swr" => ok(
+			"Cache-Control: max-age=1, stale-while-revalidate=30\r\n",
+			&count.to_string(),
+		),
+		"/etag" if if_none_match.as_deref() == Some("\"v1\"") => {
+			"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nCache-Control: max-age=60\r\nConnection: close\r\n\r\n"
+				.to_string()
+		}
+		"/etag" => ok("ETag: \"v1\"\r\nCache-Control: max-age=60\r\n", "etag"),
+		"/broken" => {
+			"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 10\r\nConnection: close\r\n\r\n12345"
+				.to_string()
+		}
+		_ if path.starts_with("/disk/") => ok("Cache-Control: max-age=60\r\n", &body(&path)),
+		_ => ok("Cache-Control: max-age=60\r\n", &path),
+	};
+	let _ = stream.write_all(resp.as_bytes());
+}
+
+/// `/disk/*` のボディ
+fn body(path: &str) -> String {
+	path.chars().last().unwrap().to_string().repeat(60)
+}
+
+/// rebab のレスポンス
+struct Reply {
+	status: u16,
+	headers: Vec<(String, String)>,
+	body: String,
+}
+
+impl Reply {
+	fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str())
+	}
+
+	fn outcome(&self) -> &str {
+		self.header("x-cache").unwrap_or_default()
+	}
+}
+
+/// 生のリクエストを送る（途中で切れたボディはそこまで）
+async fn send(addr: SocketAddr, method: &'static str, path: &str, headers: &str) -> Reply {
+	let request = format!(
+		"{} {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+		method, path, addr, headers
+	);
+	tokio::task::spawn_blocking(move || {
+		let mut stream = TcpStream::connect(addr).unwrap();
+		stream.write_all(request.as_bytes()).unwrap();
+		let mut resp = vec![];
+		let _ = stream.read_to_end(&mut resp);
+		let resp = String::from_utf8_lossy(&resp).to_string();
+		let (head, body) = resp.split_once("\r\n\r\n").unwrap_or((&resp, ""));
+		let mut lines = head.lines();
+		let status = lines
+			.next()
+			.and_then(|v| v.split(' ').nth(1))
+			.and_then(|v| v.parse().ok())
+			.unwrap_or(0);
+		let headers = lines
+			.filter_map(|v| v.split_once(':'))
+			.map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+			.collect();
+		Reply {
+			status,
+			headers,
+			body: body.to_string(),
+		}
+	})
+	.await
+	.unwrap()
+}
+
+async fn get(addr: SocketAddr, path: &str) -> Reply {
+	send(addr, "GET", path, "").await
+}
+
+/// テストごとのキャッシュのディレクトリ
+fn cache_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("rebab-cache-{}-{}", std::process::id(), name));
+	let _ = std::fs::remove_dir_all(&dir);
+	dir
+}
+
+fn router(upstream: &Upstream, dir: Option<&Path>) -> rebab::config::Router {
+	let mut rule = serde_json::json!({
+		"name": "api",
+		"backend_port": upstream.addr.port(),
+		"cache": true,
+	});
+	if let Some(dir) = dir {
+		// 60 バイトのボディは一つしかメモリに置けない
+		rule["cache_max_size"] = 100.into();
+		rule["cache_dir"] = dir.to_str().unwrap().into();
+	}
+	serde_json::from_value(serde_json::json!({
+		"frontend": "127.0.0.1:0",
+		"rules": [rule],
+	}))
+	.unwrap()
+}
+
+/// ディレクトリの下の .cache ファイル
+fn cached_files(dir: &Path) -> Vec<PathBuf> {
+	let Ok(entries) = std::fs::read_dir(dir) else {
+		return vec![];
+	};
+	entries
+		.filter_map(|v| v.ok())
+		.map(|v| v.path())
+		.flat_map(|v| match v.is_dir() {
+			true => cached_files(&v),
+			false => vec![v],
+		})
+		.filter(|v| v.extension().is_some_and(|v| v == "cache"))
+		.collect()
+}
+
+/// 条件を満たすまで待つ（裏で動く書き込みや再検証のため）
+async fn wait_until(what: &str, condition: impl Fn() -> bool) {
+	let start = Instant::now();
+	while !condition() {
+		assert!(
+			start.elapsed() < Duration::from_secs(5),
+			"timed out: {}",
+			what
+		);
+		tokio::time::sleep(Duration::from_millis(20)).await;
+	}
+}
+
+#[tokio::test]
+async fn outcomes() {
+	let upstream = Upstream::start();
+	let app = rebab::Rebab::start(router(&upstream, None)).await.unwrap();
+	let addr = app.local_addr();
+
+	let first = get(addr, "/fresh").await;
+	assert_eq!((first.status, first.outcome()), (200, "MISS"));
+	let second = get(addr, "/fresh").await;
+	assert_eq!((second.outcome(), second.body.as_str()), ("HIT", "/fresh"));
+	assert!(second.header("age").is_some());
+	assert_eq!(upstream.count("/fresh"), 1);
+
+	let post = send(addr, "POST", "/fresh", "Content-Length: 0\r\n").await;
+	assert_eq!(post.outcome(), "BYPASS");
+	assert_eq!(upstream.count("/fresh"), 2);
+
+	assert_eq!(get(addr, "/etag").await.outcome(), "MISS");
+	let revalidated = send(addr, "GET", "/etag", "Cache-Control: no-cache\r\n").await;
+	assert_eq!(revalidated.outcome(), "REVALIDATED");
+	assert_eq!(revalidated.body, "etag");
+	assert_eq!(upstream.count("/etag"), 2);
+
+	app.stop().await;
+}
+
+#[tokio::test]
+async fn authenticated_responses_are_not_shared() {
+	let upstream = Upstream::start();
+	let mut router = router(&upstream, None);
+	// Authorization を消しても、認証したリクエストへのレスポンスは保存しない
+	router.rules[0].bearer_tokens = vec!["secret".to_string()];
+	router.rules[0].strip_authorization = Some(true);
+	let app = rebab::Rebab::start(router).await.unwrap();
+	let addr = app.local_addr();
+
+	assert_eq!(get(addr, "/private").await.status, 401);
+	for _ in 0..2 {
+		let reply = send(addr, "GET", "/private", "Authorization: Bearer secret\r\n").await;
+		assert_eq!((reply.status, reply.outcome()), (200, "MISS"));
+	}
+	assert_eq!(upstream.count("/private"), 2);
+
+	app.stop().await;
+}
+
+#[tokio::test]
+async fn concurrent_requests_collapse() {
+	let upstream = Upstream::start();
+	let app = rebab::Rebab::start(router(&upstream, None)).await.unwrap();
+	let addr = app.local_addr();
+
+	let replies = fetch_all(addr, "/slow", 4).await;
+	assert!(replies.iter().all(|v| v.body == "slow"));
+	assert_eq!(
+		replies.iter().filter(|v| v.outcome() == "MISS").count(),
+		1,
+		"{:?}",
+		replies.iter().map(Reply::outcome).collect::<Vec<_>>()
+	);
+	assert_eq!(upstream.count("/slow"), 1);
+
+	app.stop().await;
+}
+
+/// 同じパスに同時に送る
+async fn fetch_all(addr: SocketAddr, path: &'static str, n: usize) -> Vec<Reply> {
+	let tasks: Vec<_> = (0..n)
+		.map(|_| tokio::spawn(async move { get(addr, path).await }))
+		.collect();
+	let mut replies = vec![];
+	for task in tasks {
+		replies.push(task.await.unwrap());
+	}
+	replies
+}
+
+#[tokio::test]
+async fn stale_while_revalidate() {
+	let upstream = Upstream::start();
+	let app = rebab::Rebab::start(router(&upstream, None)).await.unwrap();
+	let addr = app.local_addr();
+
+	assert_eq!(get(addr, "/swr").await.body, "1");
+	tokio::time::sleep(Duration::from_millis(1100)).await;
+	// 古いものをすぐに返し、裏で取り直す
+	let stale = get(addr, "/swr").await;
+	assert_eq!((stale.outcome(), stale.body.as_str()), ("STALE", "1"));
+	wait_until("background revalidation", || upstream.count("/swr") == 2).await;
+	// 取り直したボディを保存し終えるまで、古いものが返ることがある
+	let mut fresh = get(addr, "/swr").await;
+	let start = Instant::now();
+	while fresh.outcome() == "STALE" && start.elapsed() < Duration::from_secs(5) {
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		fresh = get(addr, "/swr").await;
+	}
+	assert_eq!((fresh.outcome(), fresh.body.as_str()), ("HIT", "2"));
+	assert_eq!(upstream.count("/swr"), 2);
+
+	app.stop().await;
+}
+
+#[tokio::test]
+async fn only_complete_bodies_are_stored() {
+	let upstream = Upstream::start();
+	let app = rebab::Rebab::start(router(&upstream, None)).await.unwrap();
+	let addr = app.local_addr();
+
+	let broken = get(addr, "/broken").await;
+	assert_eq!(broken.status, 200);
+	assert_ne!(broken.body, "1234567890");
+	let again = get(addr, "/broken").await;
+	assert_eq!(again.outcome(), "MISS");
+	assert_eq!(upstream.count("/broken"), 2);
+
+	app.stop().await;
+}
+
+#[tokio::test]
+async fn disk_tier_spills_and_promotes() {
+	let upstream = Upstream::start();
+	let dir = cache_dir("disk");
+	let app = rebab::Rebab::start(router(&upstream, Some(&dir)))
+		.await
+		.unwrap();
+	let addr = app.local_addr();
+
+	assert_eq!(get(addr, "/disk/a").await.outcome(), "MISS");
+	assert_eq!(get(addr, "/disk/b").await.outcome(), "MISS");
+	// メモリに入らない a はディスクに移る
+	wait_until("spill to disk", || cached_files(&dir).len() == 1).await;
+	let a = get(addr, "/disk/a").await;
+	assert_eq!((a.outcome(), a.body.clone()), ("HIT", body("/disk/a")));
+	// a をメモリに戻し、代わりに b がディスクに移る（書き終えるまでは見つからない）
+	wait_until("spill to disk", || cached_files(&dir).len() == 2).await;
+	let b = get(addr, "/disk/b").await;
+	assert_eq!((b.outcome(), b.body.clone()), ("HIT", body("/disk/b")));
+	assert_eq!(upstream.count("/disk/a"), 1);
+	assert_eq!(upstream.count("/disk/b"), 1);
+
+	app.stop().await;
+	let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn reload_starts_an_empty_cache_in_a_new_directory() {
+	let upstream = Upstream::start();
+	let dir = cache_dir("reload");
+	let mut app = rebab::Rebab::start(router(&upstream, Some(&dir)))
+		.await
+		.unwrap();
+	let addr = app.local_addr();
+
+	get(addr, "/disk/a").await;
+	get(addr, "/disk/b").await;
+	wait_until("spill to disk", || cached_files(&dir).len() == 1).await;
+	let before = cached_files(&dir);
+
+	app.reload(app.router()).await.unwrap();
+	// 前のキャッシュのディレクトリは消え、新しいキャッシュは空から始まる
+	wait_until("old directory removed", || !before[0].exists()).await;
+	assert_eq!(get(addr, "/disk/a").await.outcome(), "MISS");
+	assert_eq!(get(addr, "/disk/b").await.outcome(), "MISS");
+	wait_until("spill to disk", || cached_files(&dir).len() == 1).await;
+	assert_ne!(cached_files(&dir)[0].parent(), before[0].parent());
+	assert_eq!(get(addr, "/disk/a").await.outcome(), "HIT");
+
+	app.stop().await;
+	let _ = std::fs::remove_dir_all(&dir);
+}
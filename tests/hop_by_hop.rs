@@ -0,0 +1,241 @@
+// rebab を起動し、ローカルの upstream との間でどのヘッダが届くかを確かめる
+//
+// RFC 9110 7.6.1: Connection に列挙されたヘッダと hop-by-hop ヘッダは転送しない
+// RFC 9110 7.6.3: 転送したメッセージに Via を付ける
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{Receiver, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 受け取ったリクエストのヘッダを送り、決まったレスポンスを返す upstream
+struct Upstream {
+	addr: SocketAddr,
+	requests: Receiver<Vec<(String, String)>>,
+}
+
+impl Upstream {
+	fn start(response_headers: &'static str) -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (tx, rx) = channel();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let Ok(mut stream) = stream else {
+					return;
+				};
+				let mut reader = BufReader::new(stream.try_clone().unwrap());
+				let (_, headers) = read_head(&mut reader);
+				let _ = tx.send(headers);
+				let _ = write!(
+					stream,
+					"HTTP/1.1 200 OK\r\n{}Content-Length: 2\r\n\r\nok",
+					response_headers
+				);
+			}
+		});
+		Self { addr, requests: rx }
+	}
+
+	fn received(&self) -> Vec<(String, String)> {
+		self.requests
+			.recv_timeout(Duration::from_secs(5))
+			.expect("upstream received no request")
+	}
+}
+
+/// テスト中だけ動かす rebab
+struct Rebab {
+	addr: SocketAddr,
+	child: Child,
+}
+
+impl Rebab {
+	fn start(upstream: &Upstream) -> Self {
+		// 空いているポートを借りてから解放する
+		let addr = TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap();
+		let child = Command::new(env!("CARGO_BIN_EXE_rebab"))
+			.arg("--frontend")
+			.arg(addr.to_string())
+			.arg("--rule")
+			.arg(format!("host=127.0.0.1,port={}", upstream.addr.port()))
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap();
+		let started = Instant::now();
+		while TcpStream::connect(addr).is_err() {
+			assert!(
+				started.elapsed() < Duration::from_secs(10),
+				"rebab did not start"
+			);
+			thread::sleep(Duration::from_millis(50));
+		}
+		Self { addr, child }
+	}
+
+	/// 生のリクエストを送り、レスポンスのステータス行とヘッダを返す
+	fn send(&self, request_headers: &str) -> (String, Vec<(String, String)>) {
+		let mut stream = TcpStream::connect(self.addr).unwrap();
+		stream
+			.set_read_timeout(Some(Duration::from_secs(5)))
+			.unwrap();
+		write!(
+			stream,
+			"GET /conformance HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+			self.addr, request_headers
+		)
+		.unwrap();
+		let mut reader = BufReader::new(stream);
+		let head = read_head(&mut reader);
+		let mut body = Vec::new();
+		let _ = reader.read_to_end(&mut body);
+		head
+	}
+}
+
+impl Drop for Rebab {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+/// 先頭行と、小文字にしたヘッダ名と値
+fn read_head(reader: &mut impl BufRead) -> (String, Vec<(String, String)>) {
+	let mut start = String::new();
+	reader.read_line(&mut start).unwrap();
+	let mut headers = Vec::new();
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line).unwrap() == 0 {
+			break;
+		}
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+		let (name, value) = line.split_once(':').unwrap();
+		headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+	}
+	(start.trim_end().to_string(), headers)
+}
+
+fn get<'a>(headers: &'a [(String, String)], name: &str) -> Vec<&'a str> {
+	headers
+		.iter()
+		.filter(|(k, _)| k == name)
+		.map(|(_, v)| v.as_str())
+		.collect()
+}
+
+fn assert_absent(headers: &[(String, String)], names: &[&str]) {
+	for name in names {
+		assert!(
+			get(headers, name).is_empty(),
+			"{} should not be forwarded: {:?}",
+			name,
+			headers
+		);
+	}
+}
+
+#[test]
+fn request_drops_hop_by_hop_headers() {
+	let upstream = Upstream::start("");
+	let rebab = Rebab::start(&upstream);
+	rebab.send(
+		"Keep-Alive: timeout=5\r\n\
+		 Proxy-Connection: keep-alive\r\n\
+		 TE: trailers\r\n\
+		 Upgrade: websocket\r\n\
+		 Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\
+		 X-End-To-End: 1\r\n",
+	);
+	let headers = upstream.received();
+	assert_absent(
+		&headers,
+		&[
+			"keep-alive",
+			"proxy-connection",
+			"te",
+			"upgrade",
+			"proxy-authorization",
+		],
+	);
+	assert_eq!(get(&headers, "x-end-to-end"), ["1"]);
+}
+
+#[test]
+fn request_drops_headers_listed_in_connection() {
+	let upstream = Upstream::start("");
+	let rebab = Rebab::start(&upstream);
+	rebab.send(
+		"Connection: X-Client-Hop, x-OTHER-hop\r\n\
+		 X-Client-Hop: 1\r\n\
+		 X-Other-Hop: 2\r\n\
+		 X-End-To-End: 1\r\n",
+	);
+	let headers = upstream.received();
+	assert_absent(&headers, &["x-client-hop", "x-other-hop"]);
+	for value in get(&headers, "connection") {
+		assert!(!value.to_ascii_lowercase().contains("hop"), "{}", value);
+	}
+	assert_eq!(get(&headers, "x-end-to-end"), ["1"]);
+}
+
+#[test]
+fn request_appends_via() {
+	let upstream = Upstream::start("");
+	let rebab = Rebab::start(&upstream);
+	rebab.send("Via: 1.1 edge\r\n");
+	let headers = upstream.received();
+	assert_eq!(get(&headers, "via").join(", "), "1.1 edge, 1.1 rebab");
+}
+
+#[test]
+fn response_drops_hop_by_hop_headers() {
+	let upstream = Upstream::start(
+		"Connection: X-Upstream-Hop\r\n\
+		 X-Upstream-Hop: 1\r\n\
+		 Keep-Alive: timeout=5\r\n\
+		 Proxy-Connection: keep-alive\r\n\
+		 Proxy-Authenticate: Basic\r\n\
+		 Upgrade: h2c\r\n\
+		 X-End-To-End: 1\r\n",
+	);
+	let rebab = Rebab::start(&upstream);
+	let (status, headers) = rebab.send("");
+	assert_eq!(status, "HTTP/1.1 200 OK");
+	assert_absent(
+		&headers,
+		&[
+			"x-upstream-hop",
+			"keep-alive",
+			"proxy-connection",
+			"proxy-authenticate",
+			"upgrade",
+		],
+	);
+	// rebab 自身が付ける Connection: close だけが残る
+	for value in get(&headers, "connection") {
+		assert_eq!(value.to_ascii_lowercase(), "close");
+	}
+	assert_eq!(get(&headers, "x-end-to-end"), ["1"]);
+}
+
+#[test]
+fn response_appends_via() {
+	let upstream = Upstream::start("Via: 1.1 origin-cache\r\n");
+	let rebab = Rebab::start(&upstream);
+	let (_, headers) = rebab.send("");
+	assert_eq!(
+		get(&headers, "via").join(", "),
+		"1.1 origin-cache, 1.1 rebab"
+	);
+}
@@ -0,0 +1,122 @@
+// Middleware の順序、打ち切り、リクエストごとの状態を、ライブラリとして起動した rebab で確かめる
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use hyper::{Request, Response};
+use rebab::body::RebabBody;
+use rebab::middleware::{BoxFuture, Middleware, Next};
+use rebab::{Connection, Decision, Listener, Proxy, Routing, Server};
+
+/// extensions で Proxy に渡す値
+#[derive(Clone)]
+struct User(String);
+
+/// X-User を extensions に移し、無ければ 401 で打ち切る
+struct Auth;
+
+impl Middleware for Auth {
+	fn call<'a>(
+		&'a self,
+		mut req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let Some(user) = req.headers_mut().remove("x-user") else {
+				return rebab::service::response(401, "who are you".to_string());
+			};
+			let user = User(user.to_str().unwrap().to_string());
+			req.extensions_mut().insert(user);
+			next.run(req, conn).await
+		})
+	}
+}
+
+/// 通った順をレスポンスの X-Trace に追記する
+struct Trace(&'static str);
+
+impl Middleware for Trace {
+	fn call<'a>(
+		&'a self,
+		req: Request<RebabBody>,
+		conn: &'a Connection,
+		next: Next<'a>,
+	) -> BoxFuture<'a, Response<RebabBody>> {
+		Box::pin(async move {
+			let mut resp = next.run(req, conn).await;
+			resp.headers_mut()
+				.append("x-trace", self.0.parse().unwrap());
+			resp
+		})
+	}
+}
+
+struct Hello;
+
+impl Proxy for Hello {
+	fn route(&self, parts: &hyper::http::request::Parts, _conn: &Connection) -> Routing {
+		let user = parts.extensions.get::<User>().map(|v| v.0.clone());
+		Routing {
+			middleware: vec![Arc::new(Trace("route"))],
+			..Decision::Respond {
+				status: 200,
+				headers: vec![],
+				body: format!("hello {}", user.unwrap_or_default()),
+			}
+			.into()
+		}
+	}
+}
+
+async fn start() -> Server {
+	let mut listener = Listener::from("127.0.0.1:0".parse::<SocketAddr>().unwrap());
+	listener.middleware = vec![Arc::new(Trace("outer")), Arc::new(Auth)];
+	Server::bind(listener, Hello).await.unwrap()
+}
+
+/// 生のリクエストを送り、レスポンス全体を返す
+async fn send(addr: SocketAddr, headers: &'static str) -> String {
+	tokio::task::spawn_blocking(move || {
+		let mut stream = TcpStream::connect(addr).unwrap();
+		write!(
+			stream,
+			"GET / HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+			addr, headers
+		)
+		.unwrap();
+		let mut resp = String::new();
+		stream.read_to_string(&mut resp).unwrap();
+		resp.to_ascii_lowercase()
+	})
+	.await
+	.unwrap()
+}
+
+#[tokio::test]
+async fn state_reaches_proxy_and_layers_unwind_in_order() {
+	let server = start().await;
+	let resp = send(server.local_addr(), "X-User: alice\r\n").await;
+	assert!(resp.starts_with("http/1.1 200"), "{}", resp);
+	assert!(resp.ends_with("hello alice"), "{}", resp);
+	// 内側（ルート）から外側の順に戻る
+	let trace: Vec<&str> = resp
+		.lines()
+		.filter_map(|v| v.strip_prefix("x-trace: "))
+		.map(str::trim)
+		.collect();
+	assert_eq!(trace, ["route", "outer"]);
+	server.stop().await;
+}
+
+#[tokio::test]
+async fn middleware_can_short_circuit() {
+	let server = start().await;
+	let resp = send(server.local_addr(), "").await;
+	assert!(resp.starts_with("http/1.1 401"), "{}", resp);
+	assert!(resp.contains("x-trace: outer"), "{}", resp);
+	// ルーティングまで進まない
+	assert!(!resp.contains("x-trace: route"), "{}", resp);
+	server.stop().await;
+}